@@ -0,0 +1,345 @@
+//! An implementation of the aes128gcm encoding of RFC8291 that doesn't go through the `ece` crate. Requires the
+//! `custom-aes128gcm` feature.
+//!
+//! It backs the encryption options `ece` doesn't offer: reusing an ephemeral key, custom record sizes and progress
+//! reporting. Everything else, and the default build, encrypts with `ece`.
+
+use hkdf::Hkdf;
+use openssl::bn::{BigNum, BigNumContext};
+use openssl::derive::Deriver;
+use openssl::ec::{EcGroup, EcKey, EcPoint, PointConversionForm};
+use openssl::nid::Nid;
+use openssl::pkey::{PKey, Private};
+use openssl::symm::{encrypt_aead, Cipher};
+use sha2::Sha256;
+
+use crate::error::WebPushError;
+
+/// An ephemeral P-256 key pair for aes128gcm encryption, see
+/// [`HttpEce::encrypt_with_ephemeral_key`](crate::HttpEce::encrypt_with_ephemeral_key). Requires the
+/// `custom-aes128gcm` feature.
+#[derive(Clone)]
+pub struct EphemeralKey {
+    key: PKey<Private>,
+    public_key: Vec<u8>,
+}
+
+impl EphemeralKey {
+    /// Generates a new random ephemeral key pair.
+    pub fn generate() -> Result<EphemeralKey, WebPushError> {
+        let group = p256_group()?;
+        let key = EcKey::generate(&group).map_err(|_| WebPushError::Unspecified)?;
+
+        Self::from_ec_key(&group, key)
+    }
+
+    /// Creates an ephemeral key pair from the raw 32 byte private key. The public key is derived once here, so
+    /// reusing the result skips the expensive P-256 operations on each encryption.
+    pub fn from_private_bytes(private_key: &[u8]) -> Result<EphemeralKey, WebPushError> {
+        let group = p256_group()?;
+        let mut ctx = BigNumContext::new().map_err(|_| WebPushError::Unspecified)?;
+
+        let private_number = BigNum::from_slice(private_key).map_err(|_| WebPushError::InvalidCryptoKeys)?;
+        let mut public_point = EcPoint::new(&group).map_err(|_| WebPushError::Unspecified)?;
+        public_point
+            .mul_generator2(&group, &private_number, &mut ctx)
+            .map_err(|_| WebPushError::InvalidCryptoKeys)?;
+
+        let key = EcKey::from_private_components(&group, &private_number, &public_point)
+            .map_err(|_| WebPushError::InvalidCryptoKeys)?;
+        key.check_key().map_err(|_| WebPushError::InvalidCryptoKeys)?;
+
+        Self::from_ec_key(&group, key)
+    }
+
+    /// Gets the uncompressed public key bytes, as sent to the browser in the aes128gcm header.
+    pub fn public_key(&self) -> &[u8] {
+        &self.public_key
+    }
+
+    fn from_ec_key(group: &EcGroup, key: EcKey<Private>) -> Result<EphemeralKey, WebPushError> {
+        let mut ctx = BigNumContext::new().map_err(|_| WebPushError::Unspecified)?;
+        let public_key = key
+            .public_key()
+            .to_bytes(group, PointConversionForm::UNCOMPRESSED, &mut ctx)
+            .map_err(|_| WebPushError::InvalidCryptoKeys)?;
+        let key = PKey::from_ec_key(key).map_err(|_| WebPushError::InvalidCryptoKeys)?;
+
+        Ok(EphemeralKey { key, public_key })
+    }
+}
+
+/// Record size used for aes128gcm, matching the `ece` crate.
+pub(crate) const AES128GCM_RECORD_SIZE: usize = 4096;
+/// The smallest record size allowed by RFC 8188, holding a tag and one byte of content with its delimiter.
+pub(crate) const AES128GCM_MIN_RECORD_SIZE: usize = 18;
+/// Length of the AES-GCM authentication tag appended to each record.
+const AES128GCM_TAG_LENGTH: usize = 16;
+/// The plaintext is padded to a multiple of this size, matching the `ece` crate.
+pub(crate) const AES128GCM_PADDING_BLOCK_SIZE: usize = 128;
+
+fn p256_group() -> Result<EcGroup, WebPushError> {
+    EcGroup::from_curve_name(Nid::X9_62_PRIME256V1).map_err(|_| WebPushError::Unspecified)
+}
+
+/// A random salt for a single encryption.
+pub(crate) fn random_salt() -> Result<[u8; 16], WebPushError> {
+    let mut salt = [0u8; 16];
+    openssl::rand::rand_bytes(&mut salt).map_err(|_| WebPushError::Unspecified)?;
+
+    Ok(salt)
+}
+
+/// Encrypts the plaintext records from [`aes128gcm_records`] as described in RFC8291, with the given ephemeral key and
+/// salt. Calls `on_progress` with the number of records encrypted and the total after each record.
+pub(crate) fn aes128gcm_encrypt(
+    ephemeral_key: &EphemeralKey,
+    peer_public_key: &[u8],
+    peer_secret: &[u8],
+    salt: &[u8; 16],
+    record_size: usize,
+    records: &[Vec<u8>],
+    on_progress: &mut dyn FnMut(usize, usize),
+) -> Result<Vec<u8>, WebPushError> {
+    let group = p256_group()?;
+    let mut ctx = BigNumContext::new().map_err(|_| WebPushError::Unspecified)?;
+
+    let peer_point =
+        EcPoint::from_bytes(&group, peer_public_key, &mut ctx).map_err(|_| WebPushError::InvalidCryptoKeys)?;
+    let peer_key = EcKey::from_public_key(&group, &peer_point)
+        .and_then(PKey::from_ec_key)
+        .map_err(|_| WebPushError::InvalidCryptoKeys)?;
+
+    let ecdh_secret = Deriver::new(&ephemeral_key.key)
+        .and_then(|mut deriver| {
+            deriver.set_peer(&peer_key)?;
+            deriver.derive_to_vec()
+        })
+        .map_err(|_| WebPushError::InvalidCryptoKeys)?;
+
+    // Combine the ECDH secret with the auth secret, keyed to both public keys.
+    let mut key_info = b"WebPush: info\0".to_vec();
+    key_info.extend_from_slice(peer_public_key);
+    key_info.extend_from_slice(&ephemeral_key.public_key);
+
+    let mut ikm = [0u8; 32];
+    Hkdf::<Sha256>::new(Some(peer_secret), &ecdh_secret)
+        .expand(&key_info, &mut ikm)
+        .map_err(|_| WebPushError::Unspecified)?;
+
+    let prk = Hkdf::<Sha256>::new(Some(salt), &ikm);
+    let mut cek = [0u8; 16];
+    let mut nonce = [0u8; 12];
+    prk.expand(b"Content-Encoding: aes128gcm\0", &mut cek)
+        .and_then(|_| prk.expand(b"Content-Encoding: nonce\0", &mut nonce))
+        .map_err(|_| WebPushError::Unspecified)?;
+
+    // Header: salt, record size, key id length and the ephemeral public key as key id.
+    let plaintext_len: usize = records.iter().map(Vec::len).sum();
+    let mut output = Vec::with_capacity(plaintext_len + records.len() * AES128GCM_TAG_LENGTH + 128);
+    output.extend_from_slice(salt);
+    output.extend_from_slice(&(record_size as u32).to_be_bytes());
+    output.push(ephemeral_key.public_key.len() as u8);
+    output.extend_from_slice(&ephemeral_key.public_key);
+
+    for (sequence, record) in records.iter().enumerate() {
+        let mut record_nonce = nonce;
+        for (byte, sequence_byte) in record_nonce[4..].iter_mut().zip((sequence as u64).to_be_bytes()) {
+            *byte ^= sequence_byte;
+        }
+
+        let mut tag = [0u8; AES128GCM_TAG_LENGTH];
+        let ciphertext = encrypt_aead(Cipher::aes_128_gcm(), &cek, Some(&record_nonce), &[], record, &mut tag)
+            .map_err(|_| WebPushError::Unspecified)?;
+
+        output.extend_from_slice(&ciphertext);
+        output.extend_from_slice(&tag);
+
+        on_progress(sequence + 1, records.len());
+    }
+
+    Ok(output)
+}
+
+/// Splits the content into padded plaintext records. Every record but the last is filled to the record size and
+/// ends with a `1` delimiter, the last one ends with a `2` delimiter and pads the total to a multiple of
+/// `padding_block_size` bytes.
+pub(crate) fn aes128gcm_records(content: &[u8], record_size: usize, padding_block_size: usize) -> Vec<Vec<u8>> {
+    let capacity = record_size - AES128GCM_TAG_LENGTH;
+    let mut records = Vec::new();
+    let mut rest = content;
+
+    while rest.len() > capacity - 1 {
+        let (chunk, tail) = rest.split_at(capacity - 1);
+        let mut record = chunk.to_vec();
+        record.push(1);
+        records.push(record);
+        rest = tail;
+    }
+
+    let padding = (padding_block_size - (rest.len() + 1) % padding_block_size) % padding_block_size;
+    let padding = padding.min(capacity - 1 - rest.len());
+
+    let mut record = rest.to_vec();
+    record.push(2);
+    record.resize(record.len() + padding, 0);
+    records.push(record);
+
+    records
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::aes128gcm::{aes128gcm_encrypt, aes128gcm_records, EphemeralKey, AES128GCM_RECORD_SIZE};
+    use crate::error::WebPushError;
+    use crate::http_ece::{ContentEncoding, HttpEce};
+
+    /// Tests that one ephemeral key can encrypt reversibly for several recipients.
+    #[test]
+    fn test_payload_encrypts_with_ephemeral_key() {
+        let ephemeral_key = EphemeralKey::generate().unwrap();
+        let ephemeral_key =
+            EphemeralKey::from_private_bytes(&ephemeral_key.key.ec_key().unwrap().private_key().to_vec()).unwrap();
+        let plaintext = "Hello world!";
+
+        for _ in 0..2 {
+            let (key, auth) = ece::generate_keypair_and_auth_secret().unwrap();
+            let p_key = key.raw_components().unwrap();
+            let http_ece = HttpEce::new(ContentEncoding::Aes128Gcm, p_key.public_key(), &auth, None);
+
+            let ciphertext = http_ece
+                .encrypt_with_ephemeral_key(plaintext.as_bytes(), &ephemeral_key)
+                .unwrap();
+
+            // The key id of the header is the ephemeral public key.
+            assert_eq!(ephemeral_key.public_key(), &ciphertext.content[21..86]);
+            assert_eq!(
+                String::from_utf8(ece::decrypt(&p_key, &auth, &ciphertext.content).unwrap()).unwrap(),
+                plaintext
+            );
+        }
+    }
+
+    /// The example from RFC8291 appendix A, which uses no padding.
+    #[test]
+    fn test_aes128gcm_matches_rfc8291_example() {
+        let decode = |value| base64::decode_config(value, base64::URL_SAFE_NO_PAD).unwrap();
+
+        let ephemeral_key =
+            EphemeralKey::from_private_bytes(&decode("yfWPiYE-n46HLnH0KqZOF1fJJU3MYrct3AELtAQ-oRw")).unwrap();
+        let ua_public =
+            decode("BCVxsr7N_eNgVRqvHtD0zTZsEc6-VV-JvLexhqUzORcxaOzi6-AYWXvTBHm4bjyPjs7Vd8pZGH6SRpkNtoIAiw4");
+        let auth_secret = decode("BTBZMqHH6r4Tts7J_aSIgg");
+        let mut salt = [0u8; 16];
+        salt.copy_from_slice(&decode("DGv6ra1nlYgDCS1FRnbzlw"));
+
+        assert_eq!(
+            decode("BP4z9KsN6nGRTbVYI_c7VJSPQTBtkgcy27mlmlMoZIIgDll6e3vCYLocInmYWAmS6TlzAC8wEqKK6PBru3jl7A8"),
+            ephemeral_key.public_key()
+        );
+
+        let ciphertext = aes128gcm_encrypt(
+            &ephemeral_key,
+            &ua_public,
+            &auth_secret,
+            &salt,
+            AES128GCM_RECORD_SIZE,
+            &aes128gcm_records(b"When I grow up, I want to be a watermelon", AES128GCM_RECORD_SIZE, 1),
+            &mut |_, _| (),
+        )
+        .unwrap();
+
+        assert_eq!(
+            "DGv6ra1nlYgDCS1FRnbzlwAAEABBBP4z9KsN6nGRTbVYI_c7VJSPQTBtkgcy27mlmlMoZIIgDll6e3vCYLocInmYWAmS6TlzAC8wEqKK6PBru3jl7A_yl95bQpu6cVPTpK4Mqgkf1CXztLVBSt2Ks3oZwbuwXPXLWyouBWLVWGNWQexSgSxsj_Qulcy4a-fN",
+            base64::encode_config(ciphertext, base64::URL_SAFE_NO_PAD)
+        );
+    }
+
+    #[test]
+    fn test_custom_record_size_is_decryptable() {
+        let (key, auth) = ece::generate_keypair_and_auth_secret().unwrap();
+        let p_key = key.raw_components().unwrap();
+        let mut http_ece = HttpEce::new(ContentEncoding::Aes128Gcm, p_key.public_key(), &auth, None);
+
+        assert!(matches!(
+            http_ece.set_record_size(17),
+            Err(WebPushError::InvalidRecordSize(17))
+        ));
+        http_ece.set_record_size(256).unwrap();
+
+        let payload = http_ece.encrypt(b"Hello world!").unwrap();
+
+        assert_eq!(256, payload.aes128gcm_header().unwrap().record_size);
+        assert_eq!(
+            b"Hello world!".to_vec(),
+            ece::decrypt(&p_key, &auth, &payload.content).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_reports_progress_per_record() {
+        let (key, auth) = ece::generate_keypair_and_auth_secret().unwrap();
+        let p_key = key.raw_components().unwrap();
+        let mut http_ece = HttpEce::new(ContentEncoding::Aes128Gcm, p_key.public_key(), &auth, None);
+        let content = vec![b'x'; 1000];
+
+        let mut progress = Vec::new();
+        http_ece
+            .encrypt_with_progress(&content, |done, total| progress.push((done, total)))
+            .unwrap();
+        assert_eq!(vec![(1, 1)], progress);
+
+        http_ece.set_record_size(256).unwrap();
+
+        let mut progress = Vec::new();
+        let payload = http_ece
+            .encrypt_with_progress(&content, |done, total| progress.push((done, total)))
+            .unwrap();
+
+        assert_eq!((1..=5).map(|done| (done, 5)).collect::<Vec<_>>(), progress);
+        assert_eq!(content, ece::decrypt(&p_key, &auth, &payload.content).unwrap());
+    }
+
+    #[test]
+    fn test_deterministic_encryption_is_repeatable() {
+        let (key, auth) = ece::generate_keypair_and_auth_secret().unwrap();
+        let p_key = key.raw_components().unwrap();
+        let http_ece = HttpEce::new(ContentEncoding::Aes128Gcm, p_key.public_key(), &auth, None);
+        let ephemeral_key = EphemeralKey::generate().unwrap();
+        let salt = [3u8; 16];
+
+        let first = http_ece
+            .encrypt_deterministic(b"Hello world!", &ephemeral_key, &salt)
+            .unwrap();
+        let second = http_ece
+            .encrypt_deterministic(b"Hello world!", &ephemeral_key, &salt)
+            .unwrap();
+
+        assert_eq!(first, second);
+        assert_eq!(
+            b"Hello world!".to_vec(),
+            ece::decrypt(&p_key, &auth, &first.content).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_ephemeral_key_encryption_matches_ece_size() {
+        let (key, auth) = ece::generate_keypair_and_auth_secret().unwrap();
+        let p_key = key.raw_components().unwrap();
+        let http_ece = HttpEce::new(ContentEncoding::Aes128Gcm, p_key.public_key(), &auth, None);
+        let ephemeral_key = EphemeralKey::generate().unwrap();
+
+        for len in [1, 127, 128, 3052] {
+            let content = vec![7u8; len];
+
+            assert_eq!(
+                http_ece.encrypt(&content).unwrap().content.len(),
+                http_ece
+                    .encrypt_with_ephemeral_key(&content, &ephemeral_key)
+                    .unwrap()
+                    .content
+                    .len()
+            );
+        }
+    }
+}
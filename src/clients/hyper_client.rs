@@ -1,12 +1,26 @@
+use std::future::Future;
+use std::io;
+use std::net::SocketAddr;
+use std::num::NonZeroUsize;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+
 use async_trait::async_trait;
 
-use http::header::{CONTENT_LENGTH, RETRY_AFTER};
-use hyper::{body::HttpBody, client::HttpConnector, Body, Client, Request as HttpRequest};
+use http::header::CONTENT_LENGTH;
+use hyper::client::connect::dns::Name;
+use hyper::client::ResponseFuture;
+use hyper::{body::HttpBody, client::HttpConnector, service::Service, Body, Client, Request as HttpRequest};
 use hyper_tls::HttpsConnector;
+use tokio::sync::{Semaphore, SemaphorePermit};
 
-use crate::clients::{request_builder, WebPushClient};
-use crate::error::{RetryAfter, WebPushError};
-use crate::message::WebPushMessage;
+use crate::clients::{
+    self, request_builder, BaseUrlOverride, CompletionHook, SendContext, SendReport, SendStats, WebPushClient,
+};
+use crate::error::WebPushError;
+use crate::message::{Urgency, WebPushMessage};
 
 /// An async client for sending the notification payload.
 ///
@@ -14,9 +28,19 @@ use crate::message::WebPushMessage;
 /// so cloning is a cheap and effective method to provide access to the client.
 ///
 /// This client is [`hyper`](https://crates.io/crates/hyper) based, and will only work in Tokio contexts.
+///
+/// Connections are kept in a pool and reused, so repeated sends to a host skip the TLS handshake. The client has no
+/// option for TLS session resumption on new connections: TLS is done by `native-tls`, which doesn't expose it. Use
+/// [`WebPushClient::warm_up`], or HTTP/2 with the `hyper-http2` feature, to cut handshakes for bursty traffic instead.
 #[derive(Clone)]
 pub struct HyperWebPushClient {
-    client: Client<HttpsConnector<HttpConnector>>,
+    client: HyperClient,
+    content_length_header: bool,
+    timeout: Option<Duration>,
+    on_complete: Option<CompletionHook>,
+    default_urgency: Option<Urgency>,
+    concurrency_limit: Option<Arc<Semaphore>>,
+    base_url_override: Option<BaseUrlOverride>,
 }
 
 impl Default for HyperWebPushClient {
@@ -28,43 +52,205 @@ impl Default for HyperWebPushClient {
 impl From<Client<HttpsConnector<HttpConnector>>> for HyperWebPushClient {
     /// Creates a new client from a custom hyper HTTP client.
     fn from(client: Client<HttpsConnector<HttpConnector>>) -> Self {
-        Self { client }
+        Self::from_client(HyperClient::Default(client))
     }
 }
 
 impl HyperWebPushClient {
     /// Creates a new client.
     pub fn new() -> Self {
+        Self::from_client(HyperClient::Default(Client::builder().build(HttpsConnector::new())))
+    }
+
+    /// Creates a new client with a maximum time to connect to the push service, including DNS resolution. A breach
+    /// is returned as `ConnectionFailed`. The client from [`HyperWebPushClient::new`] has no connect timeout.
+    pub fn new_with_connect_timeout(connect_timeout: Duration) -> Self {
+        let mut http = HttpConnector::new();
+        http.enforce_http(false);
+        http.set_connect_timeout(Some(connect_timeout));
+
+        Self::from_client(HyperClient::Default(
+            Client::builder().build(HttpsConnector::new_with_connector(http)),
+        ))
+    }
+
+    /// Creates a new client resolving host names with `resolve` instead of the system resolver, for example to
+    /// address push relays by a service discovery name.
+    ///
+    /// `resolve` gets the host name of the endpoint, and returns the addresses to try in order. The port of the
+    /// endpoint is used for all of them.
+    ///
+    /// ```no_run
+    /// # use std::net::SocketAddr;
+    /// # use web_push::HyperWebPushClient;
+    /// let client = HyperWebPushClient::new_with_resolver(|host| async move {
+    ///     match host.as_str() {
+    ///         "push-relay" => Ok(vec![SocketAddr::from(([10, 0, 0, 7], 0))]),
+    ///         _ => tokio::net::lookup_host((host, 0)).await.map(|addrs| addrs.collect()),
+    ///     }
+    /// });
+    /// ```
+    pub fn new_with_resolver<F, R>(resolve: F) -> Self
+    where
+        F: Fn(String) -> R + Send + Sync + 'static,
+        R: Future<Output = io::Result<Vec<SocketAddr>>> + Send + 'static,
+    {
+        let resolver = FnResolver(Arc::new(move |host| Box::pin(resolve(host))));
+
+        let mut http = HttpConnector::new_with_resolver(resolver);
+        http.enforce_http(false);
+
+        Self::from_client(HyperClient::Resolved(
+            Client::builder().build(HttpsConnector::new_with_connector(http)),
+        ))
+    }
+
+    /// Creates a new client refusing TLS versions older than `min_version`, e.g. `TlsVersion::Tlsv13` where
+    /// compliance requires TLS 1.3. All the big push services support TLS 1.3. The client from
+    /// [`HyperWebPushClient::new`] accepts the defaults of the system TLS library.
+    ///
+    /// ```no_run
+    /// # use web_push::{HyperWebPushClient, TlsVersion};
+    /// let client = HyperWebPushClient::new_with_min_tls_version(TlsVersion::Tlsv13).unwrap();
+    /// ```
+    pub fn new_with_min_tls_version(min_version: native_tls::Protocol) -> Result<Self, WebPushError> {
+        let mut http = HttpConnector::new();
+        http.enforce_http(false);
+
+        let tls = native_tls::TlsConnector::builder()
+            .min_protocol_version(Some(min_version))
+            .build()
+            .map_err(|_| WebPushError::Unspecified)?;
+
+        Ok(Self::from_client(HyperClient::Default(Client::builder().build(
+            HttpsConnector::from((http, tokio_native_tls::TlsConnector::from(tls))),
+        ))))
+    }
+
+    /// Creates a new client that only speaks HTTP/2, negotiated with ALPN. Requires the `hyper-http2` feature.
+    ///
+    /// All sends to the same push service host are multiplexed over one connection, which is a throughput win when
+    /// fanning out to many subscriptions of a service like FCM. Only use this if all of your push services support
+    /// HTTP/2, as the connection fails otherwise. The client from [`HyperWebPushClient::new`] keeps using HTTP/1.
+    #[cfg(feature = "hyper-http2")]
+    pub fn new_http2() -> Result<Self, WebPushError> {
+        let mut http = HttpConnector::new();
+        http.enforce_http(false);
+
+        let tls = native_tls::TlsConnector::builder()
+            .request_alpns(&["h2"])
+            .build()
+            .map_err(|_| WebPushError::Unspecified)?;
+
+        Ok(Self::from_client(HyperClient::Default(
+            Client::builder()
+                .http2_only(true)
+                .build(HttpsConnector::from((http, tokio_native_tls::TlsConnector::from(tls)))),
+        )))
+    }
+
+    /// The client with all options at their defaults, on top of `client`.
+    fn from_client(client: HyperClient) -> Self {
         Self {
-            client: Client::builder().build(HttpsConnector::new()),
+            client,
+            content_length_header: true,
+            timeout: None,
+            on_complete: None,
+            default_urgency: None,
+            concurrency_limit: None,
+            base_url_override: None,
         }
     }
-}
 
-#[async_trait]
-impl WebPushClient for HyperWebPushClient {
-    /// Sends a notification. Never times out.
-    async fn send(&self, message: WebPushMessage) -> Result<(), WebPushError> {
-        trace!("Message: {:?}", message);
+    /// Stops adding an explicit `Content-Length` header to requests, leaving it to the HTTP layer. Needed for some
+    /// gateways that reject a manually set `Content-Length`.
+    pub fn without_content_length(mut self) -> Self {
+        self.content_length_header = false;
+        self
+    }
+
+    /// Sets the [timeout](crate::WebPushClient#options) of the whole request.
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    /// Sets the [default urgency](crate::WebPushClient#options) of messages built without one.
+    pub fn with_default_urgency(mut self, urgency: Urgency) -> Self {
+        self.default_urgency = Some(urgency);
+        self
+    }
+
+    /// Sets the [maximum of concurrent requests](crate::WebPushClient#options) of the client and its clones.
+    pub fn set_max_concurrent_requests(mut self, max_concurrent_requests: NonZeroUsize) -> Self {
+        self.concurrency_limit = Some(Arc::new(Semaphore::new(max_concurrent_requests.get())));
+        self
+    }
+
+    /// Sends every request to the scheme, host and port of `base_url` instead of those of the endpoint, keeping
+    /// the path and query. For integration tests against a local server such as `TestPushServer`, without faking
+    /// subscriptions. Fails with `InvalidUri` if `base_url` has a path. Defaults to sending to the endpoint.
+    pub fn with_base_url_override(mut self, base_url: &str) -> Result<Self, WebPushError> {
+        self.base_url_override = Some(BaseUrlOverride::parse(base_url)?);
+        Ok(self)
+    }
+
+    /// Calls `on_complete` after every send, successful or not, with the host, duration and result. Useful for
+    /// metrics. Defaults to no hook.
+    pub fn with_on_complete<F>(mut self, on_complete: F) -> Self
+    where
+        F: Fn(&SendReport<'_>) + Send + Sync + 'static,
+    {
+        self.on_complete = Some(Arc::new(on_complete));
+        self
+    }
+
+    /// Sends a notification like [`WebPushClient::send`], and returns what was put on the wire. Only times out if
+    /// configured with [`HyperWebPushClient::new_with_connect_timeout`] or [`HyperWebPushClient::with_timeout`], or
+    /// if the message has a deadline.
+    pub async fn send_accounted(&self, message: WebPushMessage) -> Result<SendStats, WebPushError> {
+        let _permit = self.acquire_permit().await?;
+
+        let endpoint = message.endpoint.clone();
+        let started = Instant::now();
+
+        let result = match clients::send_timeout(self.timeout, message.deadline) {
+            Some(timeout) if timeout.is_zero() => Err(WebPushError::Timeout),
+            Some(timeout) => tokio::time::timeout(timeout, self.send_accounted_inner(message))
+                .await
+                .unwrap_or(Err(WebPushError::Timeout)),
+            None => self.send_accounted_inner(message).await,
+        };
+
+        clients::report_completion(&self.on_complete, &endpoint, started, &result);
+
+        result
+    }
+
+    async fn send_accounted_inner(&self, mut message: WebPushMessage) -> Result<SendStats, WebPushError> {
+        message.sign_deferred_vapid()?;
+
+        let context = SendContext::new(&message.endpoint);
+
+        trace!("{} Message: {:?}", context, message);
+
+        let endpoint = message.endpoint.clone();
+        let body_len = message.payload.as_ref().map_or(0, |payload| payload.content.len());
 
-        let request: HttpRequest<Body> = request_builder::build_request(message);
+        let request = self.build_request(message);
+        let headers_len = clients::headers_len(request.headers());
 
-        debug!("Request: {:?}", request);
+        debug!("{} Request: {:?}", context, request);
 
         let requesting = self.client.request(request);
 
         let response = requesting.await?;
 
-        trace!("Response: {:?}", response);
-
-        let retry_after = response
-            .headers()
-            .get(RETRY_AFTER)
-            .and_then(|ra| ra.to_str().ok())
-            .and_then(RetryAfter::from_str);
+        trace!("{} Response: {:?}", context, response);
 
         let response_status = response.status();
-        trace!("Response status: {}", response_status);
+        let response_headers = response.headers().clone();
+        trace!("{} Response status: {}", context, response_status);
 
         let content_length: usize = response
             .headers()
@@ -79,22 +265,212 @@ impl WebPushClient for HyperWebPushClient {
         while let Some(chunk) = chunks.data().await {
             body.extend(&chunk?);
         }
-        trace!("Body: {:?}", body);
+        trace!("{} Body: {:?}", context, body);
+
+        trace!("{} Body text: {:?}", context, std::str::from_utf8(&body));
+
+        let response =
+            request_builder::consume_response_from(&endpoint, response_status, &response_headers, body.to_vec());
+
+        debug!("{} Response: {:?}", context, response);
+
+        response.map(|()| SendStats {
+            body_len,
+            headers_len,
+            status: response_status,
+        })
+    }
+
+    /// Waits for a free slot if the number of concurrent requests is limited. The slot is freed with the permit.
+    async fn acquire_permit(&self) -> Result<Option<SemaphorePermit<'_>>, WebPushError> {
+        match &self.concurrency_limit {
+            Some(limit) => Ok(Some(limit.acquire().await.map_err(|_| WebPushError::Cancelled)?)),
+            None => Ok(None),
+        }
+    }
+
+    fn build_request(&self, mut message: WebPushMessage) -> HttpRequest<Body> {
+        if message.urgency.is_none() {
+            message.urgency = self.default_urgency;
+        }
+
+        let mut request = request_builder::build_request(message);
+
+        if let Some(base_url) = &self.base_url_override {
+            *request.uri_mut() = base_url.apply(request.uri());
+        }
 
-        trace!("Body text: {:?}", std::str::from_utf8(&body));
+        if !self.content_length_header {
+            request.headers_mut().remove(CONTENT_LENGTH);
+        }
 
-        let response = request_builder::parse_response(response_status, body.to_vec());
+        request
+    }
+}
 
-        debug!("Response: {:?}", response);
+/// The hyper client, with the system or a custom resolver.
+#[derive(Clone)]
+enum HyperClient {
+    Default(Client<HttpsConnector<HttpConnector>>),
+    Resolved(Client<HttpsConnector<HttpConnector<FnResolver>>>),
+}
 
-        if let Err(WebPushError::ServerError {
-            retry_after: None,
-            info,
-        }) = response
-        {
-            Err(WebPushError::ServerError { retry_after, info })
-        } else {
-            Ok(response?)
+impl HyperClient {
+    fn request(&self, request: HttpRequest<Body>) -> ResponseFuture {
+        match self {
+            HyperClient::Default(client) => client.request(request),
+            HyperClient::Resolved(client) => client.request(request),
         }
     }
 }
+
+type Resolving = Pin<Box<dyn Future<Output = io::Result<Vec<SocketAddr>>> + Send>>;
+
+/// A resolver calling the function given to [`HyperWebPushClient::new_with_resolver`].
+#[derive(Clone)]
+struct FnResolver(Arc<dyn Fn(String) -> Resolving + Send + Sync>);
+
+impl Service<Name> for FnResolver {
+    type Response = std::vec::IntoIter<SocketAddr>;
+    type Error = io::Error;
+    type Future = Pin<Box<dyn Future<Output = io::Result<Self::Response>> + Send>>;
+
+    fn poll_ready(&mut self, _: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, name: Name) -> Self::Future {
+        let resolving = (self.0)(name.as_str().to_string());
+
+        Box::pin(async move { resolving.await.map(Vec::into_iter) })
+    }
+}
+
+#[async_trait]
+impl WebPushClient for HyperWebPushClient {
+    /// Sends a notification. Only times out if configured.
+    async fn send(&self, message: WebPushMessage) -> Result<(), WebPushError> {
+        self.send_accounted(message).await.map(|_| ())
+    }
+
+    /// Sends a `HEAD` request to the host, or the base URL override if set, leaving the connection in the pool. The
+    /// response status is ignored. Counts against the maximum of concurrent requests.
+    async fn warm_up(&self, host: &str) -> Result<(), WebPushError> {
+        let mut uri = clients::warm_up_uri(host)?;
+
+        if let Some(base_url) = &self.base_url_override {
+            uri = base_url.apply(&uri);
+        }
+
+        let _permit = self.acquire_permit().await?;
+        let request = HttpRequest::head(uri)
+            .body(Body::empty())
+            .map_err(|_| WebPushError::InvalidUri)?;
+
+        let requesting = self.client.request(request);
+
+        match self.timeout {
+            Some(timeout) => tokio::time::timeout(timeout, requesting)
+                .await
+                .map_err(|_| WebPushError::Timeout)??,
+            None => requesting.await?,
+        };
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use crate::clients::hyper_client::HyperWebPushClient;
+    use crate::error::WebPushError;
+    use crate::message::test_message;
+
+    #[cfg(feature = "hyper-http2")]
+    #[test]
+    fn creates_an_http2_client() {
+        assert!(HyperWebPushClient::new_http2().is_ok());
+    }
+
+    #[test]
+    fn creates_a_tls13_only_client() {
+        assert!(HyperWebPushClient::new_with_min_tls_version(native_tls::Protocol::Tlsv13).is_ok());
+    }
+
+    #[tokio::test]
+    async fn resolves_hosts_with_a_custom_resolver() {
+        use std::io::{Read, Write};
+
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let server = std::thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut request = [0u8; 1024];
+            let _ = stream.read(&mut request).unwrap();
+            stream
+                .write_all(b"HTTP/1.1 201 Created\r\nContent-Length: 0\r\n\r\n")
+                .unwrap();
+        });
+
+        let client = HyperWebPushClient::new_with_resolver(move |host| async move {
+            match host.as_str() {
+                "push-relay" => Ok(vec![addr]),
+                _ => Err(std::io::ErrorKind::NotFound.into()),
+            }
+        });
+        let message = test_message(&format!("http://push-relay:{}/push", addr.port()));
+
+        assert!(client.send_accounted(message).await.is_ok());
+
+        server.join().unwrap();
+    }
+
+    #[tokio::test]
+    async fn times_out_a_stalled_request() {
+        // Accepts the connection, but never responds.
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let endpoint = format!("http://{}/push", listener.local_addr().unwrap());
+
+        let client = HyperWebPushClient::new_with_connect_timeout(Duration::from_secs(5))
+            .with_timeout(Duration::from_millis(100));
+        let message = test_message(&endpoint);
+
+        assert!(matches!(
+            client.send_accounted(message).await,
+            Err(WebPushError::Timeout)
+        ));
+
+        drop(listener);
+    }
+
+    #[tokio::test]
+    async fn warms_up_the_base_url_override() {
+        use std::io::{Read, Write};
+
+        use crate::clients::WebPushClient;
+
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let server = std::thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut request = [0u8; 1024];
+            let len = stream.read(&mut request).unwrap();
+            stream
+                .write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n")
+                .unwrap();
+
+            String::from_utf8_lossy(&request[..len]).into_owned()
+        });
+
+        let client = HyperWebPushClient::new()
+            .with_base_url_override(&format!("http://{}", addr))
+            .unwrap();
+
+        assert!(client.warm_up("fcm.googleapis.com").await.is_ok());
+        assert!(server.join().unwrap().starts_with("HEAD / HTTP/1.1"));
+    }
+}
@@ -1,12 +1,18 @@
 use async_trait::async_trait;
 use futures_lite::AsyncReadExt;
-use http::header::{CONTENT_LENGTH, RETRY_AFTER};
+use http::header::CONTENT_LENGTH;
+use http::Request;
+use isahc::config::Configurable;
 use isahc::HttpClient;
+use std::num::NonZeroUsize;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::{Semaphore, SemaphorePermit};
 
 use crate::clients::request_builder;
-use crate::clients::WebPushClient;
-use crate::error::{RetryAfter, WebPushError};
-use crate::message::WebPushMessage;
+use crate::clients::{self, BaseUrlOverride, CompletionHook, SendContext, SendReport, SendStats, WebPushClient};
+use crate::error::WebPushError;
+use crate::message::{Urgency, WebPushMessage};
 
 /// An async client for sending the notification payload. This client is expensive to create, and
 /// should be reused.
@@ -18,6 +24,13 @@ use crate::message::WebPushMessage;
 #[derive(Clone)]
 pub struct IsahcWebPushClient {
     client: HttpClient,
+    content_length_header: bool,
+    connect_timeout: Option<Duration>,
+    timeout: Option<Duration>,
+    on_complete: Option<CompletionHook>,
+    default_urgency: Option<Urgency>,
+    concurrency_limit: Option<Arc<Semaphore>>,
+    base_url_override: Option<BaseUrlOverride>,
 }
 
 impl Default for IsahcWebPushClient {
@@ -29,7 +42,16 @@ impl Default for IsahcWebPushClient {
 impl From<HttpClient> for IsahcWebPushClient {
     /// Creates a new client from a custom Isahc HTTP client.
     fn from(client: HttpClient) -> Self {
-        Self { client }
+        Self {
+            client,
+            content_length_header: true,
+            connect_timeout: None,
+            timeout: None,
+            on_complete: None,
+            default_urgency: None,
+            concurrency_limit: None,
+            base_url_override: None,
+        }
     }
 }
 
@@ -38,34 +60,114 @@ impl IsahcWebPushClient {
     pub fn new() -> Result<Self, WebPushError> {
         Ok(Self {
             client: HttpClient::new()?,
+            content_length_header: true,
+            connect_timeout: None,
+            timeout: None,
+            on_complete: None,
+            default_urgency: None,
+            concurrency_limit: None,
+            base_url_override: None,
         })
     }
-}
 
-#[async_trait]
-impl WebPushClient for IsahcWebPushClient {
-    /// Sends a notification. Never times out.
-    async fn send(&self, message: WebPushMessage) -> Result<(), WebPushError> {
-        trace!("Message: {:?}", message);
+    /// Stops adding an explicit `Content-Length` header to requests, leaving it to the HTTP layer. Needed for some
+    /// gateways that reject a manually set `Content-Length`.
+    pub fn without_content_length(mut self) -> Self {
+        self.content_length_header = false;
+        self
+    }
 
-        let request = request_builder::build_request::<isahc::AsyncBody>(message);
+    /// Sets the maximum time to connect to the push service, including DNS resolution and the TLS handshake. A
+    /// breach is returned as `ConnectionFailed`. Defaults to no timeout.
+    pub fn with_connect_timeout(mut self, connect_timeout: Duration) -> Self {
+        self.connect_timeout = Some(connect_timeout);
+        self
+    }
 
-        trace!("Request: {:?}", request);
+    /// Sets the [timeout](crate::WebPushClient#options) of the whole request.
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
 
-        let requesting = self.client.send_async(request);
+    /// Sets the [default urgency](crate::WebPushClient#options) of messages built without one.
+    pub fn with_default_urgency(mut self, urgency: Urgency) -> Self {
+        self.default_urgency = Some(urgency);
+        self
+    }
 
-        let response = requesting.await?;
+    /// Sets the [maximum of concurrent requests](crate::WebPushClient#options) of the client and its clones.
+    pub fn set_max_concurrent_requests(mut self, max_concurrent_requests: NonZeroUsize) -> Self {
+        self.concurrency_limit = Some(Arc::new(Semaphore::new(max_concurrent_requests.get())));
+        self
+    }
 
-        trace!("Response: {:?}", response);
+    /// Sends every request to the scheme, host and port of `base_url` instead of those of the endpoint, keeping
+    /// the path and query. For integration tests against a local server such as `TestPushServer`, without faking
+    /// subscriptions. Fails with `InvalidUri` if `base_url` has a path. Defaults to sending to the endpoint.
+    pub fn with_base_url_override(mut self, base_url: &str) -> Result<Self, WebPushError> {
+        self.base_url_override = Some(BaseUrlOverride::parse(base_url)?);
+        Ok(self)
+    }
 
-        let retry_after = response
-            .headers()
-            .get(RETRY_AFTER)
-            .and_then(|ra| ra.to_str().ok())
-            .and_then(RetryAfter::from_str);
+    /// Calls `on_complete` after every send, successful or not, with the host, duration and result. Useful for
+    /// metrics. Defaults to no hook.
+    pub fn with_on_complete<F>(mut self, on_complete: F) -> Self
+    where
+        F: Fn(&SendReport<'_>) + Send + Sync + 'static,
+    {
+        self.on_complete = Some(Arc::new(on_complete));
+        self
+    }
+
+    /// Sends a notification like [`WebPushClient::send`], and returns what was put on the wire. Only times out if
+    /// configured with [`IsahcWebPushClient::with_connect_timeout`] or [`IsahcWebPushClient::with_timeout`], or if
+    /// the message has a deadline.
+    pub async fn send_accounted(&self, message: WebPushMessage) -> Result<SendStats, WebPushError> {
+        let _permit = self.acquire_permit().await?;
+
+        let endpoint = message.endpoint.clone();
+        let started = Instant::now();
+
+        let result = match message.deadline {
+            Some(deadline) if Instant::now() >= deadline => Err(WebPushError::Timeout),
+            _ => self.send_accounted_inner(message).await,
+        };
+
+        clients::report_completion(&self.on_complete, &endpoint, started, &result);
+
+        result
+    }
+
+    async fn send_accounted_inner(&self, mut message: WebPushMessage) -> Result<SendStats, WebPushError> {
+        message.sign_deferred_vapid()?;
+
+        let context = SendContext::new(&message.endpoint);
+
+        trace!("{} Message: {:?}", context, message);
+
+        let endpoint = message.endpoint.clone();
+        let deadline = message.deadline;
+        let body_len = message.payload.as_ref().map_or(0, |payload| payload.content.len());
+
+        let request = self.build_request(message);
+        let headers_len = clients::headers_len(request.headers());
+
+        trace!("{} Request: {:?}", context, request);
+
+        let started = Instant::now();
+        let requesting = self.client.send_async(request);
+
+        let response = requesting.await.map_err(|err| match deadline {
+            Some(deadline) if err.is_timeout() && Instant::now() >= deadline => WebPushError::Timeout,
+            _ => self.send_error(err, started.elapsed()),
+        })?;
+
+        trace!("{} Response: {:?}", context, response);
 
         let response_status = response.status();
-        trace!("Response status: {}", response_status);
+        let response_headers = response.headers().clone();
+        trace!("{} Response status: {}", context, response_status);
 
         let content_length: usize = response
             .headers()
@@ -82,22 +184,364 @@ impl WebPushClient for IsahcWebPushClient {
             .await
             .map_err(|_| WebPushError::InvalidResponse)?;
 
-        trace!("Body: {:?}", body);
+        trace!("{} Body: {:?}", context, body);
 
-        trace!("Body text: {:?}", std::str::from_utf8(&body));
+        trace!("{} Body text: {:?}", context, std::str::from_utf8(&body));
 
-        let response = request_builder::parse_response(response_status, body.to_vec());
+        let response =
+            request_builder::consume_response_from(&endpoint, response_status, &response_headers, body.to_vec());
 
-        trace!("Response: {:?}", response);
+        trace!("{} Response: {:?}", context, response);
 
-        if let Err(WebPushError::ServerError {
-            retry_after: None,
-            info,
-        }) = response
-        {
-            Err(WebPushError::ServerError { retry_after, info })
+        response.map(|()| SendStats {
+            body_len,
+            headers_len,
+            status: response_status,
+        })
+    }
+
+    /// Waits for a free slot if the number of concurrent requests is limited. The slot is freed with the permit.
+    async fn acquire_permit(&self) -> Result<Option<SemaphorePermit<'_>>, WebPushError> {
+        match &self.concurrency_limit {
+            Some(limit) => Ok(Some(limit.acquire().await.map_err(|_| WebPushError::Cancelled)?)),
+            None => Ok(None),
+        }
+    }
+
+    fn build_request(&self, mut message: WebPushMessage) -> Request<isahc::AsyncBody> {
+        if message.urgency.is_none() {
+            message.urgency = self.default_urgency;
+        }
+
+        let timeout = clients::send_timeout(self.timeout, message.deadline);
+        let mut request = request_builder::build_request(message);
+
+        if let Some(base_url) = &self.base_url_override {
+            *request.uri_mut() = base_url.apply(request.uri());
+        }
+
+        if !self.content_length_header {
+            request.headers_mut().remove(CONTENT_LENGTH);
+        }
+
+        if self.connect_timeout.is_none() && timeout.is_none() {
+            return request;
+        }
+
+        let (parts, body) = request.into_parts();
+        let mut builder = Request::builder()
+            .method(parts.method)
+            .uri(parts.uri)
+            .version(parts.version);
+
+        if let Some(headers) = builder.headers_mut() {
+            *headers = parts.headers;
+        }
+
+        if let Some(connect_timeout) = self.connect_timeout {
+            builder = builder.connect_timeout(connect_timeout);
+        }
+
+        if let Some(timeout) = timeout {
+            builder = builder.timeout(timeout);
+        }
+
+        builder.body(body).unwrap()
+    }
+
+    /// Curl reports both timeouts the same way, so a timeout before the total timeout must be the connect timeout.
+    fn send_error(&self, err: isahc::Error, elapsed: Duration) -> WebPushError {
+        if err.is_timeout() && self.is_connect_timeout(elapsed) {
+            WebPushError::ConnectionFailed(err.to_string())
         } else {
-            Ok(response?)
+            err.into()
+        }
+    }
+
+    fn is_connect_timeout(&self, elapsed: Duration) -> bool {
+        self.connect_timeout.is_some() && self.timeout.is_none_or(|timeout| elapsed < timeout)
+    }
+}
+
+#[async_trait]
+impl WebPushClient for IsahcWebPushClient {
+    /// Sends a notification. Only times out if configured.
+    async fn send(&self, message: WebPushMessage) -> Result<(), WebPushError> {
+        self.send_accounted(message).await.map(|_| ())
+    }
+
+    /// Sends a `HEAD` request to the host, or the base URL override if set, leaving the connection in the pool. The
+    /// response status is ignored. Counts against the maximum of concurrent requests.
+    async fn warm_up(&self, host: &str) -> Result<(), WebPushError> {
+        let mut uri = clients::warm_up_uri(host)?;
+
+        if let Some(base_url) = &self.base_url_override {
+            uri = base_url.apply(&uri);
+        }
+
+        let _permit = self.acquire_permit().await?;
+        let request = Request::head(uri).body(()).map_err(|_| WebPushError::InvalidUri)?;
+
+        let started = Instant::now();
+
+        self.client
+            .send_async(request)
+            .await
+            .map(|_| ())
+            .map_err(|err| self.send_error(err, started.elapsed()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use http::header::CONTENT_LENGTH;
+
+    use crate::clients::isahc_client::IsahcWebPushClient;
+    use crate::error::WebPushError;
+    use crate::http_ece::ContentEncoding;
+    use crate::message::{SubscriptionInfo, Urgency, WebPushMessageBuilder};
+
+    fn message() -> crate::WebPushMessage {
+        let info = SubscriptionInfo::new(
+            "https://fcm.googleapis.com/fcm/send/eKClHsXFm9E",
+            "BGa4N1PI79lboMR_YrwCiCsgp35DRvedt7opHcf0yM3iOBTSoQYqQLwWxAfRKE6tsDnReWmhsImkhDF_DBdkNSU",
+            "EvcWjEgzr4rbvhfi3yds0A",
+        );
+        let mut builder = WebPushMessageBuilder::new(&info);
+        builder.set_payload(ContentEncoding::Aes128Gcm, "test".as_bytes());
+
+        builder.build().unwrap()
+    }
+
+    #[test]
+    fn tells_connect_timeouts_from_total_timeouts() {
+        let client = IsahcWebPushClient::new()
+            .unwrap()
+            .with_connect_timeout(Duration::from_secs(1))
+            .with_timeout(Duration::from_secs(10));
+
+        assert!(client.is_connect_timeout(Duration::from_secs(1)));
+        assert!(!client.is_connect_timeout(Duration::from_secs(10)));
+        assert!(!IsahcWebPushClient::new()
+            .unwrap()
+            .with_timeout(Duration::from_secs(10))
+            .is_connect_timeout(Duration::from_secs(1)));
+    }
+
+    #[test]
+    fn keeps_headers_when_setting_timeouts() {
+        let client = IsahcWebPushClient::new()
+            .unwrap()
+            .with_connect_timeout(Duration::from_secs(1))
+            .with_timeout(Duration::from_secs(10));
+        let request = client.build_request(message());
+
+        assert_eq!("230", request.headers()[CONTENT_LENGTH]);
+        assert_eq!("aes128gcm", request.headers()["Content-Encoding"]);
+        assert_eq!("https://fcm.googleapis.com/fcm/send/eKClHsXFm9E", request.uri());
+    }
+
+    #[test]
+    fn redirects_requests_to_the_base_url_override() {
+        let client = IsahcWebPushClient::new()
+            .unwrap()
+            .with_base_url_override("http://127.0.0.1:8080")
+            .unwrap()
+            .with_timeout(Duration::from_secs(10));
+        let request = client.build_request(message());
+
+        assert_eq!("http://127.0.0.1:8080/fcm/send/eKClHsXFm9E", request.uri());
+        assert_eq!("aes128gcm", request.headers()["Content-Encoding"]);
+        assert!(IsahcWebPushClient::new()
+            .unwrap()
+            .with_base_url_override("http://127.0.0.1:8080/push")
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn fails_without_sending_once_the_deadline_passed() {
+        use std::time::Instant;
+
+        use crate::clients::WebPushClient;
+
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        listener.set_nonblocking(true).unwrap();
+
+        let mut message = message();
+        message.endpoint = format!("http://{}/push", listener.local_addr().unwrap())
+            .parse()
+            .unwrap();
+        message.deadline = Some(Instant::now());
+
+        let started = Instant::now();
+        let result = IsahcWebPushClient::new().unwrap().send(message).await;
+
+        assert!(matches!(result, Err(WebPushError::Timeout)));
+        assert!(started.elapsed() < Duration::from_secs(1));
+        assert!(listener.accept().is_err());
+    }
+
+    #[tokio::test]
+    async fn caps_concurrent_requests() {
+        use std::io::{Read, Write};
+        use std::num::NonZeroUsize;
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        use std::sync::Arc;
+
+        use crate::clients::WebPushClient;
+
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let in_flight = Arc::new(AtomicUsize::new(0));
+        let max_in_flight = Arc::new(AtomicUsize::new(0));
+
+        {
+            let in_flight = in_flight.clone();
+            let max_in_flight = max_in_flight.clone();
+
+            std::thread::spawn(move || {
+                for stream in listener.incoming().take(6) {
+                    let mut stream = stream.unwrap();
+                    let in_flight = in_flight.clone();
+                    let max_in_flight = max_in_flight.clone();
+
+                    std::thread::spawn(move || {
+                        let mut request = [0u8; 1024];
+                        let _ = stream.read(&mut request).unwrap();
+
+                        max_in_flight.fetch_max(in_flight.fetch_add(1, Ordering::SeqCst) + 1, Ordering::SeqCst);
+                        std::thread::sleep(Duration::from_millis(200));
+                        in_flight.fetch_sub(1, Ordering::SeqCst);
+
+                        stream
+                            .write_all(b"HTTP/1.1 201 Created\r\nContent-Length: 0\r\nConnection: close\r\n\r\n")
+                            .unwrap();
+                    });
+                }
+            });
         }
+
+        let client = IsahcWebPushClient::new()
+            .unwrap()
+            .set_max_concurrent_requests(NonZeroUsize::new(2).unwrap());
+        let info = SubscriptionInfo::new(
+            format!("http://{}/push", addr).as_str(),
+            "BGa4N1PI79lboMR_YrwCiCsgp35DRvedt7opHcf0yM3iOBTSoQYqQLwWxAfRKE6tsDnReWmhsImkhDF_DBdkNSU",
+            "EvcWjEgzr4rbvhfi3yds0A",
+        );
+
+        let sends: Vec<_> = (0..6)
+            .map(|_| {
+                let client = client.clone();
+                let message = WebPushMessageBuilder::new(&info).build().unwrap();
+
+                tokio::spawn(async move { client.send(message).await })
+            })
+            .collect();
+
+        for send in sends {
+            assert!(send.await.unwrap().is_ok());
+        }
+
+        assert_eq!(2, max_in_flight.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn sets_content_length_by_default() {
+        let client = IsahcWebPushClient::new().unwrap();
+
+        assert_eq!("230", client.build_request(message()).headers()[CONTENT_LENGTH]);
+    }
+
+    #[test]
+    fn applies_the_default_urgency() {
+        let client = IsahcWebPushClient::new().unwrap().with_default_urgency(Urgency::Low);
+
+        assert_eq!("low", client.build_request(message()).headers()["Urgency"]);
+
+        let mut urgent = message();
+        urgent.urgency = Some(Urgency::High);
+
+        assert_eq!("high", client.build_request(urgent).headers()["Urgency"]);
+        assert!(IsahcWebPushClient::new()
+            .unwrap()
+            .build_request(message())
+            .headers()
+            .get("Urgency")
+            .is_none());
+    }
+
+    #[test]
+    fn omits_content_length_when_configured() {
+        let client = IsahcWebPushClient::new().unwrap().without_content_length();
+        let request = client.build_request(message());
+
+        assert!(request.headers().get(CONTENT_LENGTH).is_none());
+        assert_eq!(Some(230), request.body().len());
+    }
+
+    #[tokio::test]
+    async fn warms_up_the_base_url_override() {
+        use std::io::{Read, Write};
+
+        use crate::clients::WebPushClient;
+
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let server = std::thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut request = [0u8; 1024];
+            let len = stream.read(&mut request).unwrap();
+            stream
+                .write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n")
+                .unwrap();
+
+            String::from_utf8_lossy(&request[..len]).into_owned()
+        });
+
+        let client = IsahcWebPushClient::new()
+            .unwrap()
+            .with_base_url_override(&format!("http://{}", addr))
+            .unwrap();
+
+        assert!(client.warm_up("fcm.googleapis.com").await.is_ok());
+        assert!(server.join().unwrap().starts_with("HEAD / HTTP/1.1"));
+    }
+
+    #[async_std::test]
+    async fn sends_on_async_std() {
+        use std::io::{Read, Write};
+        use std::num::NonZeroUsize;
+
+        use crate::clients::WebPushClient;
+        use crate::message::test_message;
+
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let server = std::thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut request = [0u8; 1024];
+            let len = stream.read(&mut request).unwrap();
+            stream
+                .write_all(b"HTTP/1.1 201 Created\r\nContent-Length: 0\r\n\r\n")
+                .unwrap();
+
+            String::from_utf8_lossy(&request[..len]).into_owned()
+        });
+
+        // No Tokio runtime is running here, the concurrency limit included.
+        let client = IsahcWebPushClient::new()
+            .unwrap()
+            .set_max_concurrent_requests(NonZeroUsize::new(1).unwrap());
+
+        assert!(client
+            .send(test_message(&format!("http://{}/push", addr)))
+            .await
+            .is_ok());
+        assert!(server.join().unwrap().starts_with("POST /push HTTP/1.1"));
     }
 }
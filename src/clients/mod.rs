@@ -3,10 +3,31 @@
 //! [`request_builder`] contains the functions used to send and consume push http messages.
 //! This module should be consumed by each client, by using [`http`]'s flexible api.
 
+#[cfg(any(feature = "isahc-client", feature = "hyper-client"))]
+use std::fmt;
+#[cfg(any(feature = "isahc-client", feature = "hyper-client"))]
+use std::sync::atomic::{AtomicU64, Ordering};
+#[cfg(any(feature = "isahc-client", feature = "hyper-client"))]
+use std::sync::Arc;
+use std::time::Duration;
+#[cfg(any(feature = "isahc-client", feature = "hyper-client"))]
+use std::time::Instant;
+
 use crate::{WebPushError, WebPushMessage};
 use async_trait::async_trait;
+#[cfg(any(feature = "isahc-client", feature = "hyper-client"))]
+use http::uri::{Authority, Scheme};
+#[cfg(any(feature = "isahc-client", feature = "hyper-client"))]
+use http::HeaderMap;
+use http::{StatusCode, Uri};
+#[cfg(feature = "cancellation")]
+use std::{future::Future, task::Poll};
+#[cfg(feature = "cancellation")]
+use tokio_util::sync::CancellationToken;
 
+pub mod rate_limit;
 pub mod request_builder;
+pub mod retry;
 
 #[cfg(feature = "hyper-client")]
 pub mod hyper_client;
@@ -16,8 +37,536 @@ pub mod isahc_client;
 
 /// An async client for sending the notification payload.
 /// Other features, such as thread safety, may vary by implementation.
+///
+/// # Options
+///
+/// The bundled clients share these options, all unset by default:
+///
+/// - **Timeout**: the maximum time for the whole request, from connecting until the response is received. A breach
+///   is returned as `Timeout`.
+/// - **Default urgency**: the `Urgency` header sent for messages built without one. An urgency set with
+///   [`WebPushMessageBuilder::set_urgency`](crate::WebPushMessageBuilder::set_urgency) takes precedence.
+/// - **Max concurrent requests**: at most this many sends are in flight at once, the others wait for their turn.
+///   Guards a push service against floods of connections when thousands of tasks send through the client. The limit
+///   is shared with the clones of the client. Waiting uses the semaphore of `tokio::sync`, which doesn't need a Tokio
+///   runtime.
 #[async_trait]
 pub trait WebPushClient {
     /// Sends a notification. Never times out.
     async fn send(&self, message: WebPushMessage) -> Result<(), WebPushError>;
+
+    /// Sends a notification, aborting with `Cancelled` when `cancel` fires. Requires the `cancellation` feature.
+    ///
+    /// Cancelling stops the send at its next await point and drops the connection. If the push service already
+    /// received the request, the notification may still be delivered, so cancellation is best-effort.
+    #[cfg(feature = "cancellation")]
+    async fn send_cancellable(&self, message: WebPushMessage, cancel: CancellationToken) -> Result<(), WebPushError> {
+        if cancel.is_cancelled() {
+            return Err(WebPushError::Cancelled);
+        }
+
+        let mut sending = self.send(message);
+        let mut cancelled = std::pin::pin!(cancel.cancelled());
+
+        std::future::poll_fn(|cx| {
+            if cancelled.as_mut().poll(cx).is_ready() {
+                return Poll::Ready(Err(WebPushError::Cancelled));
+            }
+
+            sending.as_mut().poll(cx)
+        })
+        .await
+    }
+
+    /// Sends a notification like [`WebPushClient::send`], and classifies the result by how it should be handled.
+    ///
+    /// Encodes the usual handling once: delete the subscription on [`SendOutcome::SubscriptionGone`], retry later
+    /// on [`SendOutcome::Transient`], and give up on [`SendOutcome::Fatal`].
+    async fn send_tracking(&self, message: WebPushMessage) -> SendOutcome {
+        match self.send(message).await {
+            Ok(()) => SendOutcome::Delivered,
+            Err(error) if error.is_subscription_dead() => SendOutcome::SubscriptionGone,
+            Err(error) if error.is_transient() => SendOutcome::Transient(error),
+            Err(error) => SendOutcome::Fatal(error),
+        }
+    }
+
+    /// Sends all `messages` one after another, for sending a campaign and then pruning the subscriptions that are
+    /// gone.
+    ///
+    /// Returns the result of every send in the order of `messages`, and the indices of the messages whose
+    /// subscription should be deleted, see [`WebPushError::is_subscription_dead`]. Sends run sequentially, spawn
+    /// several calls on chunks of the messages to send concurrently.
+    async fn send_all_collecting_dead(
+        &self,
+        messages: Vec<WebPushMessage>,
+    ) -> (Vec<Result<(), WebPushError>>, Vec<usize>) {
+        let mut results = Vec::with_capacity(messages.len());
+        let mut dead = Vec::new();
+
+        for (index, message) in messages.into_iter().enumerate() {
+            let result = self.send(message).await;
+
+            if result.as_ref().is_err_and(WebPushError::is_subscription_dead) {
+                dead.push(index);
+            }
+
+            results.push(result);
+        }
+
+        (results, dead)
+    }
+
+    /// Opens a connection to the push service at `host`, e.g. `fcm.googleapis.com`, and keeps it in the connection
+    /// pool without sending a message. Avoids the DNS lookup and TLS handshake on the first send to a known host.
+    ///
+    /// This is best-effort: the connection may still be closed by either side before the next send. Returns
+    /// `InvalidUri` if `host` is not a valid host, and a connection error if the host can't be reached. Does nothing
+    /// by default.
+    async fn warm_up(&self, host: &str) -> Result<(), WebPushError> {
+        warm_up_uri(host).map(|_| ())
+    }
+
+    /// Checks that the message would produce a well formed request, without sending it.
+    /// See [`request_builder::validate_request`].
+    fn validate(&self, message: &WebPushMessage) -> Result<(), WebPushError> {
+        request_builder::validate_request(message)
+    }
+}
+
+/// The result of [`WebPushClient::send_tracking`].
+#[derive(Debug)]
+pub enum SendOutcome {
+    /// The push service accepted the message.
+    Delivered,
+    /// The subscription expired or was unsubscribed, and should be deleted.
+    SubscriptionGone,
+    /// The send failed, but may succeed if retried later, see [`WebPushError::is_transient`].
+    Transient(WebPushError),
+    /// The send failed, and will fail again if retried.
+    Fatal(WebPushError),
+}
+
+/// What a successful send put on the wire, returned by the `send_accounted` method of the clients. Useful for
+/// bandwidth metering.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SendStats {
+    /// Length of the request body, the encrypted payload.
+    pub body_len: usize,
+    /// Estimated length of the request headers, counting each as `name: value\r\n`.
+    pub headers_len: usize,
+    /// The status the push service responded with.
+    pub status: StatusCode,
+}
+
+/// A finished send, passed to the hook set with the `with_on_complete` method of the clients. Useful for metrics.
+#[derive(Debug)]
+pub struct SendReport<'a> {
+    /// The host of the push endpoint.
+    pub host: &'a str,
+    /// How long the send took, until the response was parsed or the send failed.
+    pub elapsed: Duration,
+    /// What was put on the wire, or why the send failed.
+    pub result: Result<&'a SendStats, &'a WebPushError>,
+}
+
+impl SendReport<'_> {
+    /// A short label for the outcome: `success`, or the [`short_description`](WebPushError::short_description) of
+    /// the error. Suitable as a metrics label.
+    pub fn category(&self) -> &'static str {
+        match self.result {
+            Ok(_) => "success",
+            Err(error) => error.short_description(),
+        }
+    }
+}
+
+/// The hook called after every send.
+#[cfg(any(feature = "isahc-client", feature = "hyper-client"))]
+pub(crate) type CompletionHook = Arc<dyn Fn(&SendReport<'_>) + Send + Sync>;
+
+#[cfg(any(feature = "isahc-client", feature = "hyper-client"))]
+/// Calls the `hook`, if set, with the result of a send to `endpoint` that started at `started`.
+pub(crate) fn report_completion(
+    hook: &Option<CompletionHook>,
+    endpoint: &Uri,
+    started: Instant,
+    result: &Result<SendStats, WebPushError>,
+) {
+    if let Some(hook) = hook {
+        hook(&SendReport {
+            host: endpoint.host().unwrap_or("-"),
+            elapsed: started.elapsed(),
+            result: result.as_ref(),
+        });
+    }
+}
+
+#[cfg(any(feature = "isahc-client", feature = "hyper-client"))]
+/// Estimated wire length of the headers, counting each as `name: value\r\n`.
+pub(crate) fn headers_len(headers: &HeaderMap) -> usize {
+    headers
+        .iter()
+        .map(|(name, value)| name.as_str().len() + value.len() + 4)
+        .sum()
+}
+
+#[cfg(any(feature = "isahc-client", feature = "hyper-client"))]
+/// The time left for a send, the shorter of the client `timeout` and the time until the message `deadline`. Zero once
+/// the deadline has passed.
+pub(crate) fn send_timeout(timeout: Option<Duration>, deadline: Option<Instant>) -> Option<Duration> {
+    let until_deadline = deadline.map(|deadline| deadline.saturating_duration_since(Instant::now()));
+
+    match (timeout, until_deadline) {
+        (Some(timeout), Some(until_deadline)) => Some(timeout.min(until_deadline)),
+        (timeout, until_deadline) => timeout.or(until_deadline),
+    }
+}
+
+/// The URI requested by [`WebPushClient::warm_up`] to open a connection to `host`.
+pub(crate) fn warm_up_uri(host: &str) -> Result<Uri, WebPushError> {
+    let uri: Uri = format!("https://{}/", host).parse()?;
+
+    match uri.authority() {
+        Some(authority) if authority.as_str() == host && !host.contains('@') => Ok(uri),
+        _ => Err(WebPushError::InvalidUri),
+    }
+}
+
+#[cfg(any(feature = "isahc-client", feature = "hyper-client"))]
+/// The scheme, host and port replacing those of every request URI, for redirecting sends to a test server.
+#[derive(Debug, Clone)]
+pub(crate) struct BaseUrlOverride {
+    scheme: Scheme,
+    authority: Authority,
+}
+
+#[cfg(any(feature = "isahc-client", feature = "hyper-client"))]
+impl BaseUrlOverride {
+    /// Parses `base_url`, which must have a scheme and a host, and no path besides `/`.
+    pub(crate) fn parse(base_url: &str) -> Result<BaseUrlOverride, WebPushError> {
+        let uri: Uri = base_url.parse()?;
+        let parts = uri.into_parts();
+
+        match (parts.scheme, parts.authority, parts.path_and_query) {
+            (Some(scheme), Some(authority), path) if path.as_ref().is_none_or(|path| path == "/") => {
+                Ok(BaseUrlOverride { scheme, authority })
+            }
+            _ => Err(WebPushError::InvalidUri),
+        }
+    }
+
+    /// Rewrites `uri` to the overriding scheme, host and port, keeping the path and query.
+    pub(crate) fn apply(&self, uri: &Uri) -> Uri {
+        let mut parts = uri.clone().into_parts();
+        parts.scheme = Some(self.scheme.clone());
+        parts.authority = Some(self.authority.clone());
+
+        Uri::from_parts(parts).unwrap_or_else(|_| uri.clone())
+    }
+}
+
+#[cfg(any(feature = "isahc-client", feature = "hyper-client"))]
+/// Prefix for the log lines of a single send, so concurrent sends can be told apart.
+///
+/// Contains a request id unique within the process, and the host of the push endpoint.
+pub(crate) struct SendContext {
+    id: u64,
+    host: String,
+}
+
+#[cfg(any(feature = "isahc-client", feature = "hyper-client"))]
+impl SendContext {
+    pub(crate) fn new(endpoint: &Uri) -> SendContext {
+        static NEXT_ID: AtomicU64 = AtomicU64::new(1);
+
+        SendContext {
+            id: NEXT_ID.fetch_add(1, Ordering::Relaxed),
+            host: endpoint.host().unwrap_or("-").to_string(),
+        }
+    }
+}
+
+#[cfg(any(feature = "isahc-client", feature = "hyper-client"))]
+impl fmt::Display for SendContext {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "[send {} to {}]", self.id, self.host)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::clients::warm_up_uri;
+    #[cfg(any(feature = "isahc-client", feature = "hyper-client"))]
+    use crate::clients::{headers_len, send_timeout, BaseUrlOverride, SendContext};
+    use crate::WebPushError;
+
+    #[cfg(feature = "cancellation")]
+    mod cancellation {
+        use async_trait::async_trait;
+        use tokio_util::sync::CancellationToken;
+
+        use crate::message::test_message;
+        use crate::{WebPushClient, WebPushError, WebPushMessage};
+
+        /// A client whose sends never complete.
+        struct PendingClient;
+
+        #[async_trait]
+        impl WebPushClient for PendingClient {
+            async fn send(&self, _: WebPushMessage) -> Result<(), WebPushError> {
+                std::future::pending().await
+            }
+        }
+
+        #[tokio::test]
+        async fn cancels_an_in_flight_send() {
+            let cancel = CancellationToken::new();
+            let trigger = cancel.clone();
+
+            tokio::spawn(async move { trigger.cancel() });
+
+            assert!(matches!(
+                PendingClient
+                    .send_cancellable(test_message("https://fcm.googleapis.com/fcm/send/eKClHsXFm9E"), cancel)
+                    .await,
+                Err(WebPushError::Cancelled)
+            ));
+        }
+
+        #[tokio::test]
+        async fn does_not_start_a_cancelled_send() {
+            let cancel = CancellationToken::new();
+            cancel.cancel();
+
+            assert!(matches!(
+                PendingClient
+                    .send_cancellable(test_message("https://fcm.googleapis.com/fcm/send/eKClHsXFm9E"), cancel)
+                    .await,
+                Err(WebPushError::Cancelled)
+            ));
+        }
+    }
+
+    mod tracking {
+        use async_trait::async_trait;
+
+        use crate::error::ErrorInfo;
+        use crate::message::test_message;
+        use crate::{SendOutcome, WebPushClient, WebPushError, WebPushMessage};
+
+        /// A client whose sends fail with the status it was created with, or succeed for 201.
+        struct StatusClient(u16);
+
+        #[async_trait]
+        impl WebPushClient for StatusClient {
+            async fn send(&self, _: WebPushMessage) -> Result<(), WebPushError> {
+                let info = ErrorInfo {
+                    code: self.0,
+                    errno: 999,
+                    error: "error".into(),
+                    message: "-".into(),
+                };
+
+                match self.0 {
+                    201 => Ok(()),
+                    404 => Err(WebPushError::EndpointNotFound(info)),
+                    410 => Err(WebPushError::EndpointNotValid(info)),
+                    503 => Err(WebPushError::ServerError {
+                        retry_after: None,
+                        info,
+                    }),
+                    _ => Err(WebPushError::Unauthorized(info)),
+                }
+            }
+        }
+
+        /// A client responding with the status in the path of the endpoint, like [`StatusClient`].
+        struct EndpointStatusClient;
+
+        #[async_trait]
+        impl WebPushClient for EndpointStatusClient {
+            async fn send(&self, message: WebPushMessage) -> Result<(), WebPushError> {
+                let status = message.endpoint.path().trim_start_matches('/').parse().unwrap();
+
+                StatusClient(status).send(message).await
+            }
+        }
+
+        fn message_to(path: &str) -> WebPushMessage {
+            test_message(&format!("https://fcm.googleapis.com{}", path))
+        }
+
+        #[tokio::test]
+        async fn collects_dead_subscriptions() {
+            let messages = ["/201", "/410", "/503", "/201", "/404", "/401"]
+                .into_iter()
+                .map(message_to)
+                .collect();
+
+            let (results, dead) = EndpointStatusClient.send_all_collecting_dead(messages).await;
+
+            assert_eq!(6, results.len());
+            assert!(results[0].is_ok());
+            assert!(matches!(results[1], Err(WebPushError::EndpointNotValid(_))));
+            assert!(matches!(results[2], Err(WebPushError::ServerError { .. })));
+            assert!(results[3].is_ok());
+            assert!(matches!(results[5], Err(WebPushError::Unauthorized(_))));
+            assert_eq!(vec![1, 4], dead);
+        }
+
+        #[tokio::test]
+        async fn classifies_send_results() {
+            assert!(matches!(
+                StatusClient(201)
+                    .send_tracking(message_to("/fcm/send/eKClHsXFm9E"))
+                    .await,
+                SendOutcome::Delivered
+            ));
+            assert!(matches!(
+                StatusClient(404)
+                    .send_tracking(message_to("/fcm/send/eKClHsXFm9E"))
+                    .await,
+                SendOutcome::SubscriptionGone
+            ));
+            assert!(matches!(
+                StatusClient(410)
+                    .send_tracking(message_to("/fcm/send/eKClHsXFm9E"))
+                    .await,
+                SendOutcome::SubscriptionGone
+            ));
+            assert!(matches!(
+                StatusClient(503)
+                    .send_tracking(message_to("/fcm/send/eKClHsXFm9E"))
+                    .await,
+                SendOutcome::Transient(WebPushError::ServerError { .. })
+            ));
+            assert!(matches!(
+                StatusClient(401)
+                    .send_tracking(message_to("/fcm/send/eKClHsXFm9E"))
+                    .await,
+                SendOutcome::Fatal(WebPushError::Unauthorized(_))
+            ));
+        }
+    }
+
+    #[cfg(any(feature = "isahc-client", feature = "hyper-client"))]
+    #[test]
+    fn reports_a_completed_send() {
+        use std::sync::{Arc, Mutex};
+        use std::time::Instant;
+
+        use http::StatusCode;
+
+        use crate::clients::{report_completion, CompletionHook, SendStats};
+
+        let reports = Arc::new(Mutex::new(Vec::new()));
+        let hook: CompletionHook = {
+            let reports = reports.clone();
+            Arc::new(move |report| {
+                reports
+                    .lock()
+                    .unwrap()
+                    .push((report.host.to_string(), report.category()))
+            })
+        };
+        let endpoint = "https://fcm.googleapis.com/fcm/send/eKClHsXFm9E".parse().unwrap();
+
+        let stats = SendStats {
+            body_len: 0,
+            headers_len: 0,
+            status: StatusCode::CREATED,
+        };
+        report_completion(&Some(hook.clone()), &endpoint, Instant::now(), &Ok(stats));
+        report_completion(&Some(hook), &endpoint, Instant::now(), &Err(WebPushError::Timeout));
+        report_completion(&None, &endpoint, Instant::now(), &Err(WebPushError::Timeout));
+
+        assert_eq!(
+            vec![
+                ("fcm.googleapis.com".to_string(), "success"),
+                ("fcm.googleapis.com".to_string(), "timeout")
+            ],
+            *reports.lock().unwrap()
+        );
+    }
+
+    #[cfg(any(feature = "isahc-client", feature = "hyper-client"))]
+    #[test]
+    fn counts_header_bytes() {
+        use http::HeaderMap;
+
+        let mut headers = HeaderMap::new();
+        headers.insert("TTL", "0".parse().unwrap());
+        headers.insert("Content-Encoding", "aes128gcm".parse().unwrap());
+
+        assert_eq!(
+            "ttl: 0\r\n".len() + "content-encoding: aes128gcm\r\n".len(),
+            headers_len(&headers)
+        );
+    }
+
+    #[test]
+    fn builds_the_warm_up_uri_from_a_host() {
+        assert_eq!(
+            "https://fcm.googleapis.com/",
+            warm_up_uri("fcm.googleapis.com").unwrap()
+        );
+        assert_eq!("https://localhost:8443/", warm_up_uri("localhost:8443").unwrap());
+        assert!(matches!(warm_up_uri("example.com/push"), Err(WebPushError::InvalidUri)));
+        assert!(matches!(warm_up_uri("user@example.com"), Err(WebPushError::InvalidUri)));
+    }
+
+    #[cfg(any(feature = "isahc-client", feature = "hyper-client"))]
+    #[test]
+    fn send_timeout_is_the_shorter_of_timeout_and_deadline() {
+        use std::time::{Duration, Instant};
+
+        let deadline = Instant::now() + Duration::from_secs(60);
+
+        assert_eq!(None, send_timeout(None, None));
+        assert_eq!(
+            Some(Duration::from_secs(10)),
+            send_timeout(Some(Duration::from_secs(10)), None)
+        );
+        assert_eq!(
+            Some(Duration::from_secs(10)),
+            send_timeout(Some(Duration::from_secs(10)), Some(deadline))
+        );
+        assert!(send_timeout(Some(Duration::from_secs(120)), Some(deadline)).unwrap() <= Duration::from_secs(60));
+        assert_eq!(Some(Duration::ZERO), send_timeout(None, Some(Instant::now())));
+    }
+
+    #[cfg(any(feature = "isahc-client", feature = "hyper-client"))]
+    #[test]
+    fn base_url_override_keeps_path_and_query() {
+        let base_url = BaseUrlOverride::parse("http://127.0.0.1:8080").unwrap();
+        let endpoint = "https://fcm.googleapis.com/fcm/send/eKClHsXFm9E?topic=news"
+            .parse()
+            .unwrap();
+
+        assert_eq!(
+            "http://127.0.0.1:8080/fcm/send/eKClHsXFm9E?topic=news",
+            base_url.apply(&endpoint)
+        );
+        assert!(BaseUrlOverride::parse("http://127.0.0.1:8080/").is_ok());
+        assert!(matches!(
+            BaseUrlOverride::parse("http://127.0.0.1:8080/push"),
+            Err(WebPushError::InvalidUri)
+        ));
+        assert!(matches!(
+            BaseUrlOverride::parse("127.0.0.1:8080"),
+            Err(WebPushError::InvalidUri)
+        ));
+    }
+
+    #[cfg(any(feature = "isahc-client", feature = "hyper-client"))]
+    #[test]
+    fn send_context_contains_unique_id_and_host() {
+        let endpoint = "https://fcm.googleapis.com/fcm/send/eKClHsXFm9E".parse().unwrap();
+        let first = SendContext::new(&endpoint);
+        let second = SendContext::new(&endpoint);
+
+        assert_ne!(first.id, second.id);
+        assert_eq!(format!("[send {} to fcm.googleapis.com]", first.id), first.to_string());
+    }
 }
@@ -0,0 +1,142 @@
+//! Pacing of sends to each push service host.
+
+use std::collections::HashMap;
+use std::sync::{Mutex, PoisonError};
+use std::time::{Duration, Instant};
+
+use crate::message::WebPushMessage;
+
+/// Paces sends to stay under a per host request rate, e.g. the per project limits of FCM.
+///
+/// Every host gets its own token bucket, so a busy or slow host doesn't hold back sends to the others. Like
+/// [`RetryPolicy`](crate::RetryPolicy), the limiter only computes delays and leaves the waiting to the caller, so it
+/// works with any executor. The limiter can be shared between tasks.
+///
+/// # Example
+///
+/// ```no_run
+/// # use web_push::{HostRateLimiter, IsahcWebPushClient, WebPushClient, WebPushMessage};
+/// # async fn sleep(_: std::time::Duration) {}
+/// # async fn send_all(client: IsahcWebPushClient, messages: Vec<WebPushMessage>) {
+/// let limiter = HostRateLimiter::new(100);
+///
+/// for message in messages {
+///     sleep(limiter.delay_for(&message)).await;
+///     let _ = client.send(message).await;
+/// }
+/// # }
+/// ```
+#[derive(Debug)]
+pub struct HostRateLimiter {
+    requests_per_second: f64,
+    burst: f64,
+    buckets: Mutex<HashMap<String, Bucket>>,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Bucket {
+    /// Tokens left, negative when sends are already waiting for tokens.
+    tokens: f64,
+    refilled_at: Instant,
+}
+
+impl HostRateLimiter {
+    /// Creates a limiter allowing `requests_per_second` sends to each host, at least one. Sends are evenly spaced,
+    /// allow bursts with [`HostRateLimiter::set_burst`].
+    pub fn new(requests_per_second: u32) -> HostRateLimiter {
+        HostRateLimiter {
+            requests_per_second: f64::from(requests_per_second.max(1)),
+            burst: 1.0,
+            buckets: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Sets how many sends to a host may go out at once after it has been idle, at least one. Defaults to one.
+    pub fn set_burst(&mut self, burst: u32) {
+        self.burst = f64::from(burst.max(1));
+    }
+
+    /// Reserves a send of `message`, returning how long to wait before sending it. Every call takes a slot, so call
+    /// it once per send.
+    pub fn delay_for(&self, message: &WebPushMessage) -> Duration {
+        self.delay_at(message.endpoint.host().unwrap_or_default(), Instant::now())
+    }
+
+    fn delay_at(&self, host: &str, now: Instant) -> Duration {
+        let mut buckets = self.buckets.lock().unwrap_or_else(PoisonError::into_inner);
+
+        let bucket = buckets.entry(host.to_ascii_lowercase()).or_insert(Bucket {
+            tokens: self.burst,
+            refilled_at: now,
+        });
+
+        let elapsed = now.saturating_duration_since(bucket.refilled_at).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * self.requests_per_second).min(self.burst) - 1.0;
+        bucket.refilled_at = bucket.refilled_at.max(now);
+
+        if bucket.tokens >= 0.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64(-bucket.tokens / self.requests_per_second)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use crate::clients::rate_limit::HostRateLimiter;
+
+    #[test]
+    fn paces_sends_to_a_host() {
+        let limiter = HostRateLimiter::new(4);
+        let now = Instant::now();
+
+        let delays: Vec<Duration> = (0..4).map(|_| limiter.delay_at("fcm.googleapis.com", now)).collect();
+
+        assert_eq!(
+            vec![
+                Duration::ZERO,
+                Duration::from_millis(250),
+                Duration::from_millis(500),
+                Duration::from_millis(750)
+            ],
+            delays
+        );
+        assert_eq!(
+            Duration::from_millis(500),
+            limiter.delay_at("fcm.googleapis.com", now + Duration::from_millis(500))
+        );
+    }
+
+    #[test]
+    fn limits_each_host_separately() {
+        let limiter = HostRateLimiter::new(1);
+        let now = Instant::now();
+
+        assert_eq!(Duration::ZERO, limiter.delay_at("fcm.googleapis.com", now));
+        assert_eq!(Duration::from_secs(1), limiter.delay_at("FCM.googleapis.com", now));
+        assert_eq!(
+            Duration::ZERO,
+            limiter.delay_at("updates.push.services.mozilla.com", now)
+        );
+    }
+
+    #[test]
+    fn allows_bursts_after_idling() {
+        let mut limiter = HostRateLimiter::new(2);
+        limiter.set_burst(3);
+        let now = Instant::now();
+
+        for _ in 0..3 {
+            assert_eq!(Duration::ZERO, limiter.delay_at("fcm.googleapis.com", now));
+        }
+        assert_eq!(Duration::from_millis(500), limiter.delay_at("fcm.googleapis.com", now));
+
+        let later = now + Duration::from_secs(10);
+        for _ in 0..3 {
+            assert_eq!(Duration::ZERO, limiter.delay_at("fcm.googleapis.com", later));
+        }
+    }
+}
@@ -1,16 +1,23 @@
 //! Functions used to send and consume push http messages.
 //! This module can be used to build custom clients.
 
-use http::header::{CONTENT_ENCODING, CONTENT_LENGTH, CONTENT_TYPE};
-use http::{Request, StatusCode};
+use http::header::{AUTHORIZATION, CONTENT_ENCODING, CONTENT_LENGTH, CONTENT_TYPE, LOCATION, RETRY_AFTER};
+use std::collections::BTreeMap;
+use std::convert::TryFrom;
 
-use crate::{error::ErrorInfo, error::WebPushError, message::WebPushMessage};
+use http::{HeaderMap, Request, StatusCode, Uri};
+
+use crate::error::{ErrorInfo, PayloadLimitSource, RetryAfter, WebPushError};
+use crate::message::WebPushMessage;
+use crate::message::{validate_header, validate_header_value, validate_method, validate_topic};
 
 /// Builds the request to send to the push service.
 ///
 /// This function is generic over the request body, this means that you can swap out client implementations
 /// even if they use different body types.
 ///
+/// The `Urgency` header is only added if the message has an urgency set, including an explicit `Urgency::Normal`.
+///
 /// # Example
 ///
 /// ```no_run
@@ -33,35 +40,114 @@ where
     T: From<Vec<u8>> + From<&'static str>, //This bound can be reduced to a &[u8] instead of str if needed
 {
     let mut builder = Request::builder()
-        .method("POST")
-        .uri(message.endpoint)
-        .header("TTL", format!("{}", message.ttl).as_bytes());
-
-    if let Some(urgency) = message.urgency {
-        builder = builder.header("Urgency", urgency.to_string());
-    }
+        .method(message.method.clone())
+        .uri(message.endpoint.clone());
 
-    if let Some(topic) = message.topic {
-        builder = builder.header("Topic", topic);
+    for (k, v) in request_headers(&message) {
+        builder = builder.header(k, v);
     }
 
     if let Some(payload) = message.payload {
-        builder = builder
-            .header(CONTENT_ENCODING, payload.content_encoding.to_str())
-            .header(CONTENT_LENGTH, format!("{}", payload.content.len() as u64).as_bytes())
-            .header(CONTENT_TYPE, "application/octet-stream");
-
-        for (k, v) in payload.crypto_headers.into_iter() {
-            let v: &str = v.as_ref();
-            builder = builder.header(k, v);
-        }
-
         builder.body(payload.content.into()).unwrap()
     } else {
         builder.body("".into()).unwrap()
     }
 }
 
+/// Builds the request with a `Vec<u8>` body, see [`build_request`].
+///
+/// A [deferred VAPID signature](WebPushMessage::sign_deferred_vapid) is signed first, and the message is checked
+/// with [`validate_request`], so an invalid message returns an error instead of panicking.
+///
+/// ```no_run
+/// # use std::convert::TryFrom;
+/// # use web_push::{SubscriptionInfo, WebPushMessageBuilder};
+/// # let info = SubscriptionInfo::new("https://example.com", "p256dh", "auth");
+/// let message = WebPushMessageBuilder::new(&info).build().unwrap();
+/// let request = http::Request::<Vec<u8>>::try_from(message).unwrap();
+/// ```
+impl TryFrom<WebPushMessage> for Request<Vec<u8>> {
+    type Error = WebPushError;
+
+    fn try_from(mut message: WebPushMessage) -> Result<Self, Self::Error> {
+        message.sign_deferred_vapid()?;
+        validate_request(&message)?;
+        Ok(build_request(message))
+    }
+}
+
+/// Checks that the message would produce a well formed request, without sending anything.
+///
+/// Runs the same steps as [`build_request`], and additionally checks that the endpoint is an absolute URI, the method
+/// is `POST` or `PUT`, and the topic, idempotency key and extra headers are valid. Useful as a smoke test for push
+/// configuration, as no network I/O is done.
+pub fn validate_request(message: &WebPushMessage) -> Result<(), WebPushError> {
+    if message.endpoint.scheme().is_none() || message.endpoint.host().is_none() {
+        return Err(WebPushError::InvalidUri);
+    }
+
+    if let Some(topic) = &message.topic {
+        validate_topic(topic)?;
+    }
+
+    validate_method(&message.method)?;
+
+    if let Some(key) = &message.idempotency_key {
+        validate_header_value(key)?;
+    }
+
+    for (name, value) in &message.extra_headers {
+        validate_header(name, value)?;
+    }
+
+    let mut builder = Request::builder()
+        .method(message.method.clone())
+        .uri(message.endpoint.clone());
+
+    for (k, v) in request_headers(message) {
+        builder = builder.header(k, v);
+    }
+
+    builder.body(()).map(|_| ()).map_err(|_| WebPushError::InvalidHeader)
+}
+
+/// The headers [`build_request`] adds to the request for the message, in order.
+pub(crate) fn request_headers(message: &WebPushMessage) -> Vec<(String, String)> {
+    let mut headers = vec![("TTL", message.ttl.to_string())];
+
+    if let Some(urgency) = message.urgency {
+        headers.push(("Urgency", urgency.to_string()));
+    }
+
+    if let Some(topic) = &message.topic {
+        headers.push(("Topic", topic.clone()));
+    }
+
+    if message.prefer_async {
+        headers.push(("Prefer", "respond-async".into()));
+    }
+
+    if let Some(key) = &message.idempotency_key {
+        headers.push(("Idempotency-Key", key.clone()));
+    }
+
+    if let Some(payload) = &message.payload {
+        headers.push((CONTENT_ENCODING.as_str(), payload.content_encoding.to_str().into()));
+        headers.push((CONTENT_LENGTH.as_str(), payload.content.len().to_string()));
+        headers.push((CONTENT_TYPE.as_str(), "application/octet-stream".into()));
+        headers.extend(payload.crypto_headers.iter().cloned());
+    } else if let Some(encoding) = message.empty_body_encoding {
+        headers.push((CONTENT_ENCODING.as_str(), encoding.to_str().into()));
+        headers.push((CONTENT_LENGTH.as_str(), "0".into()));
+    }
+
+    headers
+        .into_iter()
+        .map(|(name, value)| (name.to_string(), value))
+        .chain(message.extra_headers.iter().cloned())
+        .collect()
+}
+
 /// Parses the response from the push service, and will return `Err` if the request was bad.
 pub fn parse_response(response_status: StatusCode, body: Vec<u8>) -> Result<(), WebPushError> {
     if response_status.is_success() {
@@ -75,28 +161,427 @@ pub fn parse_response(response_status: StatusCode, body: Vec<u8>) -> Result<(),
         message: String::from_utf8(body).unwrap_or_else(|_| "-".into()),
     });
 
+    Err(error_from_status(response_status, info))
+}
+
+/// Details of a successful response from the push service, see [`parse_response_detailed`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SuccessInfo {
+    /// The success status, usually `201 Created`.
+    pub status: StatusCode,
+    /// The URL of the created push message resource from the `Location` header, if given.
+    pub location: Option<String>,
+}
+
+/// Parses the response from the push service like [`parse_response`], but returns the status and `Location` of a
+/// successful response.
+pub fn parse_response_detailed(
+    response_status: StatusCode,
+    headers: &HeaderMap,
+    body: Vec<u8>,
+) -> Result<SuccessInfo, WebPushError> {
+    parse_response(response_status, body)?;
+
+    Ok(SuccessInfo {
+        status: response_status,
+        location: headers
+            .get(LOCATION)
+            .and_then(|location| location.to_str().ok())
+            .map(String::from),
+    })
+}
+
+/// A data message for the FCM HTTP v1 API, addressed to a registration token and authorized with an OAuth 2.0
+/// access token instead of VAPID. Build the request with [`build_fcm_v1_request`].
+///
+/// Getting the access token for the service account of the Firebase project is up to the caller, e.g. with the
+/// `https://www.googleapis.com/auth/firebase.messaging` scope.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FcmV1Message {
+    project_id: String,
+    access_token: String,
+    token: String,
+    data: BTreeMap<String, String>,
+}
+
+impl FcmV1Message {
+    /// Creates a message to the registration `token`, sent through the Firebase project `project_id`.
+    pub fn new(project_id: &str, access_token: &str, token: &str) -> FcmV1Message {
+        FcmV1Message {
+            project_id: project_id.to_string(),
+            access_token: access_token.to_string(),
+            token: token.to_string(),
+            data: BTreeMap::new(),
+        }
+    }
+
+    /// Adds a key to the `data` of the message, delivered to the app as is.
+    pub fn add_data(&mut self, key: &str, value: &str) {
+        self.data.insert(key.to_string(), value.to_string());
+    }
+
+    /// The `messages:send` endpoint of the project. Returns `InvalidUri` if the project id doesn't fit in a URI.
+    pub fn endpoint(&self) -> Result<Uri, WebPushError> {
+        Ok(format!(
+            "https://fcm.googleapis.com/v1/projects/{}/messages:send",
+            self.project_id
+        )
+        .parse()?)
+    }
+}
+
+/// Builds the request sending `message` with the FCM HTTP v1 API, with the access token as a bearer token in the
+/// `Authorization` header and the message as a JSON body. Responses can be parsed with [`parse_response`].
+///
+/// Returns `InvalidUri` if the project id doesn't fit in a URI, and `InvalidHeader` if the access token can't be
+/// sent in a header.
+pub fn build_fcm_v1_request<T>(message: &FcmV1Message) -> Result<Request<T>, WebPushError>
+where
+    T: From<Vec<u8>>,
+{
+    let mut body = serde_json::json!({ "message": { "token": message.token } });
+
+    if !message.data.is_empty() {
+        body["message"]["data"] = serde_json::to_value(&message.data)?;
+    }
+
+    let body = serde_json::to_vec(&body)?;
+
+    Request::builder()
+        .method("POST")
+        .uri(message.endpoint()?)
+        .header(AUTHORIZATION, format!("Bearer {}", message.access_token))
+        .header(CONTENT_TYPE, "application/json")
+        .header(CONTENT_LENGTH, body.len())
+        .body(body.into())
+        .map_err(|_| WebPushError::InvalidHeader)
+}
+
+/// A data message for the legacy FCM HTTP API, sent to registration tokens with a server key. Build the request with
+/// [`build_fcm_legacy_request`] and parse the response with [`parse_fcm_legacy_response`]. Requires the
+/// `fcm-legacy` feature.
+#[cfg(feature = "fcm-legacy")]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct FcmLegacyMessage {
+    #[serde(skip)]
+    server_key: String,
+    registration_ids: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    restricted_package_name: Option<String>,
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    data: BTreeMap<String, String>,
+}
+
+#[cfg(feature = "fcm-legacy")]
+impl FcmLegacyMessage {
+    /// Creates a message to the `registration_ids`, authorized with the `server_key` of the Firebase project.
+    pub fn new(server_key: &str, registration_ids: Vec<String>) -> FcmLegacyMessage {
+        FcmLegacyMessage {
+            server_key: server_key.to_string(),
+            registration_ids,
+            restricted_package_name: None,
+            data: BTreeMap::new(),
+        }
+    }
+
+    /// Adds a key to the `data` of the message, delivered to the app as is.
+    pub fn add_data(&mut self, key: &str, value: &str) {
+        self.data.insert(key.to_string(), value.to_string());
+    }
+
+    /// Only delivers the message to tokens of the app with this package name. Tokens of other apps fail with
+    /// `InvalidPackageName`, see [`FcmLegacyResult::to_error`].
+    pub fn set_restricted_package_name(&mut self, package_name: &str) {
+        self.restricted_package_name = Some(package_name.to_string());
+    }
+}
+
+/// Builds the request sending `message` with the legacy FCM HTTP API, with the server key in the `Authorization`
+/// header and the message as a JSON body. Requires the `fcm-legacy` feature.
+///
+/// Returns `InvalidHeader` if the server key can't be sent in a header.
+#[cfg(feature = "fcm-legacy")]
+pub fn build_fcm_legacy_request<T>(message: &FcmLegacyMessage) -> Result<Request<T>, WebPushError>
+where
+    T: From<Vec<u8>>,
+{
+    let body = serde_json::to_vec(message)?;
+
+    Request::builder()
+        .method("POST")
+        .uri("https://fcm.googleapis.com/fcm/send")
+        .header(AUTHORIZATION, format!("key={}", message.server_key))
+        .header(CONTENT_TYPE, "application/json")
+        .header(CONTENT_LENGTH, body.len())
+        .body(body.into())
+        .map_err(|_| WebPushError::InvalidHeader)
+}
+
+/// The body of a successful response from the legacy FCM HTTP API, sent to multiple registration tokens with
+/// `registration_ids`. Requires the `fcm-legacy` feature.
+#[cfg(feature = "fcm-legacy")]
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+pub struct FcmLegacyResponse {
+    pub multicast_id: i64,
+    /// Number of tokens the message was sent to.
+    pub success: u64,
+    /// Number of tokens that failed.
+    pub failure: u64,
+    /// Number of tokens that have a newer canonical token.
+    #[serde(default)]
+    pub canonical_ids: u64,
+    /// The results in the order of the `registration_ids` of the request.
+    #[serde(default)]
+    pub results: Vec<FcmLegacyResult>,
+}
+
+/// The result for one registration token in a [`FcmLegacyResponse`].
+#[cfg(feature = "fcm-legacy")]
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+pub struct FcmLegacyResult {
+    /// The id of the message, if sent.
+    pub message_id: Option<String>,
+    /// The canonical token to use instead of the one sent to, if it changed.
+    pub registration_id: Option<String>,
+    /// The reason the token failed, e.g. `NotRegistered` or `InvalidRegistration`.
+    pub error: Option<String>,
+}
+
+#[cfg(feature = "fcm-legacy")]
+impl FcmLegacyResult {
+    /// The error of a failed token, `None` if it was sent. `InvalidPackageName` for tokens of another app than the
+    /// restricted package name, `EndpointNotFound` and `EndpointNotValid` for tokens that should be deleted, and
+    /// `ServerError` for tokens worth retrying.
+    pub fn to_error(&self) -> Option<WebPushError> {
+        let error = self.error.as_deref()?;
+        let info = ErrorInfo {
+            code: 200,
+            errno: 999,
+            error: error.into(),
+            message: "the token failed in a multicast message".into(),
+        };
+
+        Some(match error {
+            "InvalidPackageName" => WebPushError::InvalidPackageName,
+            "NotRegistered" => WebPushError::EndpointNotFound(info),
+            "InvalidRegistration" | "MismatchSenderId" => WebPushError::EndpointNotValid(info),
+            "Unavailable" | "InternalServerError" => WebPushError::ServerError {
+                retry_after: None,
+                info,
+            },
+            _ => WebPushError::Other(info),
+        })
+    }
+}
+
+#[cfg(feature = "fcm-legacy")]
+impl FcmLegacyResponse {
+    /// The index in `registration_ids` and the error of every failed token.
+    pub fn failed_tokens(&self) -> impl Iterator<Item = (usize, &str)> {
+        self.results
+            .iter()
+            .enumerate()
+            .filter_map(|(index, result)| result.error.as_deref().map(|error| (index, error)))
+    }
+}
+
+/// Parses a response from the legacy FCM HTTP API into the result per registration token. Errors of the whole
+/// request are returned like [`parse_response`]. Requires the `fcm-legacy` feature.
+///
+/// A successful response with a body that isn't a multicast result returns `InvalidResponse`.
+#[cfg(feature = "fcm-legacy")]
+pub fn parse_fcm_legacy_response(
+    response_status: StatusCode,
+    body: Vec<u8>,
+) -> Result<FcmLegacyResponse, WebPushError> {
+    if !response_status.is_success() {
+        return Err(parse_response(response_status, body).unwrap_err());
+    }
+
+    Ok(serde_json::from_slice(&body)?)
+}
+
+/// Classifies a complete response from the push service, for custom clients. Like [`parse_response`], and a
+/// `ServerError` also gets the delay of the `Retry-After` header, if any. Use [`consume_response_from`] to decode the
+/// error formats of Apple and WNS too.
+pub fn consume_response(response_status: StatusCode, headers: &HeaderMap, body: Vec<u8>) -> Result<(), WebPushError> {
+    with_retry_after(headers, parse_response(response_status, body))
+}
+
+/// Classifies a complete response from the push service behind `endpoint` like [`consume_response`], using the
+/// error format of that service, see [`parse_response_from`]. This is what the built-in clients do.
+pub fn consume_response_from(
+    endpoint: &Uri,
+    response_status: StatusCode,
+    headers: &HeaderMap,
+    body: Vec<u8>,
+) -> Result<(), WebPushError> {
+    with_retry_after(headers, parse_response_from(endpoint, response_status, headers, body))
+}
+
+/// Adds the delay of the `Retry-After` header to a `ServerError` without one.
+fn with_retry_after(headers: &HeaderMap, response: Result<(), WebPushError>) -> Result<(), WebPushError> {
+    match response {
+        Err(WebPushError::ServerError {
+            retry_after: None,
+            info,
+        }) => Err(WebPushError::ServerError {
+            retry_after: headers
+                .get(RETRY_AFTER)
+                .and_then(|ra| ra.to_str().ok())
+                .and_then(RetryAfter::from_str),
+            info,
+        }),
+        response => response,
+    }
+}
+
+/// Parses the response from the push service behind `endpoint`, using the error format of that service.
+///
+/// Apple's push service (`web.push.apple.com`) returns APNs style `{"reason": "..."}` error bodies, and Windows Push
+/// Notification Services (`*.notify.windows.com`) describe errors in `X-WNS-*` headers. Both are decoded into a
+/// matching error. All other services are handled by [`parse_response`].
+pub fn parse_response_from(
+    endpoint: &Uri,
+    response_status: StatusCode,
+    headers: &HeaderMap,
+    body: Vec<u8>,
+) -> Result<(), WebPushError> {
+    if is_apple_endpoint(endpoint) {
+        parse_apple_response(response_status, body)
+    } else if is_wns_endpoint(endpoint) {
+        parse_wns_response(response_status, headers, body)
+    } else {
+        parse_response(response_status, body)
+    }
+}
+
+/// Parses a response with an APNs style error body from Apple's push service.
+pub fn parse_apple_response(response_status: StatusCode, body: Vec<u8>) -> Result<(), WebPushError> {
+    if response_status.is_success() {
+        return Ok(());
+    }
+
+    let reason = match serde_json::from_slice::<AppleErrorBody>(&body) {
+        Ok(AppleErrorBody { reason }) => reason,
+        Err(_) => return parse_response(response_status, body),
+    };
+
+    let info = ErrorInfo {
+        code: response_status.as_u16(),
+        errno: 999,
+        message: apple_reason_description(&reason).into(),
+        error: reason,
+    };
+
+    match info.error.as_str() {
+        "BadDeviceToken" | "DeviceTokenNotForTopic" | "Unregistered" | "ExpiredToken" => {
+            Err(WebPushError::EndpointNotValid(info))
+        }
+        "ExpiredProviderToken" | "InvalidProviderToken" | "MissingProviderToken" | "Forbidden" => {
+            Err(WebPushError::Unauthorized(info))
+        }
+        "BadExpirationDate" => Err(WebPushError::InvalidTtl),
+        "BadTopic" | "MissingTopic" | "TopicDisallowed" => Err(WebPushError::InvalidTopic),
+        "PayloadTooLarge" => Err(WebPushError::PayloadTooLarge {
+            source: PayloadLimitSource::Server,
+        }),
+        _ => Err(error_from_status(response_status, info)),
+    }
+}
+
+/// Parses a response from Windows Push Notification Services, which describes errors in `X-WNS-*` headers.
+pub fn parse_wns_response(response_status: StatusCode, headers: &HeaderMap, body: Vec<u8>) -> Result<(), WebPushError> {
+    if response_status.is_success() {
+        return Ok(());
+    }
+
+    let header = |name: &str| headers.get(name).and_then(|value| value.to_str().ok());
+
+    let info = ErrorInfo {
+        code: response_status.as_u16(),
+        errno: 999,
+        error: header("X-WNS-Status").unwrap_or("unknown error").into(),
+        message: match header("X-WNS-Error-Description") {
+            Some(description) => description.into(),
+            None => String::from_utf8(body).unwrap_or_else(|_| "-".into()),
+        },
+    };
+
     match response_status {
-        StatusCode::UNAUTHORIZED => Err(WebPushError::Unauthorized(info)),
-        StatusCode::GONE => Err(WebPushError::EndpointNotValid(info)),
-        StatusCode::NOT_FOUND => Err(WebPushError::EndpointNotFound(info)),
-        StatusCode::PAYLOAD_TOO_LARGE => Err(WebPushError::PayloadTooLarge),
-        StatusCode::BAD_REQUEST => Err(WebPushError::BadRequest(info)),
-        status if status.is_server_error() => Err(WebPushError::ServerError {
+        // The sender isn't allowed to push to this channel, usually a VAPID key mismatch.
+        StatusCode::FORBIDDEN => Err(WebPushError::Unauthorized(info)),
+        // The sender exceeded its throttle limit.
+        StatusCode::NOT_ACCEPTABLE => Err(WebPushError::ServerError {
             retry_after: None,
             info,
         }),
-        _ => Err(WebPushError::Other(info)),
+        _ => Err(error_from_status(response_status, info)),
+    }
+}
+
+/// Error body returned by Apple's push service.
+#[derive(Deserialize)]
+struct AppleErrorBody {
+    reason: String,
+}
+
+fn is_apple_endpoint(endpoint: &Uri) -> bool {
+    endpoint
+        .host()
+        .map(|host| host == "push.apple.com" || host.ends_with(".push.apple.com"))
+        .unwrap_or(false)
+}
+
+fn is_wns_endpoint(endpoint: &Uri) -> bool {
+    endpoint
+        .host()
+        .map(|host| host.ends_with(".notify.windows.com"))
+        .unwrap_or(false)
+}
+
+fn apple_reason_description(reason: &str) -> &'static str {
+    match reason {
+        "BadDeviceToken" => "the subscription endpoint is invalid",
+        "DeviceTokenNotForTopic" => "the subscription does not belong to this sender",
+        "Unregistered" | "ExpiredToken" => "the subscription is no longer active",
+        "ExpiredProviderToken" => "the VAPID token has expired",
+        "InvalidProviderToken" => "the VAPID token or public key is invalid",
+        "MissingProviderToken" => "the request has no VAPID signature",
+        "Forbidden" => "the sender is not allowed to push to this subscription",
+        "BadExpirationDate" => "the TTL value is not accepted",
+        "BadTopic" | "MissingTopic" | "TopicDisallowed" => "the topic value is not accepted",
+        "PayloadTooLarge" => "the payload is too large",
+        "TooManyRequests" => "too many requests were sent to the same subscription",
+        "InternalServerError" | "ServiceUnavailable" | "Shutdown" => "the push service is unavailable",
+        _ => "unknown error",
+    }
+}
+
+fn error_from_status(response_status: StatusCode, info: ErrorInfo) -> WebPushError {
+    match response_status {
+        StatusCode::UNAUTHORIZED => WebPushError::Unauthorized(info),
+        StatusCode::GONE => WebPushError::EndpointNotValid(info),
+        StatusCode::NOT_FOUND => WebPushError::EndpointNotFound(info),
+        StatusCode::PAYLOAD_TOO_LARGE => WebPushError::PayloadTooLarge {
+            source: PayloadLimitSource::Server,
+        },
+        StatusCode::BAD_REQUEST => WebPushError::BadRequest(info),
+        status if status.is_server_error() => WebPushError::ServerError {
+            retry_after: None,
+            info,
+        },
+        _ => WebPushError::Other(info),
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use http::Uri;
 
     use crate::clients::request_builder::*;
-    use crate::error::WebPushError;
+    use crate::error::{PayloadLimitSource, WebPushError};
     use crate::http_ece::ContentEncoding;
-    use crate::message::WebPushMessageBuilder;
+    use crate::message::{test_message, SubscriptionInfo, WebPushMessageBuilder};
     use crate::Urgency;
 
     #[test]
@@ -117,7 +602,7 @@ mod tests {
         builder.set_urgency(Urgency::VeryLow);
         builder.set_topic("some-topic".into());
 
-        let request = build_request::<isahc::Body>(builder.build().unwrap());
+        let request = build_request::<Vec<u8>>(builder.build().unwrap());
         let ttl = request.headers().get("TTL").unwrap().to_str().unwrap();
         let urgency = request.headers().get("Urgency").unwrap().to_str().unwrap();
         let topic = request.headers().get("Topic").unwrap().to_str().unwrap();
@@ -129,6 +614,26 @@ mod tests {
         assert_eq!(expected_uri.host(), request.uri().host());
     }
 
+    #[test]
+    fn sends_the_encoding_with_an_empty_body() {
+        let info = SubscriptionInfo::new(
+            "https://example.com/push",
+            "BLMbF9ffKBiWQLCKvTHb6LO8Nb6dcUh6TItC455vu2kElga6PQvUmaFyCdykxY2nOSSL3yKgfbmFLRTUaGv4yV8",
+            "xS03Fi5ErfTNH_l9WHE9Ig",
+        );
+        let mut builder = WebPushMessageBuilder::new(&info);
+
+        let request = build_request::<Vec<u8>>(WebPushMessageBuilder::new(&info).build().unwrap());
+        assert!(request.headers().get("Content-Encoding").is_none());
+
+        builder.set_empty_body_encoding(ContentEncoding::Aes128Gcm);
+        let request = build_request::<Vec<u8>>(builder.build().unwrap());
+
+        assert_eq!("aes128gcm", request.headers()["Content-Encoding"]);
+        assert_eq!("0", request.headers()["Content-Length"]);
+        assert!(request.body().is_empty());
+    }
+
     #[test]
     fn builds_a_correct_request_with_payload() {
         //This *was* a real token
@@ -145,7 +650,7 @@ mod tests {
 
         builder.set_payload(ContentEncoding::Aes128Gcm, "test".as_bytes());
 
-        let request = build_request::<isahc::Body>(builder.build().unwrap());
+        let request = build_request::<Vec<u8>>(builder.build().unwrap());
 
         let encoding = request.headers().get("Content-Encoding").unwrap().to_str().unwrap();
 
@@ -157,6 +662,289 @@ mod tests {
         assert_eq!(expected_uri.host(), request.uri().host());
     }
 
+    #[test]
+    fn omits_urgency_header_when_not_set() {
+        let info = SubscriptionInfo::new(
+            "https://fcm.googleapis.com/fcm/send/eKClHsXFm9E",
+            "BGa4N1PI79lboMR_YrwCiCsgp35DRvedt7opHcf0yM3iOBTSoQYqQLwWxAfRKE6tsDnReWmhsImkhDF_DBdkNSU",
+            "EvcWjEgzr4rbvhfi3yds0A",
+        );
+        let builder = WebPushMessageBuilder::new(&info);
+
+        let request = build_request::<Vec<u8>>(builder.build().unwrap());
+
+        assert!(request.headers().get("Urgency").is_none());
+    }
+
+    #[test]
+    fn sends_explicit_normal_urgency_header() {
+        let info = SubscriptionInfo::new(
+            "https://fcm.googleapis.com/fcm/send/eKClHsXFm9E",
+            "BGa4N1PI79lboMR_YrwCiCsgp35DRvedt7opHcf0yM3iOBTSoQYqQLwWxAfRKE6tsDnReWmhsImkhDF_DBdkNSU",
+            "EvcWjEgzr4rbvhfi3yds0A",
+        );
+        let mut builder = WebPushMessageBuilder::new(&info);
+        builder.set_urgency(Urgency::Normal);
+
+        let request = build_request::<Vec<u8>>(builder.build().unwrap());
+
+        assert_eq!("normal", request.headers().get("Urgency").unwrap().to_str().unwrap());
+    }
+
+    #[test]
+    fn sends_prefer_header_when_enabled() {
+        let info = SubscriptionInfo::new(
+            "https://fcm.googleapis.com/fcm/send/eKClHsXFm9E",
+            "BGa4N1PI79lboMR_YrwCiCsgp35DRvedt7opHcf0yM3iOBTSoQYqQLwWxAfRKE6tsDnReWmhsImkhDF_DBdkNSU",
+            "EvcWjEgzr4rbvhfi3yds0A",
+        );
+        let mut builder = WebPushMessageBuilder::new(&info);
+        builder.set_prefer_async(true);
+
+        let request = build_request::<Vec<u8>>(builder.build().unwrap());
+
+        assert_eq!(
+            "respond-async",
+            request.headers().get("Prefer").unwrap().to_str().unwrap()
+        );
+    }
+
+    #[test]
+    fn omits_prefer_header_by_default() {
+        let info = SubscriptionInfo::new(
+            "https://fcm.googleapis.com/fcm/send/eKClHsXFm9E",
+            "BGa4N1PI79lboMR_YrwCiCsgp35DRvedt7opHcf0yM3iOBTSoQYqQLwWxAfRKE6tsDnReWmhsImkhDF_DBdkNSU",
+            "EvcWjEgzr4rbvhfi3yds0A",
+        );
+        let builder = WebPushMessageBuilder::new(&info);
+
+        let request = build_request::<Vec<u8>>(builder.build().unwrap());
+
+        assert!(request.headers().get("Prefer").is_none());
+    }
+
+    #[test]
+    fn keeps_idempotency_key_across_retries() {
+        let info = SubscriptionInfo::new(
+            "https://fcm.googleapis.com/fcm/send/eKClHsXFm9E",
+            "BGa4N1PI79lboMR_YrwCiCsgp35DRvedt7opHcf0yM3iOBTSoQYqQLwWxAfRKE6tsDnReWmhsImkhDF_DBdkNSU",
+            "EvcWjEgzr4rbvhfi3yds0A",
+        );
+        let mut builder = WebPushMessageBuilder::new(&info);
+        builder.set_payload(ContentEncoding::Aes128Gcm, "test".as_bytes());
+        builder.set_idempotency_key("5f0e3c8a-retry").unwrap();
+
+        let message = builder.build().unwrap();
+        let first = build_request::<Vec<u8>>(message.clone());
+        let retry = build_request::<Vec<u8>>(message);
+
+        assert_eq!("5f0e3c8a-retry", first.headers()["Idempotency-Key"]);
+        assert_eq!(first.headers()["Idempotency-Key"], retry.headers()["Idempotency-Key"]);
+    }
+
+    #[test]
+    fn validates_a_correct_message() {
+        let info = SubscriptionInfo::new(
+            "https://fcm.googleapis.com/fcm/send/eKClHsXFm9E",
+            "BGa4N1PI79lboMR_YrwCiCsgp35DRvedt7opHcf0yM3iOBTSoQYqQLwWxAfRKE6tsDnReWmhsImkhDF_DBdkNSU",
+            "EvcWjEgzr4rbvhfi3yds0A",
+        );
+        let mut builder = WebPushMessageBuilder::new(&info);
+        builder.set_payload(ContentEncoding::AesGcm, "test".as_bytes());
+        builder.set_topic("some-topic".into());
+
+        assert!(validate_request(&builder.build().unwrap()).is_ok());
+    }
+
+    #[test]
+    fn rejects_an_invalid_idempotency_key() {
+        let mut message = test_message("https://fcm.googleapis.com/fcm/send/eKClHsXFm9E");
+        message.idempotency_key = Some("abc\r\nX-Evil: 1".into());
+
+        assert!(matches!(validate_request(&message), Err(WebPushError::InvalidHeader)));
+    }
+
+    #[test]
+    fn rejects_a_relative_endpoint() {
+        let message = test_message("/fcm/send/eKClHsXFm9E");
+
+        assert!(matches!(validate_request(&message), Err(WebPushError::InvalidUri)));
+    }
+
+    #[test]
+    fn rejects_an_oversized_topic() {
+        let mut message = test_message("https://fcm.googleapis.com/fcm/send/eKClHsXFm9E");
+        message.topic = Some("a".repeat(33));
+
+        assert!(matches!(validate_request(&message), Err(WebPushError::InvalidTopic)));
+    }
+
+    #[test]
+    fn converts_a_message_into_a_request() {
+        let info = SubscriptionInfo::new(
+            "https://fcm.googleapis.com/fcm/send/eKClHsXFm9E",
+            "BGa4N1PI79lboMR_YrwCiCsgp35DRvedt7opHcf0yM3iOBTSoQYqQLwWxAfRKE6tsDnReWmhsImkhDF_DBdkNSU",
+            "EvcWjEgzr4rbvhfi3yds0A",
+        );
+        let mut builder = WebPushMessageBuilder::new(&info);
+        builder.set_payload(ContentEncoding::Aes128Gcm, "test".as_bytes());
+
+        let request = Request::<Vec<u8>>::try_from(builder.build().unwrap()).unwrap();
+
+        assert_eq!(
+            "230",
+            request.headers().get("Content-Length").unwrap().to_str().unwrap()
+        );
+        assert_eq!(230, request.body().len());
+    }
+
+    #[test]
+    fn fails_to_convert_an_invalid_message_into_a_request() {
+        let message = test_message("/fcm/send/eKClHsXFm9E");
+
+        assert!(matches!(
+            Request::<Vec<u8>>::try_from(message),
+            Err(WebPushError::InvalidUri)
+        ));
+    }
+
+    #[test]
+    fn parses_a_created_response_with_location() {
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            "Location",
+            "https://updates.push.services.mozilla.com/m/gAAAAABaso4Vajy4"
+                .parse()
+                .unwrap(),
+        );
+
+        assert_eq!(
+            SuccessInfo {
+                status: StatusCode::CREATED,
+                location: Some("https://updates.push.services.mozilla.com/m/gAAAAABaso4Vajy4".into()),
+            },
+            parse_response_detailed(StatusCode::CREATED, &headers, vec![]).unwrap()
+        );
+    }
+
+    #[test]
+    fn parses_an_ok_response_without_location() {
+        let info = parse_response_detailed(StatusCode::OK, &HeaderMap::new(), vec![]).unwrap();
+
+        assert_eq!(StatusCode::OK, info.status);
+        assert_eq!(None, info.location);
+    }
+
+    #[test]
+    fn parses_a_detailed_error_response_like_parse_response() {
+        assert!(matches!(
+            parse_response_detailed(StatusCode::GONE, &HeaderMap::new(), vec![]),
+            Err(WebPushError::EndpointNotValid(_))
+        ));
+    }
+
+    #[test]
+    fn builds_an_fcm_v1_request() {
+        let mut message = FcmV1Message::new("my-project", "ya29.a0Af", "eKClHsXFm9E:APA91bH2x3gN");
+        message.add_data("title", "Hello");
+
+        let request = build_fcm_v1_request::<Vec<u8>>(&message).unwrap();
+
+        assert_eq!(
+            "https://fcm.googleapis.com/v1/projects/my-project/messages:send",
+            request.uri()
+        );
+        assert_eq!("POST", request.method());
+        assert_eq!("Bearer ya29.a0Af", request.headers()["Authorization"]);
+        assert_eq!("application/json", request.headers()["Content-Type"]);
+        assert_eq!(
+            serde_json::json!({"message": {"token": "eKClHsXFm9E:APA91bH2x3gN", "data": {"title": "Hello"}}}),
+            serde_json::from_slice::<serde_json::Value>(request.body()).unwrap()
+        );
+
+        assert!(matches!(
+            build_fcm_v1_request::<Vec<u8>>(&FcmV1Message::new("my project", "ya29", "token")),
+            Err(WebPushError::InvalidUri)
+        ));
+        assert!(matches!(
+            build_fcm_v1_request::<Vec<u8>>(&FcmV1Message::new("my-project", "ya29\r\nHost: evil", "token")),
+            Err(WebPushError::InvalidHeader)
+        ));
+    }
+
+    #[cfg(feature = "fcm-legacy")]
+    #[test]
+    fn builds_an_fcm_legacy_request_with_a_restricted_package_name() {
+        let mut message = FcmLegacyMessage::new("AAAA1234", vec!["token-a".into(), "token-b".into()]);
+        message.set_restricted_package_name("com.example.app");
+
+        let request = build_fcm_legacy_request::<Vec<u8>>(&message).unwrap();
+
+        assert_eq!("https://fcm.googleapis.com/fcm/send", request.uri());
+        assert_eq!("key=AAAA1234", request.headers()["Authorization"]);
+        assert!(matches!(
+            build_fcm_legacy_request::<Vec<u8>>(&FcmLegacyMessage::new("AAAA\n1234", vec!["token-a".into()])),
+            Err(WebPushError::InvalidHeader)
+        ));
+        assert_eq!(
+            serde_json::json!({"registration_ids": ["token-a", "token-b"], "restricted_package_name": "com.example.app"}),
+            serde_json::from_slice::<serde_json::Value>(request.body()).unwrap()
+        );
+
+        let body = r#"{"multicast_id":216,"success":1,"failure":1,"results":[
+            {"message_id":"1:0408"},{"error":"InvalidPackageName"}]}"#;
+        let response = parse_fcm_legacy_response(StatusCode::OK, body.into()).unwrap();
+
+        assert!(response.results[0].to_error().is_none());
+        assert!(matches!(
+            response.results[1].to_error(),
+            Some(WebPushError::InvalidPackageName)
+        ));
+    }
+
+    #[cfg(feature = "fcm-legacy")]
+    #[test]
+    fn parses_a_successful_fcm_legacy_response() {
+        let body = r#"{"multicast_id":216,"success":3,"failure":0,"canonical_ids":0,"results":[
+            {"message_id":"1:0408"},{"message_id":"1:1516"},{"message_id":"1:2342"}]}"#;
+
+        let response = parse_fcm_legacy_response(StatusCode::OK, body.into()).unwrap();
+
+        assert_eq!(3, response.success);
+        assert_eq!(Some("1:0408".to_string()), response.results[0].message_id);
+        assert_eq!(0, response.failed_tokens().count());
+    }
+
+    #[cfg(feature = "fcm-legacy")]
+    #[test]
+    fn parses_a_partially_failed_fcm_legacy_response() {
+        let body = r#"{"multicast_id":216,"success":3,"failure":3,"canonical_ids":1,"results":[
+            {"message_id":"1:0408"},{"error":"Unavailable"},{"error":"InvalidRegistration"},
+            {"message_id":"1:1516"},{"message_id":"1:2342","registration_id":"32"},{"error":"NotRegistered"}]}"#;
+
+        let response = parse_fcm_legacy_response(StatusCode::OK, body.into()).unwrap();
+
+        assert_eq!(3, response.failure);
+        assert_eq!(Some("32".to_string()), response.results[4].registration_id);
+        assert_eq!(
+            vec![(1, "Unavailable"), (2, "InvalidRegistration"), (5, "NotRegistered")],
+            response.failed_tokens().collect::<Vec<_>>()
+        );
+    }
+
+    #[cfg(feature = "fcm-legacy")]
+    #[test]
+    fn parses_a_failed_fcm_legacy_request() {
+        assert!(matches!(
+            parse_fcm_legacy_response(StatusCode::UNAUTHORIZED, vec![]),
+            Err(WebPushError::Unauthorized(_))
+        ));
+        assert!(matches!(
+            parse_fcm_legacy_response(StatusCode::OK, "not json".into()),
+            Err(WebPushError::InvalidResponse)
+        ));
+    }
+
     #[test]
     fn parses_a_successful_response_correctly() {
         assert!(matches!(parse_response(StatusCode::OK, vec![]), Ok(())));
@@ -190,7 +978,9 @@ mod tests {
     fn parses_a_payload_too_large_response_correctly() {
         assert!(matches!(
             parse_response(StatusCode::PAYLOAD_TOO_LARGE, vec![]),
-            Err(WebPushError::PayloadTooLarge)
+            Err(WebPushError::PayloadTooLarge {
+                source: PayloadLimitSource::Server
+            })
         ));
     }
 
@@ -202,6 +992,25 @@ mod tests {
         ));
     }
 
+    #[test]
+    fn consumes_a_server_error_with_retry_after() {
+        let mut headers = HeaderMap::new();
+        headers.insert("Retry-After", "120".parse().unwrap());
+
+        assert!(matches!(
+            consume_response(StatusCode::SERVICE_UNAVAILABLE, &headers, vec![]),
+            Err(WebPushError::ServerError {
+                retry_after: Some(delay),
+                ..
+            }) if delay == std::time::Duration::from_secs(120)
+        ));
+        assert!(matches!(
+            consume_response(StatusCode::SERVICE_UNAVAILABLE, &HeaderMap::new(), vec![]),
+            Err(WebPushError::ServerError { retry_after: None, .. })
+        ));
+        assert!(consume_response(StatusCode::CREATED, &headers, vec![]).is_ok());
+    }
+
     #[test]
     fn parses_a_bad_request_response_with_no_body_correctly() {
         assert!(matches!(
@@ -231,4 +1040,123 @@ mod tests {
             })),
         ));
     }
+
+    #[test]
+    fn parses_an_apple_bad_device_token_response_correctly() {
+        let endpoint: Uri = "https://web.push.apple.com/QGuQyavXutnMH...".parse().unwrap();
+        let json = r#"{"reason": "BadDeviceToken"}"#;
+
+        match parse_response_from(
+            &endpoint,
+            StatusCode::BAD_REQUEST,
+            &HeaderMap::new(),
+            json.as_bytes().to_vec(),
+        ) {
+            Err(WebPushError::EndpointNotValid(info)) => {
+                assert_eq!(400, info.code);
+                assert_eq!("BadDeviceToken", info.error);
+                assert_eq!("the subscription endpoint is invalid", info.message);
+            }
+            other => panic!("unexpected result: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn parses_an_apple_expired_provider_token_response_correctly() {
+        let endpoint: Uri = "https://web.push.apple.com/QGuQyavXutnMH...".parse().unwrap();
+        let json = r#"{"reason": "ExpiredProviderToken"}"#;
+
+        match parse_response_from(
+            &endpoint,
+            StatusCode::FORBIDDEN,
+            &HeaderMap::new(),
+            json.as_bytes().to_vec(),
+        ) {
+            Err(WebPushError::Unauthorized(info)) => {
+                assert_eq!(403, info.code);
+                assert_eq!("ExpiredProviderToken", info.error);
+                assert_eq!("the VAPID token has expired", info.message);
+            }
+            other => panic!("unexpected result: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn parses_an_apple_response_without_reason_correctly() {
+        let endpoint: Uri = "https://web.push.apple.com/QGuQyavXutnMH...".parse().unwrap();
+
+        assert!(matches!(
+            parse_response_from(&endpoint, StatusCode::GONE, &HeaderMap::new(), vec![]),
+            Err(WebPushError::EndpointNotValid(_))
+        ));
+    }
+
+    #[test]
+    fn parses_a_non_apple_response_with_reason_correctly() {
+        let endpoint: Uri = "https://fcm.googleapis.com/fcm/send/...".parse().unwrap();
+        let json = r#"{"reason": "ExpiredProviderToken"}"#;
+
+        assert!(matches!(
+            parse_response_from(
+                &endpoint,
+                StatusCode::FORBIDDEN,
+                &HeaderMap::new(),
+                json.as_bytes().to_vec()
+            ),
+            Err(WebPushError::Other(_))
+        ));
+    }
+
+    #[test]
+    fn parses_a_wns_forbidden_response_correctly() {
+        let endpoint: Uri = "https://wns2-par02p.notify.windows.com/w/?token=BQYAAAD"
+            .parse()
+            .unwrap();
+        let mut headers = HeaderMap::new();
+        headers.insert("X-WNS-Status", "dropped".parse().unwrap());
+        headers.insert(
+            "X-WNS-Error-Description",
+            "The cloud service is not authorized to send a notification to this URI"
+                .parse()
+                .unwrap(),
+        );
+
+        match parse_response_from(&endpoint, StatusCode::FORBIDDEN, &headers, vec![]) {
+            Err(WebPushError::Unauthorized(info)) => {
+                assert_eq!(403, info.code);
+                assert_eq!("dropped", info.error);
+                assert_eq!(
+                    "The cloud service is not authorized to send a notification to this URI",
+                    info.message
+                );
+            }
+            other => panic!("unexpected result: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn parses_a_wns_throttled_response_correctly() {
+        let endpoint: Uri = "https://wns2-par02p.notify.windows.com/w/?token=BQYAAAD"
+            .parse()
+            .unwrap();
+
+        assert!(matches!(
+            parse_response_from(&endpoint, StatusCode::NOT_ACCEPTABLE, &HeaderMap::new(), vec![]),
+            Err(WebPushError::ServerError { .. })
+        ));
+    }
+
+    #[test]
+    fn parses_a_wns_gone_response_correctly() {
+        let endpoint: Uri = "https://wns2-par02p.notify.windows.com/w/?token=BQYAAAD"
+            .parse()
+            .unwrap();
+        let mut headers = HeaderMap::new();
+        headers.insert("X-WNS-Status", "dropped".parse().unwrap());
+
+        assert!(matches!(
+            parse_response_from(&endpoint, StatusCode::GONE, &headers, vec![]),
+            Err(WebPushError::EndpointNotValid(ErrorInfo { code: 410, .. }))
+        ));
+    }
 }
@@ -0,0 +1,199 @@
+//! Backoff for retrying sends the push service asked to be retried later.
+
+use std::time::Duration;
+
+use crate::error::WebPushError;
+
+/// Decides if and when a failed send should be retried.
+///
+/// Server errors are retried after the `Retry-After` the push service sent, or an exponential backoff from the base
+/// delay if it sent none. The other [transient](WebPushError::is_transient) errors, such as I/O errors, connection
+/// failures and timeouts, are retried with the exponential backoff. All other errors are final.
+///
+/// A random jitter is applied to every delay, spreading out retries of concurrent sends that got the same
+/// `Retry-After`. The jitter defaults to 20% of the delay.
+///
+/// Retry with a clone of the same [`WebPushMessage`](crate::WebPushMessage), so the encrypted payload and the
+/// idempotency key stay the same across attempts.
+///
+/// # Example
+///
+/// ```no_run
+/// # use std::time::Duration;
+/// # use web_push::{RetryPolicy, WebPushError};
+/// # fn send() -> Result<(), WebPushError> { Ok(()) }
+/// let mut policy = RetryPolicy::new(3, Duration::from_secs(1));
+/// policy.set_jitter(0.5);
+///
+/// let mut attempt = 0;
+/// while let Err(error) = send() {
+///     match policy.delay_for(&error, attempt) {
+///         Some(delay) => std::thread::sleep(delay),
+///         None => break,
+///     }
+///     attempt += 1;
+/// }
+/// ```
+#[derive(Debug, Clone)]
+pub struct RetryPolicy {
+    max_retries: u32,
+    base_delay: Duration,
+    max_delay: Duration,
+    jitter: f64,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy::new(3, Duration::from_secs(1))
+    }
+}
+
+impl RetryPolicy {
+    /// Creates a policy retrying at most `max_retries` times, backing off exponentially from `base_delay`.
+    pub fn new(max_retries: u32, base_delay: Duration) -> RetryPolicy {
+        RetryPolicy {
+            max_retries,
+            base_delay,
+            max_delay: Duration::from_secs(300),
+            jitter: 0.2,
+        }
+    }
+
+    /// Sets the longest delay before a retry, before applying jitter. Defaults to five minutes.
+    pub fn set_max_delay(&mut self, max_delay: Duration) {
+        self.max_delay = max_delay;
+    }
+
+    /// Sets the jitter as a fraction of the delay, clamped to `0.0..=1.0`. A delay `d` becomes a random duration
+    /// in `d ± d * jitter`. Set to `0.0` to disable jitter.
+    pub fn set_jitter(&mut self, jitter: f64) {
+        self.jitter = if jitter.is_nan() { 0.0 } else { jitter.clamp(0.0, 1.0) };
+    }
+
+    /// The delay before retrying after `error` on the zero based `attempt`, or `None` if the send should not be
+    /// retried.
+    pub fn delay_for(&self, error: &WebPushError, attempt: u32) -> Option<Duration> {
+        if attempt >= self.max_retries {
+            return None;
+        }
+
+        let delay = match error {
+            WebPushError::ServerError {
+                retry_after: Some(retry_after),
+                ..
+            } => *retry_after,
+            error if error.is_transient() => self.backoff(attempt),
+            _ => return None,
+        };
+
+        Some(self.jittered(delay.min(self.max_delay), random_unit()))
+    }
+
+    fn backoff(&self, attempt: u32) -> Duration {
+        self.base_delay
+            .checked_mul(2u32.saturating_pow(attempt))
+            .unwrap_or(self.max_delay)
+    }
+
+    /// Moves `delay` by up to `jitter` of itself in either direction, `unit` being a random number in `0.0..1.0`.
+    fn jittered(&self, delay: Duration, unit: f64) -> Duration {
+        let offset = delay.as_secs_f64() * self.jitter * (unit * 2.0 - 1.0);
+
+        Duration::from_secs_f64((delay.as_secs_f64() + offset).max(0.0))
+    }
+}
+
+/// A random number in `0.0..1.0`, from the generator `jwt_simple` already uses for signing.
+fn random_unit() -> f64 {
+    jwt_simple::reexports::rand::random()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use crate::clients::retry::RetryPolicy;
+    use crate::error::{ErrorInfo, PayloadLimitSource, WebPushError};
+
+    fn server_error(retry_after: Option<Duration>) -> WebPushError {
+        WebPushError::ServerError {
+            retry_after,
+            info: ErrorInfo {
+                code: 503,
+                errno: 999,
+                error: "unknown error".into(),
+                message: "-".into(),
+            },
+        }
+    }
+
+    #[test]
+    fn jitter_spreads_delay_around_retry_after() {
+        let mut policy = RetryPolicy::new(3, Duration::from_secs(1));
+        policy.set_jitter(0.5);
+
+        let retry_after = Duration::from_secs(10);
+
+        assert_eq!(Duration::from_secs(5), policy.jittered(retry_after, 0.0));
+        assert_eq!(Duration::from_secs(10), policy.jittered(retry_after, 0.5));
+        assert_eq!(Duration::from_secs(15), policy.jittered(retry_after, 1.0));
+
+        for _ in 0..100 {
+            let delay = policy.delay_for(&server_error(Some(retry_after)), 0).unwrap();
+            assert!(delay >= Duration::from_secs(5) && delay <= Duration::from_secs(15));
+        }
+    }
+
+    #[test]
+    fn backs_off_exponentially_without_retry_after() {
+        let mut policy = RetryPolicy::new(5, Duration::from_secs(1));
+        policy.set_jitter(0.0);
+
+        assert_eq!(Some(Duration::from_secs(1)), policy.delay_for(&server_error(None), 0));
+        assert_eq!(Some(Duration::from_secs(4)), policy.delay_for(&server_error(None), 2));
+    }
+
+    #[test]
+    fn backs_off_on_network_failures() {
+        let mut policy = RetryPolicy::new(5, Duration::from_secs(1));
+        policy.set_jitter(0.0);
+
+        assert_eq!(
+            Some(Duration::from_secs(2)),
+            policy.delay_for(&WebPushError::ConnectionFailed("connection refused".into()), 1)
+        );
+        assert_eq!(
+            Some(Duration::from_secs(2)),
+            policy.delay_for(&WebPushError::Timeout, 1)
+        );
+    }
+
+    #[test]
+    fn caps_delay_and_retries() {
+        let mut policy = RetryPolicy::new(2, Duration::from_secs(1));
+        policy.set_jitter(0.0);
+        policy.set_max_delay(Duration::from_secs(30));
+
+        assert_eq!(
+            Some(Duration::from_secs(30)),
+            policy.delay_for(&server_error(Some(Duration::from_secs(3600))), 0)
+        );
+        assert_eq!(None, policy.delay_for(&server_error(None), 2));
+    }
+
+    #[test]
+    fn does_not_retry_final_errors() {
+        let policy = RetryPolicy::default();
+
+        assert_eq!(None, policy.delay_for(&WebPushError::InvalidUri, 0));
+        assert_eq!(
+            None,
+            policy.delay_for(
+                &WebPushError::PayloadTooLarge {
+                    source: PayloadLimitSource::Local
+                },
+                0
+            )
+        );
+    }
+}
@@ -25,6 +25,7 @@ impl fmt::Display for ErrorInfo {
 }
 
 #[derive(Debug)]
+#[non_exhaustive]
 pub enum WebPushError {
     /// An unknown error happened while encrypting or sending the message
     Unspecified,
@@ -45,8 +46,10 @@ pub enum WebPushError {
     EndpointNotValid(ErrorInfo),
     /// The URL specified is invalid and should not be used again
     EndpointNotFound(ErrorInfo),
-    /// Maximum allowed payload size is 3800 characters
-    PayloadTooLarge,
+    /// The payload was too large, either for the limit checked before sending, or for the push service
+    PayloadTooLarge {
+        source: PayloadLimitSource,
+    },
     /// Error in reading a file
     Io(IoError),
     /// Make sure the message was addressed to a registration token whose
@@ -56,17 +59,53 @@ pub enum WebPushError {
     InvalidTtl,
     /// The Topic value provided was invalid
     InvalidTopic,
+    /// The HTTP method was not `POST` or `PUT`
+    InvalidMethod,
+    /// An extra header was malformed, too long, or one the crate sets itself, or a credential was not a valid header
+    /// value
+    InvalidHeader,
     /// The request was missing required crypto keys
     MissingCryptoKeys,
     /// One or more of the crypto key elements are invalid.
     InvalidCryptoKeys,
+    /// The decoded auth secret of the subscription was not 16 bytes long, contains the actual length
+    InvalidAuthSecret(usize),
+    /// The public key of the VAPID signature was not the 65 byte uncompressed P-256 point, contains the actual length
+    InvalidVapidPublicKey(usize),
+    /// The aes128gcm record size was below 18 bytes, the minimum of RFC8188, contains the given size
+    InvalidRecordSize(u32),
+    /// A key of the subscription was base64 encoded twice, so it decodes to base64 text instead of the key bytes
+    DoubleEncodedKey,
+    /// Every problem found by [`SubscriptionInfo::validate_all`](crate::SubscriptionInfo::validate_all), each
+    /// prefixed with the field it concerns, or the parse error of subscription JSON prefixed with `json`
+    InvalidSubscription {
+        reasons: Vec<String>,
+    },
     /// Corrupted response data
     InvalidResponse,
     /// A claim had invalid data
     InvalidClaims,
+    /// Could not connect to the push service, for example because its host name did not resolve. Contains the
+    /// reason given by the HTTP client.
+    ConnectionFailed(String),
+    /// The send was cancelled before it completed
+    Cancelled,
+    /// The request did not complete within the configured timeout
+    Timeout,
+    /// A declarative notification had more actions than browsers display
+    TooManyActions,
     Other(ErrorInfo),
 }
 
+/// Where a payload was found too large, see [`WebPushError::PayloadTooLarge`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PayloadLimitSource {
+    /// The payload exceeded the limit checked by the crate before encrypting it. The message was not sent.
+    Local,
+    /// The push service rejected the payload with `413 Payload Too Large`.
+    Server,
+}
+
 impl Error for WebPushError {}
 
 impl From<JsonError> for WebPushError {
@@ -89,15 +128,25 @@ impl From<InvalidUri> for WebPushError {
 
 #[cfg(feature = "hyper-client")]
 impl From<hyper::Error> for WebPushError {
-    fn from(_: hyper::Error) -> Self {
-        Self::Unspecified
+    fn from(err: hyper::Error) -> Self {
+        if err.is_connect() {
+            Self::ConnectionFailed(err.to_string())
+        } else {
+            Self::Unspecified
+        }
     }
 }
 
 #[cfg(feature = "isahc-client")]
 impl From<isahc::Error> for WebPushError {
-    fn from(_: isahc::Error) -> Self {
-        Self::Unspecified
+    fn from(err: isahc::Error) -> Self {
+        match err.kind() {
+            isahc::error::ErrorKind::ConnectionFailed | isahc::error::ErrorKind::NameResolution => {
+                Self::ConnectionFailed(err.to_string())
+            }
+            isahc::error::ErrorKind::Timeout => Self::Timeout,
+            _ => Self::Unspecified,
+        }
     }
 }
 
@@ -113,6 +162,23 @@ impl From<DecodeError> for WebPushError {
     }
 }
 
+impl From<ece::Error> for WebPushError {
+    /// Classifies an encryption failure. A content too long for one record is `PayloadTooLarge`, a key or auth
+    /// secret the encryption can't use is `InvalidCryptoKeys`, and anything else `Unspecified`.
+    fn from(err: ece::Error) -> WebPushError {
+        match err {
+            ece::Error::PlaintextTooLong => WebPushError::PayloadTooLarge {
+                source: PayloadLimitSource::Local,
+            },
+            ece::Error::InvalidAuthSecret
+            | ece::Error::InvalidKeyLength
+            | ece::Error::DecodeError(_)
+            | ece::Error::OpenSSLError(_) => WebPushError::InvalidCryptoKeys,
+            _ => WebPushError::Unspecified,
+        }
+    }
+}
+
 impl WebPushError {
     pub fn short_description(&self) -> &'static str {
         match *self {
@@ -124,16 +190,89 @@ impl WebPushError {
             WebPushError::InvalidUri => "invalid_uri",
             WebPushError::EndpointNotValid(_) => "endpoint_not_valid",
             WebPushError::EndpointNotFound(_) => "endpoint_not_found",
-            WebPushError::PayloadTooLarge => "payload_too_large",
+            WebPushError::PayloadTooLarge { .. } => "payload_too_large",
             WebPushError::InvalidPackageName => "invalid_package_name",
             WebPushError::InvalidTtl => "invalid_ttl",
             WebPushError::InvalidTopic => "invalid_topic",
+            WebPushError::InvalidMethod => "invalid_method",
+            WebPushError::InvalidHeader => "invalid_header",
             WebPushError::InvalidResponse => "invalid_response",
             WebPushError::MissingCryptoKeys => "missing_crypto_keys",
             WebPushError::InvalidCryptoKeys => "invalid_crypto_keys",
+            WebPushError::InvalidAuthSecret(_) => "invalid_auth_secret",
+            WebPushError::DoubleEncodedKey => "double_encoded_key",
+            WebPushError::InvalidSubscription { .. } => "invalid_subscription",
+            WebPushError::InvalidVapidPublicKey(_) => "invalid_vapid_public_key",
+            WebPushError::InvalidRecordSize(_) => "invalid_record_size",
             WebPushError::Io(_) => "io_error",
             WebPushError::Other(_) => "other",
             WebPushError::InvalidClaims => "invalidClaims",
+            WebPushError::ConnectionFailed(_) => "connection_failed",
+            WebPushError::Cancelled => "cancelled",
+            WebPushError::Timeout => "timeout",
+            WebPushError::TooManyActions => "too_many_actions",
+        }
+    }
+
+    /// Whether the push service reported the subscription as gone, so it should be deleted and never used again.
+    pub fn is_subscription_dead(&self) -> bool {
+        matches!(
+            self,
+            WebPushError::EndpointNotValid(_) | WebPushError::EndpointNotFound(_)
+        )
+    }
+
+    /// Whether sending the same message again later may succeed: server errors, I/O errors, connection failures
+    /// and timeouts.
+    pub fn is_transient(&self) -> bool {
+        matches!(
+            self,
+            WebPushError::ServerError { .. }
+                | WebPushError::Io(_)
+                | WebPushError::ConnectionFailed(_)
+                | WebPushError::Timeout
+        )
+    }
+
+    /// A hint at what usually causes the error, for errors with a common misconfiguration behind them.
+    ///
+    /// An `Unauthorized` response almost always means the push service rejected the VAPID signature.
+    pub fn likely_cause(&self) -> Option<&'static str> {
+        match self {
+            WebPushError::Unauthorized(_) => Some(
+                "the VAPID signature was rejected: check that it hasn't expired, that its audience is the origin of \
+                 the endpoint, that it has a sub claim, and that the key is the application server key the \
+                 subscription was created with",
+            ),
+            _ => None,
+        }
+    }
+
+    /// A log safe description of the error, keeping the error category and status code, but omitting the error
+    /// messages returned by the push service, which may contain endpoints or subscription identifiers.
+    ///
+    /// The full details remain available through `Display` and `Debug`.
+    pub fn redacted(&self) -> String {
+        match self {
+            WebPushError::Unauthorized(info)
+            | WebPushError::BadRequest(info)
+            | WebPushError::NotImplemented(info)
+            | WebPushError::EndpointNotValid(info)
+            | WebPushError::EndpointNotFound(info)
+            | WebPushError::Other(info) => format!("{} (status {})", self.short_description(), info.code),
+            WebPushError::ServerError {
+                retry_after: Some(retry_after),
+                info,
+            } => format!(
+                "{} (status {}, retry after {}s)",
+                self.short_description(),
+                info.code,
+                retry_after.as_secs()
+            ),
+            WebPushError::ServerError { info, .. } => format!("{} (status {})", self.short_description(), info.code),
+            WebPushError::Io(err) => format!("{} ({:?})", self.short_description(), err.kind()),
+            WebPushError::ConnectionFailed(_) => self.short_description().into(),
+            _ => self.to_string(),
         }
     }
 }
@@ -142,10 +281,26 @@ impl fmt::Display for WebPushError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match self {
             WebPushError::Unspecified => write!(f, "unspecified error"),
-            WebPushError::Unauthorized(info) => write!(f, "unauthorized: {}", info),
+            WebPushError::Unauthorized(info) => {
+                write!(
+                    f,
+                    "unauthorized: {} ({})",
+                    info,
+                    self.likely_cause().unwrap_or_default()
+                )
+            }
             WebPushError::BadRequest(info) => write!(f, "bad request: {}", info),
+            WebPushError::ServerError {
+                retry_after: Some(retry_after),
+                info,
+            } => write!(f, "server error (retry after {}s): {}", retry_after.as_secs(), info),
             WebPushError::ServerError { info, .. } => write!(f, "server error: {}", info),
-            WebPushError::PayloadTooLarge => write!(f, "maximum payload size of 3070 characters exceeded"),
+            WebPushError::PayloadTooLarge {
+                source: PayloadLimitSource::Local,
+            } => write!(f, "maximum payload size of 3052 bytes exceeded"),
+            WebPushError::PayloadTooLarge {
+                source: PayloadLimitSource::Server,
+            } => write!(f, "payload rejected as too large by the push service"),
             WebPushError::InvalidUri => write!(f, "invalid uri provided"),
             WebPushError::NotImplemented(info) => write!(f, "not implemented: {}", info),
             WebPushError::EndpointNotValid(info) => write!(f, "endpoint not valid: {}", info),
@@ -157,11 +312,40 @@ impl fmt::Display for WebPushError {
             ),
             WebPushError::InvalidTtl => write!(f, "invalid or missing ttl value"),
             WebPushError::InvalidTopic => write!(f, "invalid topic value"),
+            WebPushError::InvalidMethod => write!(f, "invalid http method, only POST and PUT are supported"),
+            WebPushError::InvalidHeader => write!(
+                f,
+                "invalid header, it must be printable ascii, and extra headers at most 1024 bytes and not set by the crate"
+            ),
             WebPushError::InvalidResponse => write!(f, "could not parse response data"),
             WebPushError::MissingCryptoKeys => write!(f, "request is missing cryptographic keys"),
             WebPushError::InvalidCryptoKeys => write!(f, "request has invalid cryptographic keys"),
+            WebPushError::DoubleEncodedKey => write!(
+                f,
+                "a subscription key decodes to base64 text, it was probably base64 encoded twice"
+            ),
+            WebPushError::InvalidSubscription { reasons } => {
+                write!(f, "invalid subscription: {}", reasons.join("; "))
+            }
+            WebPushError::InvalidVapidPublicKey(len) => write!(
+                f,
+                "vapid public key must be the 65 byte uncompressed point, but was {} bytes",
+                len
+            ),
+            WebPushError::InvalidRecordSize(record_size) => write!(
+                f,
+                "aes128gcm record size must be at least 18 bytes, but was {} bytes",
+                record_size
+            ),
+            WebPushError::InvalidAuthSecret(len) => {
+                write!(f, "subscription auth secret must be 16 bytes, but was {} bytes", len)
+            }
             WebPushError::Other(info) => write!(f, "other: {}", info),
             WebPushError::InvalidClaims => write!(f, "at least one jwt claim was invalid"),
+            WebPushError::ConnectionFailed(reason) => write!(f, "connection failed: {}", reason),
+            WebPushError::Cancelled => write!(f, "send was cancelled"),
+            WebPushError::Timeout => write!(f, "request timed out"),
+            WebPushError::TooManyActions => write!(f, "notification has too many actions"),
         }
     }
 }
@@ -184,3 +368,104 @@ impl RetryAfter {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use crate::error::{ErrorInfo, WebPushError};
+
+    fn error_info() -> ErrorInfo {
+        ErrorInfo {
+            code: 503,
+            errno: 999,
+            error: "unknown error".into(),
+            message: "-".into(),
+        }
+    }
+
+    #[test]
+    fn displays_server_error_with_retry_after() {
+        let error = WebPushError::ServerError {
+            retry_after: Some(Duration::from_secs(30)),
+            info: error_info(),
+        };
+
+        assert_eq!(
+            "server error (retry after 30s): code 503, errno 999: unknown error (-)",
+            error.to_string()
+        );
+    }
+
+    #[test]
+    fn displays_server_error_without_retry_after() {
+        let error = WebPushError::ServerError {
+            retry_after: None,
+            info: error_info(),
+        };
+
+        assert_eq!(
+            "server error: code 503, errno 999: unknown error (-)",
+            error.to_string()
+        );
+    }
+
+    #[test]
+    fn hints_at_vapid_problems_when_unauthorized() {
+        let error = WebPushError::Unauthorized(ErrorInfo {
+            code: 401,
+            errno: 109,
+            error: "Unauthorized".into(),
+            message: "Request did not validate".into(),
+        });
+
+        assert!(error.likely_cause().unwrap().contains("VAPID signature"));
+        assert!(error
+            .to_string()
+            .starts_with("unauthorized: code 401, errno 109: Unauthorized (Request did not validate) (the VAPID"));
+        assert!(WebPushError::InvalidUri.likely_cause().is_none());
+    }
+
+    #[test]
+    fn redacts_server_messages() {
+        let error = WebPushError::EndpointNotValid(ErrorInfo {
+            code: 410,
+            errno: 106,
+            error: "Gone".into(),
+            message: "https://updates.push.services.mozilla.com/wpush/v2/gAAAAABaso4Vajy4STM25r5y5oFfyN451rUmES6mhQ"
+                .into(),
+        });
+
+        assert_eq!("endpoint_not_valid (status 410)", error.redacted());
+        assert!(error.to_string().contains("gAAAAABaso4Vajy4STM25r5y5oFfyN451rUmES6mhQ"));
+    }
+
+    #[test]
+    fn redacts_server_error_keeping_retry_after() {
+        let error = WebPushError::ServerError {
+            retry_after: Some(Duration::from_secs(30)),
+            info: error_info(),
+        };
+
+        assert_eq!("server_error (status 503, retry after 30s)", error.redacted());
+    }
+
+    #[cfg(feature = "isahc-client")]
+    #[test]
+    fn maps_isahc_name_resolution_to_connection_failed() {
+        let error = WebPushError::from(isahc::Error::from(isahc::error::ErrorKind::NameResolution));
+
+        assert!(matches!(error, WebPushError::ConnectionFailed(_)));
+        assert_eq!("connection_failed", error.redacted());
+        assert_eq!("connection failed: failed to resolve host name", error.to_string());
+    }
+
+    #[cfg(feature = "isahc-client")]
+    #[test]
+    fn keeps_isahc_timeouts_apart_from_connection_failures() {
+        let error = WebPushError::from(isahc::Error::from(isahc::error::ErrorKind::Timeout));
+
+        assert!(!matches!(error, WebPushError::ConnectionFailed(_)));
+        assert!(matches!(error, WebPushError::Timeout));
+    }
+}
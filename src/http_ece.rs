@@ -1,19 +1,43 @@
 //! Payload encryption algorithm
 
-use ece::encrypt;
+use std::fmt;
 
-use crate::error::WebPushError;
+use ece::encrypt;
+use jwt_simple::prelude::{ECDSAP256PublicKeyLike, ES256PublicKey};
+
+#[cfg(feature = "custom-aes128gcm")]
+use crate::aes128gcm::{
+    aes128gcm_encrypt, aes128gcm_records, random_salt, EphemeralKey, AES128GCM_MIN_RECORD_SIZE,
+    AES128GCM_PADDING_BLOCK_SIZE, AES128GCM_RECORD_SIZE,
+};
+use crate::error::{PayloadLimitSource, WebPushError};
 use crate::message::WebPushPayload;
 use crate::vapid::VapidSignature;
 
+/// The largest plaintext that can be encrypted, which works with Google's and Mozilla's push servers.
+pub(crate) const MAX_PAYLOAD_LEN: usize = 3052;
+
+/// The length of the uncompressed P-256 point sent as the VAPID public key.
+const VAPID_PUBLIC_KEY_LEN: usize = 65;
+
 /// Content encoding profiles.
+///
+/// This enum is `#[non_exhaustive]`, so new encodings can be added in minor versions. Code matching on it needs a
+/// wildcard arm, which means the compiler no longer points out the places to update when an encoding is added. Use
+/// [`ContentEncoding::to_str`] or `Display` to get the header value of any encoding without matching.
 #[derive(Debug, PartialEq, Copy, Clone, Default)]
+#[non_exhaustive]
 pub enum ContentEncoding {
-    //Make sure this enum remains exhaustive as that allows for easier migrations to new versions.
     #[default]
     Aes128Gcm,
     /// Note: this is an older version of ECE, and should not be used unless you know for sure it is required. In all other cases, use aes128gcm.
     AesGcm,
+    /// No encryption, the payload is sent as plaintext. Requires the `test-util` feature.
+    ///
+    /// Only for testing against local mock push servers. Browsers reject unencrypted payloads, and the payload is
+    /// readable by anyone on the way. Never use this in production.
+    #[cfg(feature = "test-util")]
+    Identity,
 }
 
 impl ContentEncoding {
@@ -22,6 +46,36 @@ impl ContentEncoding {
         match &self {
             ContentEncoding::Aes128Gcm => "aes128gcm",
             ContentEncoding::AesGcm => "aesgcm",
+            #[cfg(feature = "test-util")]
+            ContentEncoding::Identity => "identity",
+        }
+    }
+}
+
+impl fmt::Display for ContentEncoding {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.to_str())
+    }
+}
+
+/// The format of the VAPID `Authorization` header.
+#[derive(Debug, PartialEq, Eq, Copy, Clone, Default)]
+pub enum VapidScheme {
+    /// `Authorization: vapid t=<jwt>, k=<key>` from RFC8292. Supported by all current push services.
+    #[default]
+    Vapid,
+    /// `Authorization: WebPush <jwt>` with the key in `Crypto-Key: p256ecdsa=<key>`, from the drafts of RFC8292.
+    /// Only needed for push services that still expect it together with aesgcm.
+    WebPush,
+}
+
+impl VapidScheme {
+    /// The scheme push services expect together with `encoding`: `WebPush` for the legacy aesgcm, and `Vapid`
+    /// otherwise.
+    pub fn for_encoding(encoding: ContentEncoding) -> VapidScheme {
+        match encoding {
+            ContentEncoding::AesGcm => VapidScheme::WebPush,
+            _ => VapidScheme::Vapid,
         }
     }
 }
@@ -32,6 +86,10 @@ pub struct HttpEce<'a> {
     peer_secret: &'a [u8],
     encoding: ContentEncoding,
     vapid_signature: Option<VapidSignature>,
+    vapid_scheme: VapidScheme,
+    legacy_encryption_key: bool,
+    #[cfg(feature = "custom-aes128gcm")]
+    record_size: Option<usize>,
 }
 
 impl<'a> HttpEce<'a> {
@@ -50,44 +108,92 @@ impl<'a> HttpEce<'a> {
             peer_secret,
             encoding,
             vapid_signature,
+            vapid_scheme: VapidScheme::default(),
+            legacy_encryption_key: false,
+            #[cfg(feature = "custom-aes128gcm")]
+            record_size: None,
+        }
+    }
+
+    /// Sets the format of the VAPID `Authorization` header. Defaults to [`VapidScheme::Vapid`] for all encodings,
+    /// use [`VapidScheme::for_encoding`] to match the encoding instead.
+    pub fn set_vapid_scheme(&mut self, vapid_scheme: VapidScheme) {
+        self.vapid_scheme = vapid_scheme;
+    }
+
+    /// Also sends the sender's public key in the `Encryption-Key` header, as expected by push implementations
+    /// predating the `Crypto-Key` header. Only applies to the aesgcm encoding. Off by default.
+    pub fn set_legacy_encryption_key(&mut self, legacy_encryption_key: bool) {
+        self.legacy_encryption_key = legacy_encryption_key;
+    }
+
+    /// Sets the aes128gcm record size written to the payload header, instead of the 4096 bytes the `ece` crate uses.
+    /// Returns `InvalidRecordSize` if `record_size` is below 18, the minimum of RFC 8188. Only applies to the aes128gcm
+    /// encoding. Requires the `custom-aes128gcm` feature.
+    ///
+    /// Once set, even to 4096, payloads are encrypted by the implementation of the `custom-aes128gcm` feature instead
+    /// of the `ece` crate. Content longer than a record is split into several records, which some push services and
+    /// browsers don't support, so only lower the record size after testing against the services you send to.
+    #[cfg(feature = "custom-aes128gcm")]
+    pub fn set_record_size(&mut self, record_size: u32) -> Result<(), WebPushError> {
+        if (record_size as usize) < AES128GCM_MIN_RECORD_SIZE {
+            return Err(WebPushError::InvalidRecordSize(record_size));
         }
+
+        self.record_size = Some(record_size as usize);
+        Ok(())
     }
 
     /// Encrypts a payload. The maximum length for the payload is 3800
     /// characters, which is the largest that works with Google's and Mozilla's
     /// push servers.
     pub fn encrypt(&self, content: &'a [u8]) -> Result<WebPushPayload, WebPushError> {
-        if content.len() > 3052 {
-            return Err(WebPushError::PayloadTooLarge);
+        if content.len() > MAX_PAYLOAD_LEN {
+            return Err(WebPushError::PayloadTooLarge {
+                source: PayloadLimitSource::Local,
+            });
         }
 
         //Add more encoding standards to this match as they are created.
         match self.encoding {
+            #[cfg(feature = "custom-aes128gcm")]
+            ContentEncoding::Aes128Gcm if self.record_size.is_some() => {
+                self.encrypt_with_ephemeral_key(content, &EphemeralKey::generate()?)
+            }
             ContentEncoding::Aes128Gcm => {
                 let result = encrypt(self.peer_public_key, self.peer_secret, content);
 
                 let mut headers = Vec::new();
 
-                self.add_vapid_headers(&mut headers);
+                self.add_vapid_headers(&mut headers)?;
 
-                match result {
-                    Ok(data) => Ok(WebPushPayload {
-                        content: data,
-                        crypto_headers: headers,
-                        content_encoding: self.encoding,
-                    }),
-                    _ => Err(WebPushError::InvalidCryptoKeys),
-                }
+                Ok(WebPushPayload {
+                    content: result?,
+                    crypto_headers: headers,
+                    content_encoding: self.encoding,
+                })
             }
             ContentEncoding::AesGcm => {
                 let result = self.aesgcm_encrypt(content);
 
-                let data = result.map_err(|_| WebPushError::InvalidCryptoKeys)?;
+                let data = result?;
 
                 // Get headers exclusive to the aesgcm scheme (Crypto-Key ect.)
                 let mut headers = data.headers(self.vapid_signature.as_ref().map(|v| v.auth_k.as_slice()));
 
-                self.add_vapid_headers(&mut headers);
+                if self.legacy_encryption_key {
+                    let dh = headers
+                        .iter()
+                        .filter(|(name, _)| *name == "Crypto-Key")
+                        .flat_map(|(_, value)| value.split(';'))
+                        .map(str::trim)
+                        .find(|param| param.starts_with("dh="))
+                        .map(String::from);
+
+                    headers.extend(dh.map(|dh| ("Encryption-Key", dh)));
+                }
+
+                self.add_vapid_headers(&mut headers)?;
 
                 // ECE library base64 encodes content in aesgcm, but not aes128gcm, so decode base64 here to match the 128 API
                 let data = base64::decode_config(data.body(), base64::URL_SAFE_NO_PAD)
@@ -99,22 +205,152 @@ impl<'a> HttpEce<'a> {
                     content_encoding: self.encoding,
                 })
             }
+            #[cfg(feature = "test-util")]
+            ContentEncoding::Identity => {
+                let mut headers = Vec::new();
+
+                self.add_vapid_headers(&mut headers)?;
+
+                Ok(WebPushPayload {
+                    content: content.to_vec(),
+                    crypto_headers: headers,
+                    content_encoding: self.encoding,
+                })
+            }
         }
     }
 
-    /// Adds VAPID authorisation header to headers, if VAPID is being used.
-    fn add_vapid_headers(&self, headers: &mut Vec<(&str, String)>) {
-        //VAPID uses a special Authorisation header, which contains a ecdhsa key and a jwt.
+    /// Encrypts a payload like [`HttpEce::encrypt`], calling `on_progress` with the number of records encrypted so far
+    /// and the total after each aes128gcm record, for feedback while encrypting large payloads.
+    ///
+    /// Only payloads split into several records by [`HttpEce::set_record_size`] take noticeable time. Single-record
+    /// encryption, which the default record size always is, doesn't need this and reports one record out of one
+    /// once done. Requires the `custom-aes128gcm` feature.
+    #[cfg(feature = "custom-aes128gcm")]
+    pub fn encrypt_with_progress<F>(
+        &self,
+        content: &'a [u8],
+        mut on_progress: F,
+    ) -> Result<WebPushPayload, WebPushError>
+    where
+        F: FnMut(usize, usize),
+    {
+        if self.encoding != ContentEncoding::Aes128Gcm || self.record_size.is_none() {
+            let payload = self.encrypt(content)?;
+            on_progress(1, 1);
+
+            return Ok(payload);
+        }
+
+        if content.len() > MAX_PAYLOAD_LEN {
+            return Err(WebPushError::PayloadTooLarge {
+                source: PayloadLimitSource::Local,
+            });
+        }
+
+        self.encrypt_with_ephemeral_key_and_salt(content, &EphemeralKey::generate()?, &random_salt()?, &mut on_progress)
+    }
+
+    /// Encrypts a payload with aes128gcm, using the given ephemeral key instead of generating a new one.
+    ///
+    /// This is a performance escape hatch for broadcasting the same content to many recipients, as it skips the
+    /// P-256 key generation per encryption. A fresh random salt is still used for every call.
+    ///
+    /// # Security
+    ///
+    /// RFC8291 expects a new ephemeral key for every message. Reusing one links all messages encrypted with it,
+    /// and leaking its private key exposes all of them. Prefer [`HttpEce::encrypt`] unless profiling shows the
+    /// key generation matters. The encryption bypasses the `ece` crate, see the `custom-aes128gcm` feature this
+    /// requires.
+    ///
+    /// Returns `Unspecified` for encodings other than aes128gcm.
+    #[cfg(feature = "custom-aes128gcm")]
+    pub fn encrypt_with_ephemeral_key(
+        &self,
+        content: &'a [u8],
+        ephemeral_key: &EphemeralKey,
+    ) -> Result<WebPushPayload, WebPushError> {
+        if content.len() > MAX_PAYLOAD_LEN {
+            return Err(WebPushError::PayloadTooLarge {
+                source: PayloadLimitSource::Local,
+            });
+        }
+
+        if self.encoding != ContentEncoding::Aes128Gcm {
+            return Err(WebPushError::Unspecified);
+        }
+
+        self.encrypt_with_ephemeral_key_and_salt(content, ephemeral_key, &random_salt()?, &mut |_, _| ())
+    }
+
+    /// Encrypts a payload with aes128gcm, using the given ephemeral key and salt. Requires the `test-util` feature.
+    ///
+    /// The output only depends on the arguments, which makes it possible to compare it against known values in
+    /// tests. Never use this outside of tests: reusing a salt with the same key breaks the encryption.
+    ///
+    /// Returns `Unspecified` for encodings other than aes128gcm.
+    #[cfg(all(feature = "custom-aes128gcm", any(test, feature = "test-util")))]
+    pub fn encrypt_deterministic(
+        &self,
+        content: &'a [u8],
+        ephemeral_key: &EphemeralKey,
+        salt: &[u8; 16],
+    ) -> Result<WebPushPayload, WebPushError> {
+        if content.len() > MAX_PAYLOAD_LEN {
+            return Err(WebPushError::PayloadTooLarge {
+                source: PayloadLimitSource::Local,
+            });
+        }
+
+        if self.encoding != ContentEncoding::Aes128Gcm {
+            return Err(WebPushError::Unspecified);
+        }
+
+        self.encrypt_with_ephemeral_key_and_salt(content, ephemeral_key, salt, &mut |_, _| ())
+    }
+
+    #[cfg(feature = "custom-aes128gcm")]
+    fn encrypt_with_ephemeral_key_and_salt(
+        &self,
+        content: &[u8],
+        ephemeral_key: &EphemeralKey,
+        salt: &[u8; 16],
+        on_progress: &mut dyn FnMut(usize, usize),
+    ) -> Result<WebPushPayload, WebPushError> {
+        let record_size = self.record_size.unwrap_or(AES128GCM_RECORD_SIZE);
+        let data = aes128gcm_encrypt(
+            ephemeral_key,
+            self.peer_public_key,
+            self.peer_secret,
+            salt,
+            record_size,
+            &aes128gcm_records(content, record_size, AES128GCM_PADDING_BLOCK_SIZE),
+            on_progress,
+        )?;
+
+        let mut headers = Vec::new();
+
+        self.add_vapid_headers(&mut headers)?;
+
+        Ok(WebPushPayload {
+            content: data,
+            crypto_headers: headers,
+            content_encoding: self.encoding,
+        })
+    }
+
+    /// Adds VAPID authorisation header to headers, if VAPID is being used. Returns `InvalidVapidPublicKey` if the
+    /// public key of the signature is not 65 bytes, as push services reject the token anyway.
+    fn add_vapid_headers(&self, headers: &mut Vec<(&str, String)>) -> Result<(), WebPushError> {
         if let Some(signature) = &self.vapid_signature {
-            headers.push((
-                "Authorization",
-                format!(
-                    "vapid t={}, k={}",
-                    signature.auth_t,
-                    base64::encode_config(&signature.auth_k, base64::URL_SAFE_NO_PAD)
-                ),
-            ));
+            if signature.auth_k.len() != VAPID_PUBLIC_KEY_LEN {
+                return Err(WebPushError::InvalidVapidPublicKey(signature.auth_k.len()));
+            }
+
+            add_vapid_headers(signature, self.vapid_scheme, headers);
         }
+
+        Ok(())
     }
 
     /// Encrypts the content using the aesgcm encoding.
@@ -125,16 +361,76 @@ impl<'a> HttpEce<'a> {
     }
 }
 
+/// Adds the VAPID headers of `signature` in the format of `vapid_scheme`.
+pub(crate) fn add_vapid_headers(
+    signature: &VapidSignature,
+    vapid_scheme: VapidScheme,
+    headers: &mut Vec<(&str, String)>,
+) {
+    //VAPID uses a special Authorisation header, which contains a ecdhsa key and a jwt.
+    match vapid_scheme {
+        VapidScheme::Vapid => {
+            headers.push(("Authorization", signature.authorization_header().1));
+        }
+        VapidScheme::WebPush => {
+            // aesgcm already carries the key in its Crypto-Key header.
+            if !headers.iter().any(|(name, _)| *name == "Crypto-Key") {
+                let public_key = base64::encode_config(&signature.auth_k, base64::URL_SAFE_NO_PAD);
+                headers.push(("Crypto-Key", format!("p256ecdsa={}", public_key)));
+            }
+
+            headers.push(("Authorization", format!("WebPush {}", signature.auth_t)));
+        }
+    }
+}
+
+/// Converts a compressed 33 byte P-256 public key, as sent by some non-browser clients, into the uncompressed form
+/// the encryption needs. Other keys are returned as they are. Returns `InvalidCryptoKeys` if a compressed key is not
+/// a point on the curve.
+pub(crate) fn uncompressed_public_key(key: Vec<u8>) -> Result<Vec<u8>, WebPushError> {
+    if key.len() != 33 || !matches!(key[0], 0x02 | 0x03) {
+        return Ok(key);
+    }
+
+    ES256PublicKey::from_bytes(&key)
+        .map(|key| key.public_key().to_bytes_uncompressed())
+        .map_err(|_| WebPushError::InvalidCryptoKeys)
+}
+
 #[cfg(test)]
 mod tests {
     use base64::{self, URL_SAFE};
     use regex::Regex;
 
-    use crate::error::WebPushError;
-    use crate::http_ece::{ContentEncoding, HttpEce};
+    use crate::error::{PayloadLimitSource, WebPushError};
+    use crate::http_ece::{uncompressed_public_key, ContentEncoding, HttpEce, VapidScheme};
     use crate::VapidSignature;
     use crate::WebPushPayload;
 
+    #[test]
+    fn test_uncompressed_public_key() {
+        let uncompressed = base64::decode_config(
+            "BLMbF9ffKBiWQLCKvTHb6LO8Nb6dcUh6TItC455vu2kElga6PQvUmaFyCdykxY2nOSSL3yKgfbmFLRTUaGv4yV8",
+            base64::URL_SAFE,
+        )
+        .unwrap();
+        let compressed =
+            base64::decode_config("A7MbF9ffKBiWQLCKvTHb6LO8Nb6dcUh6TItC455vu2kE", base64::URL_SAFE).unwrap();
+
+        assert_eq!(uncompressed, uncompressed_public_key(compressed).unwrap());
+        assert_eq!(uncompressed, uncompressed_public_key(uncompressed.clone()).unwrap());
+        assert!(matches!(
+            uncompressed_public_key([&[0x02][..], &[0xff; 32]].concat()),
+            Err(WebPushError::InvalidCryptoKeys)
+        ));
+    }
+
+    #[test]
+    fn test_content_encoding_displays_header_value() {
+        assert_eq!("aes128gcm", ContentEncoding::Aes128Gcm.to_string());
+        assert_eq!("aesgcm", ContentEncoding::AesGcm.to_string());
+    }
+
     #[test]
     fn test_payload_too_big() {
         let p256dh = base64::decode_config(
@@ -147,7 +443,47 @@ mod tests {
         //This content is one above limit.
         let content = [0u8; 3801];
 
-        assert!(matches!(http_ece.encrypt(&content), Err(WebPushError::PayloadTooLarge)));
+        assert!(matches!(
+            http_ece.encrypt(&content),
+            Err(WebPushError::PayloadTooLarge {
+                source: PayloadLimitSource::Local,
+            })
+        ));
+    }
+
+    #[test]
+    fn test_classifies_encryption_errors() {
+        let auth = base64::decode_config("xS03Fj5ErfTNH_l9WHE9Ig", URL_SAFE).unwrap();
+
+        for encoding in [ContentEncoding::Aes128Gcm, ContentEncoding::AesGcm] {
+            // Not a point on the curve.
+            let p256dh = [4u8; 65];
+            assert!(matches!(
+                HttpEce::new(encoding, &p256dh, &auth, None).encrypt(b"test"),
+                Err(WebPushError::InvalidCryptoKeys)
+            ));
+
+            let p256dh = base64::decode_config(
+                "BLMbF9ffKBiWQLCKvTHb6LO8Nb6dcUh6TItC455vu2kElga6PQvUmaFyCdykxY2nOSSL3yKgfbmFLRTUaGv4yV8",
+                URL_SAFE,
+            )
+            .unwrap();
+            assert!(matches!(
+                HttpEce::new(encoding, &p256dh, &auth[..8], None).encrypt(b"test"),
+                Err(WebPushError::InvalidCryptoKeys)
+            ));
+        }
+
+        assert!(matches!(
+            WebPushError::from(ece::Error::PlaintextTooLong),
+            WebPushError::PayloadTooLarge {
+                source: PayloadLimitSource::Local
+            }
+        ));
+        assert!(matches!(
+            WebPushError::from(ece::Error::CryptoError),
+            WebPushError::Unspecified
+        ));
     }
 
     /// Tests that the content encryption is properly reversible while using aes128gcm.
@@ -216,12 +552,46 @@ mod tests {
         assert_eq!(wp_payload.crypto_headers.len(), 2);
     }
 
+    /// A VAPID public key of the right length, the signatures in these tests are not checked.
+    fn vapid_public_key() -> Vec<u8> {
+        let mut key = vec![0x04];
+        key.extend_from_slice(&[0x2a; 64]);
+        key
+    }
+
+    fn vapid_public_key_base64() -> String {
+        base64::encode_config(vapid_public_key(), base64::URL_SAFE_NO_PAD)
+    }
+
+    #[test]
+    fn test_rejects_vapid_public_key_of_wrong_length() {
+        let vapid_signature = VapidSignature {
+            auth_t: String::from("foo"),
+            auth_k: String::from("bar").into_bytes(),
+        };
+        let p256dh = base64::decode_config(
+            "BLMbF9ffKBiWQLCKvTHb6LO8Nb6dcUh6TItC455vu2kElga6PQvUmaFyCdykxY2nOSSL3yKgfbmFLRTUaGv4yV8",
+            URL_SAFE,
+        )
+        .unwrap();
+        let auth = base64::decode_config("xS03Fi5ErfTNH_l9WHE9Ig", URL_SAFE).unwrap();
+
+        for encoding in [ContentEncoding::Aes128Gcm, ContentEncoding::AesGcm] {
+            let http_ece = HttpEce::new(encoding, &p256dh, &auth, Some(vapid_signature.clone()));
+
+            assert!(matches!(
+                http_ece.encrypt(b"test"),
+                Err(WebPushError::InvalidVapidPublicKey(3))
+            ));
+        }
+    }
+
     #[test]
     fn test_aes128gcm_headers_vapid() {
         let auth_re = Regex::new(r"vapid t=(?P<sig_t>[^,]*), k=(?P<sig_k>[^,]*)").unwrap();
         let vapid_signature = VapidSignature {
             auth_t: String::from("foo"),
-            auth_k: String::from("bar").into_bytes(),
+            auth_k: vapid_public_key(),
         };
         let wp_payload = setup_payload(Some(vapid_signature), ContentEncoding::Aes128Gcm);
         assert_eq!(wp_payload.crypto_headers.len(), 1);
@@ -235,7 +605,7 @@ mod tests {
         let auth_re = Regex::new(r"vapid t=(?P<sig_t>[^,]*), k=(?P<sig_k>[^,]*)").unwrap();
         let vapid_signature = VapidSignature {
             auth_t: String::from("foo"),
-            auth_k: String::from("bar").into_bytes(),
+            auth_k: vapid_public_key(),
         };
         let wp_payload = setup_payload(Some(vapid_signature), ContentEncoding::AesGcm);
         // Should have Authorization, Crypto-key, and Encryption
@@ -244,4 +614,122 @@ mod tests {
         assert_eq!(auth.0, "Authorization");
         assert!(auth_re.captures(&auth.1).is_some());
     }
+
+    #[test]
+    fn test_aesgcm_legacy_encryption_key() {
+        let p256dh = base64::decode_config(
+            "BLMbF9ffKBiWQLCKvTHb6LO8Nb6dcUh6TItC455vu2kElga6PQvUmaFyCdykxY2nOSSL3yKgfbmFLRTUaGv4yV8",
+            URL_SAFE,
+        )
+        .unwrap();
+        let auth = base64::decode_config("xS03Fi5ErfTNH_l9WHE9Ig", URL_SAFE).unwrap();
+        let vapid_signature = VapidSignature {
+            auth_t: String::from("foo"),
+            auth_k: vapid_public_key(),
+        };
+
+        let header = |payload: &WebPushPayload, name: &str| {
+            payload
+                .crypto_headers
+                .iter()
+                .find(|(header, _)| *header == name)
+                .map(|(_, value)| value.clone())
+        };
+
+        for encoding in [ContentEncoding::AesGcm, ContentEncoding::Aes128Gcm] {
+            let mut http_ece = HttpEce::new(encoding, &p256dh, &auth, Some(vapid_signature.clone()));
+            assert_eq!(None, header(&http_ece.encrypt(b"test").unwrap(), "Encryption-Key"));
+
+            http_ece.set_legacy_encryption_key(true);
+            let payload = http_ece.encrypt(b"test").unwrap();
+
+            match encoding {
+                ContentEncoding::AesGcm => {
+                    let encryption_key = header(&payload, "Encryption-Key").unwrap();
+
+                    assert!(encryption_key.starts_with("dh="));
+                    assert!(header(&payload, "Crypto-Key").unwrap().starts_with(&encryption_key));
+                }
+                _ => assert_eq!(None, header(&payload, "Encryption-Key")),
+            }
+        }
+    }
+
+    #[test]
+    fn test_vapid_scheme_for_encoding() {
+        assert_eq!(
+            VapidScheme::Vapid,
+            VapidScheme::for_encoding(ContentEncoding::Aes128Gcm)
+        );
+        assert_eq!(VapidScheme::WebPush, VapidScheme::for_encoding(ContentEncoding::AesGcm));
+    }
+
+    #[test]
+    fn test_vapid_headers_encode_key_once() {
+        let public_key = base64::decode_config(
+            "BLMbF9ffKBiWQLCKvTHb6LO8Nb6dcUh6TItC455vu2kElga6PQvUmaFyCdykxY2nOSSL3yKgfbmFLRTUaGv4yV8",
+            URL_SAFE,
+        )
+        .unwrap();
+        let vapid_signature = VapidSignature {
+            auth_t: String::from("eyJ0eXAiOiJKV1QiLCJhbGciOiJFUzI1NiJ9.eyJhdWQiOiJodHRwczovL2V4YW1wbGUuY29tIn0.c2ln"),
+            auth_k: public_key.clone(),
+        };
+
+        let wp_payload = setup_payload(Some(vapid_signature.clone()), ContentEncoding::Aes128Gcm);
+        let (t, k) = wp_payload.crypto_headers[0]
+            .1
+            .strip_prefix("vapid t=")
+            .and_then(|value| value.split_once(", k="))
+            .unwrap();
+
+        assert_eq!(vapid_signature.auth_t, t);
+        assert!(!k.contains('='));
+        assert_eq!(public_key, base64::decode_config(k, base64::URL_SAFE_NO_PAD).unwrap());
+    }
+
+    fn setup_payload_with_scheme(encoding: ContentEncoding, vapid_scheme: VapidScheme) -> WebPushPayload {
+        let p256dh = base64::decode_config(
+            "BLMbF9ffKBiWQLCKvTHb6LO8Nb6dcUh6TItC455vu2kElga6PQvUmaFyCdykxY2nOSSL3yKgfbmFLRTUaGv4yV8",
+            URL_SAFE,
+        )
+        .unwrap();
+        let auth = base64::decode_config("xS03Fi5ErfTNH_l9WHE9Ig", URL_SAFE).unwrap();
+        let vapid_signature = VapidSignature {
+            auth_t: String::from("foo"),
+            auth_k: vapid_public_key(),
+        };
+
+        let mut http_ece = HttpEce::new(encoding, &p256dh, &auth, Some(vapid_signature));
+        http_ece.set_vapid_scheme(vapid_scheme);
+
+        http_ece.encrypt("Hello, world!".as_bytes()).unwrap()
+    }
+
+    #[test]
+    fn test_aesgcm_headers_webpush_scheme() {
+        let wp_payload = setup_payload_with_scheme(ContentEncoding::AesGcm, VapidScheme::WebPush);
+
+        assert_eq!(3, wp_payload.crypto_headers.len());
+        assert!(wp_payload.crypto_headers[0]
+            .1
+            .ends_with(&format!("p256ecdsa={}", vapid_public_key_base64())));
+        assert_eq!(
+            ("Authorization", String::from("WebPush foo")),
+            wp_payload.crypto_headers[2]
+        );
+    }
+
+    #[test]
+    fn test_aes128gcm_headers_webpush_scheme() {
+        let wp_payload = setup_payload_with_scheme(ContentEncoding::Aes128Gcm, VapidScheme::WebPush);
+
+        assert_eq!(
+            vec![
+                ("Crypto-Key", format!("p256ecdsa={}", vapid_public_key_base64())),
+                ("Authorization", String::from("WebPush foo")),
+            ],
+            wp_payload.crypto_headers
+        );
+    }
 }
@@ -3,7 +3,7 @@
 //! A library for creating and sending push notifications to a web browser. For
 //! content payload encryption it uses [RFC8188](https://datatracker.ietf.org/doc/html/rfc8188).
 //! The client is asynchronous and can run on any executor. An optional [`hyper`](https://crates.io/crates/hyper) based client is
-//! available with the feature `hyper-client`.
+//! available with the feature `hyper-client`, and can use HTTP/2 with the feature `hyper-http2`.
 //!
 //! # Example
 //!
@@ -47,25 +47,45 @@ extern crate log;
 #[macro_use]
 extern crate serde_derive;
 
+pub use crate::clients::rate_limit::HostRateLimiter;
 pub use crate::clients::request_builder;
-pub use crate::clients::WebPushClient;
+pub use crate::clients::retry::RetryPolicy;
+pub use crate::clients::{SendOutcome, SendReport, SendStats, WebPushClient};
 
 #[cfg(feature = "hyper-client")]
 pub use crate::clients::hyper_client::HyperWebPushClient;
 #[cfg(feature = "isahc-client")]
 pub use crate::clients::isahc_client::IsahcWebPushClient;
+#[cfg(feature = "hyper-client")]
+pub use native_tls::Protocol as TlsVersion;
 
-pub use crate::error::WebPushError;
-pub use crate::http_ece::ContentEncoding;
+#[cfg(feature = "custom-aes128gcm")]
+pub use crate::aes128gcm::EphemeralKey;
+pub use crate::error::{PayloadLimitSource, WebPushError};
+pub use crate::http_ece::{ContentEncoding, HttpEce, VapidScheme};
+#[cfg(feature = "compression")]
+pub use crate::message::Compression;
 pub use crate::message::{
-    SubscriptionInfo, SubscriptionKeys, Urgency, WebPushMessage, WebPushMessageBuilder, WebPushPayload,
+    validate_subscriptions, Aes128GcmHeader, DeferredVapid, SubscriptionChange, SubscriptionInfo, SubscriptionKeys,
+    Urgency, WebPushMessage, WebPushMessageBuilder, WebPushPayload,
 };
+pub use crate::notification::{split_payload, Notification, NotificationAction, MAX_NOTIFICATION_ACTIONS};
 pub use crate::vapid::builder::PartialVapidSignatureBuilder;
-pub use crate::vapid::{VapidSignature, VapidSignatureBuilder};
+pub use crate::vapid::{
+    application_server_key_to_raw, public_key_to_application_server_key, PreparedVapidSigner, VapidSignature,
+    VapidSignatureBuilder, VapidSignatureCache,
+};
 pub use base64::{Config, BCRYPT, BINHEX, CRYPT, IMAP_MUTF7, STANDARD, STANDARD_NO_PAD, URL_SAFE, URL_SAFE_NO_PAD};
+#[cfg(feature = "cancellation")]
+pub use tokio_util::sync::CancellationToken;
 
+#[cfg(feature = "custom-aes128gcm")]
+mod aes128gcm;
 mod clients;
 mod error;
 mod http_ece;
 mod message;
+mod notification;
+#[cfg(feature = "test-util")]
+pub mod test_util;
 mod vapid;
@@ -1,9 +1,18 @@
 use http::uri::Uri;
+use http::Method;
+use sha2::{Digest, Sha256};
+use std::borrow::Cow;
 use std::fmt::{Display, Formatter};
+use std::time::{Duration, Instant};
 
+#[cfg(feature = "compression")]
+use crate::error::PayloadLimitSource;
 use crate::error::WebPushError;
-use crate::http_ece::{ContentEncoding, HttpEce};
-use crate::vapid::VapidSignature;
+use crate::http_ece::{
+    add_vapid_headers, uncompressed_public_key, ContentEncoding, HttpEce, VapidScheme, MAX_PAYLOAD_LEN,
+};
+use crate::notification::split_payload;
+use crate::vapid::{PreparedVapidSigner, VapidSignature};
 
 /// Encryption keys from the client.
 #[derive(Debug, Deserialize, Serialize, Clone, Eq, PartialEq, Ord, PartialOrd, Default, Hash)]
@@ -14,11 +23,51 @@ pub struct SubscriptionKeys {
     pub auth: String,
 }
 
+impl SubscriptionKeys {
+    /// Creates the keys from the raw public key and authentication secret bytes, for clients that don't receive them
+    /// base64 encoded.
+    pub fn from_bytes(p256dh: &[u8], auth: &[u8]) -> SubscriptionKeys {
+        SubscriptionKeys {
+            p256dh: base64::encode_config(p256dh, base64::URL_SAFE_NO_PAD),
+            auth: base64::encode_config(auth, base64::URL_SAFE_NO_PAD),
+        }
+    }
+
+    /// Undoes a second round of base64 encoding on the keys, a frequent mistake when storing subscriptions, which
+    /// makes sending fail with `DoubleEncodedKey`. Returns whether a key was changed.
+    ///
+    /// A key is only changed if it decodes to base64 text that decodes to a key of the right length.
+    pub fn normalize_double_encoding(&mut self) -> bool {
+        let mut changed = false;
+
+        for (key, lens) in [
+            (&mut self.p256dh, &P256DH_LENS[..]),
+            (&mut self.auth, &[AUTH_SECRET_LEN][..]),
+        ] {
+            let inner = base64::decode_config(&*key, base64::URL_SAFE)
+                .ok()
+                .and_then(|decoded| String::from_utf8(decoded).ok());
+
+            if let Some(inner) = inner {
+                if decode_base64_text(inner.as_bytes()).is_some_and(|bytes| lens.contains(&bytes.len())) {
+                    *key = inner;
+                    changed = true;
+                }
+            }
+        }
+
+        changed
+    }
+}
+
 /// Client info for sending the notification. Maps the values from browser's
 /// subscription info JSON data (AKA pushSubscription object).
 ///
-/// Client pushSubscription objects can be directly deserialized into this struct using serde.
+/// Client pushSubscription objects can be directly deserialized into this struct using serde. Deserializing also
+/// accepts the endpoint under `endpoint_url` or `endpointUrl`, and the keys at the top level instead of nested under
+/// `keys`.
 #[derive(Debug, Deserialize, Serialize, Clone, Eq, PartialEq, Ord, PartialOrd, Default, Hash)]
+#[serde(try_from = "RawSubscriptionInfo")]
 pub struct SubscriptionInfo {
     /// The endpoint URI for sending the notification.
     pub endpoint: String,
@@ -41,10 +90,240 @@ impl SubscriptionInfo {
             },
         }
     }
+
+    /// Creates a `SubscriptionInfo` like [`SubscriptionInfo::new`], but checks the values first with
+    /// [`SubscriptionInfo::validate`].
+    pub fn try_new<S>(endpoint: S, p256dh: S, auth: S) -> Result<SubscriptionInfo, WebPushError>
+    where
+        S: Into<String>,
+    {
+        let info = SubscriptionInfo::new(endpoint, p256dh, auth);
+        info.validate()?;
+
+        Ok(info)
+    }
+
+    /// Checks that a message can be built for this subscription, without encrypting anything.
+    ///
+    /// Returns `InvalidUri` if the endpoint is not an absolute URI, and `InvalidCryptoKeys` if `p256dh` is not a
+    /// base64url encoded P-256 public key, or `auth` not a base64url encoded 16 byte secret. Compressed public keys
+    /// are accepted, they are decompressed before encrypting.
+    pub fn validate(&self) -> Result<(), WebPushError> {
+        self.validate_endpoint()?;
+        self.validate_p256dh()?;
+        self.validate_auth()
+    }
+
+    /// Checks the subscription like [`SubscriptionInfo::validate`], but reports every problem instead of only the
+    /// first, for listing everything wrong with a stored subscription.
+    ///
+    /// Returns `InvalidSubscription` with one reason per invalid field, such as `endpoint: invalid uri provided`.
+    /// Sending still returns the granular errors.
+    pub fn validate_all(&self) -> Result<(), WebPushError> {
+        let checks = [
+            ("endpoint", &self.endpoint, self.validate_endpoint()),
+            ("p256dh", &self.keys.p256dh, self.validate_p256dh()),
+            ("auth", &self.keys.auth, self.validate_auth()),
+        ];
+
+        let reasons: Vec<String> = checks
+            .into_iter()
+            .filter_map(|(field, value, result)| match result {
+                Ok(()) => None,
+                Err(_) if value.is_empty() => Some(format!("{}: missing", field)),
+                Err(err) => Some(format!("{}: {}", field, err)),
+            })
+            .collect();
+
+        if reasons.is_empty() {
+            Ok(())
+        } else {
+            Err(WebPushError::InvalidSubscription { reasons })
+        }
+    }
+
+    fn validate_endpoint(&self) -> Result<(), WebPushError> {
+        let endpoint: Uri = self.endpoint.parse()?;
+        if endpoint.scheme().is_none() || endpoint.host().is_none() {
+            return Err(WebPushError::InvalidUri);
+        }
+
+        Ok(())
+    }
+
+    fn validate_p256dh(&self) -> Result<(), WebPushError> {
+        let p256dh = uncompressed_public_key(decode_key(&self.keys.p256dh, &P256DH_LENS)?)?;
+        if p256dh.len() != 65 || p256dh[0] != 0x04 {
+            return Err(WebPushError::InvalidCryptoKeys);
+        }
+
+        Ok(())
+    }
+
+    fn validate_auth(&self) -> Result<(), WebPushError> {
+        if decode_key(&self.keys.auth, &[AUTH_SECRET_LEN])?.len() != AUTH_SECRET_LEN {
+            return Err(WebPushError::InvalidCryptoKeys);
+        }
+
+        Ok(())
+    }
+
+    /// Creates a `SubscriptionInfo` from the raw public key and authentication secret bytes.
+    /// See [`SubscriptionKeys::from_bytes`].
+    pub fn from_bytes<S>(endpoint: S, p256dh: &[u8], auth: &[u8]) -> SubscriptionInfo
+    where
+        S: Into<String>,
+    {
+        SubscriptionInfo {
+            endpoint: endpoint.into(),
+            keys: SubscriptionKeys::from_bytes(p256dh, auth),
+        }
+    }
+
+    /// Parses subscription info from JSON, accepting the same shapes as deserializing with serde.
+    ///
+    /// Returns `InvalidUri` if the endpoint is missing, `MissingCryptoKeys` if the keys are missing, and
+    /// `InvalidSubscription` with the parse error if `json` is malformed or a field has the wrong type.
+    pub fn from_json_str(json: &str) -> Result<SubscriptionInfo, WebPushError> {
+        let raw: RawSubscriptionInfo = serde_json::from_str(json).map_err(invalid_json)?;
+
+        SubscriptionInfo::try_from(raw)
+    }
+
+    /// Parses a JSON array of subscriptions, as exported in bulk, each in a shape accepted by
+    /// [`SubscriptionInfo::from_json_str`].
+    ///
+    /// Returns `InvalidSubscription` with the parse error if `json` is not an array. A malformed entry fails the whole
+    /// import with its error, unless `lenient` is set, which skips it and keeps the others.
+    pub fn vec_from_json(json: &str, lenient: bool) -> Result<Vec<SubscriptionInfo>, WebPushError> {
+        let entries: Vec<serde_json::Value> = serde_json::from_str(json).map_err(invalid_json)?;
+        let mut subscriptions = Vec::with_capacity(entries.len());
+
+        for entry in entries {
+            let parsed = serde_json::from_value::<RawSubscriptionInfo>(entry)
+                .map_err(invalid_json)
+                .and_then(SubscriptionInfo::try_from);
+
+            match parsed {
+                Ok(subscription) => subscriptions.push(subscription),
+                Err(_) if lenient => {}
+                Err(err) => return Err(err),
+            }
+        }
+
+        Ok(subscriptions)
+    }
+
+    /// The content encoding to use for this subscription, guessed from the push service host.
+    ///
+    /// This is a heuristic, the browser's supported encodings can't be detected from the subscription. Returns
+    /// `AesGcm` only for the legacy GCM endpoints on `android.googleapis.com`, which predate aes128gcm. All current
+    /// push services (FCM, Mozilla, Apple, Windows) and unknown hosts get the standard `Aes128Gcm`.
+    pub fn recommended_encoding(&self) -> ContentEncoding {
+        let host = self
+            .endpoint
+            .parse::<Uri>()
+            .ok()
+            .and_then(|endpoint| endpoint.host().map(str::to_ascii_lowercase));
+
+        match host.as_deref() {
+            Some("android.googleapis.com") => ContentEncoding::AesGcm,
+            _ => ContentEncoding::Aes128Gcm,
+        }
+    }
+}
+
+/// Checks all `subscriptions` with [`SubscriptionInfo::validate`], returning the results in the same order. Useful
+/// for pruning malformed subscriptions from storage without sending anything.
+pub fn validate_subscriptions(subscriptions: &[SubscriptionInfo]) -> Vec<Result<(), WebPushError>> {
+    subscriptions.iter().map(SubscriptionInfo::validate).collect()
+}
+
+/// The subscription info shapes sent by the various client libraries.
+#[derive(Deserialize)]
+struct RawSubscriptionInfo {
+    #[serde(alias = "endpoint_url", alias = "endpointUrl")]
+    endpoint: Option<String>,
+    keys: Option<SubscriptionKeys>,
+    p256dh: Option<String>,
+    auth: Option<String>,
+}
+
+/// The error for subscription JSON that doesn't parse, with the reason from serde.
+fn invalid_json(err: serde_json::Error) -> WebPushError {
+    WebPushError::InvalidSubscription {
+        reasons: vec![format!("json: {}", err)],
+    }
+}
+
+impl TryFrom<RawSubscriptionInfo> for SubscriptionInfo {
+    type Error = WebPushError;
+
+    fn try_from(raw: RawSubscriptionInfo) -> Result<SubscriptionInfo, WebPushError> {
+        let endpoint = raw.endpoint.ok_or(WebPushError::InvalidUri)?;
+        let keys = match (raw.keys, raw.p256dh, raw.auth) {
+            (Some(keys), _, _) => keys,
+            (None, Some(p256dh), Some(auth)) => SubscriptionKeys { p256dh, auth },
+            _ => return Err(WebPushError::MissingCryptoKeys),
+        };
+
+        Ok(SubscriptionInfo { endpoint, keys })
+    }
+}
+
+/// The old and new subscriptions of a browser `pushsubscriptionchange` event, as posted by a service worker.
+///
+/// Browsers fire the event when a subscription is refreshed or expires. Either side can be missing: `old` if the
+/// browser no longer knows the expired subscription, and `new` if the service worker couldn't resubscribe. Replace
+/// the stored `old` subscription with `new` when both are present.
+///
+/// ```
+/// # use web_push::SubscriptionChange;
+/// let change = SubscriptionChange::from_json_str(r#"{
+///     "oldSubscription": null,
+///     "newSubscription": {
+///         "endpoint": "https://fcm.googleapis.com/fcm/send/eKClHsXFm9E",
+///         "keys": {"p256dh": "BGa4N1PI79lboMR_YrwCiCsgp35DRvedt7opHcf0yM3iOBTSoQYqQLwWxAfRKE6tsDnReWmhsImkhDF_DBdkNSU", "auth": "EvcWjEgzr4rbvhfi3yds0A"}
+///     }
+/// }"#).unwrap();
+///
+/// assert!(change.old.is_none());
+/// ```
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct SubscriptionChange {
+    /// The subscription that is no longer valid.
+    pub old: Option<SubscriptionInfo>,
+    /// The subscription replacing it.
+    pub new: Option<SubscriptionInfo>,
+}
+
+impl SubscriptionChange {
+    /// Parses the change from JSON with the subscriptions under `oldSubscription` and `newSubscription`, as named in
+    /// the event. The subscriptions accept the same shapes as [`SubscriptionInfo::from_json_str`].
+    ///
+    /// Returns `InvalidSubscription` with the parse error for malformed JSON, and the errors of
+    /// [`SubscriptionInfo::from_json_str`] for a malformed subscription.
+    pub fn from_json_str(json: &str) -> Result<SubscriptionChange, WebPushError> {
+        let raw: RawSubscriptionChange = serde_json::from_str(json).map_err(invalid_json)?;
+
+        Ok(SubscriptionChange {
+            old: raw.old_subscription.map(SubscriptionInfo::try_from).transpose()?,
+            new: raw.new_subscription.map(SubscriptionInfo::try_from).transpose()?,
+        })
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct RawSubscriptionChange {
+    #[serde(alias = "old", alias = "old_subscription")]
+    old_subscription: Option<RawSubscriptionInfo>,
+    #[serde(alias = "new", alias = "new_subscription")]
+    new_subscription: Option<RawSubscriptionInfo>,
 }
 
 /// The push content payload, already in an encrypted form.
-#[derive(Debug, PartialEq)]
+#[derive(Debug, Clone, PartialEq)]
 pub struct WebPushPayload {
     /// Encrypted content data.
     pub content: Vec<u8>,
@@ -54,6 +333,73 @@ pub struct WebPushPayload {
     pub content_encoding: ContentEncoding,
 }
 
+impl WebPushPayload {
+    /// The length of the encrypted content in bytes, the size of the request body.
+    pub fn len(&self) -> usize {
+        self.content.len()
+    }
+
+    /// Whether the encrypted content is empty, which only happens with plaintext test payloads.
+    pub fn is_empty(&self) -> bool {
+        self.content.is_empty()
+    }
+
+    /// The uncompressed public key of the ephemeral key the payload was encrypted with, the `dh` the browser derives
+    /// the shared secret from. Useful for checking the encryption against a reference decryptor.
+    ///
+    /// For aes128gcm it is the key id in the [header](WebPushPayload::aes128gcm_header) of the content, for aesgcm
+    /// the `dh` parameter of the `Crypto-Key` header. Returns `None` if it is missing or can't be decoded.
+    pub fn sender_public_key(&self) -> Option<Vec<u8>> {
+        match self.content_encoding {
+            ContentEncoding::Aes128Gcm => self.aes128gcm_header().map(|header| header.key_id),
+            ContentEncoding::AesGcm => {
+                let dh = self
+                    .crypto_headers
+                    .iter()
+                    .filter(|(name, _)| name.eq_ignore_ascii_case("Crypto-Key"))
+                    .flat_map(|(_, value)| value.split([';', ',']))
+                    .find_map(|param| param.trim().strip_prefix("dh="))?;
+
+                base64::decode_config(dh.trim_end_matches('='), base64::URL_SAFE_NO_PAD).ok()
+            }
+            #[cfg(feature = "test-util")]
+            ContentEncoding::Identity => None,
+        }
+    }
+
+    /// Parses the header the aes128gcm encoding prepends to the content, as defined in RFC 8188 section 2.1. Useful
+    /// for comparing the framing against another implementation.
+    ///
+    /// Returns `None` for the other encodings, or if the content is too short to hold the header.
+    pub fn aes128gcm_header(&self) -> Option<Aes128GcmHeader> {
+        if self.content_encoding != ContentEncoding::Aes128Gcm {
+            return None;
+        }
+
+        let salt = self.content.get(..16)?.try_into().ok()?;
+        let record_size = u32::from_be_bytes(self.content.get(16..20)?.try_into().ok()?);
+        let key_id_len = *self.content.get(20)? as usize;
+        let key_id = self.content.get(21..21 + key_id_len)?.to_vec();
+
+        Some(Aes128GcmHeader {
+            salt,
+            record_size,
+            key_id,
+        })
+    }
+}
+
+/// The header of an aes128gcm encrypted payload.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Aes128GcmHeader {
+    /// The random salt for deriving the content encryption key.
+    pub salt: [u8; 16],
+    /// The record size, the maximum length of an encrypted record.
+    pub record_size: u32,
+    /// The key id, for web push the uncompressed public key of the sender's ephemeral key.
+    pub key_id: Vec<u8>,
+}
+
 #[derive(Debug, Deserialize, Serialize, Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Default, Hash)]
 #[serde(rename_all = "kebab-case")]
 pub enum Urgency {
@@ -78,34 +424,203 @@ impl Display for Urgency {
 }
 
 /// Everything needed to send a push notification to the user.
-#[derive(Debug)]
+///
+/// Cloning the message keeps the encrypted payload and idempotency key, so a clone can be used to retry a send.
+#[derive(Debug, Clone)]
 pub struct WebPushMessage {
     /// The endpoint URI where to send the payload.
     pub endpoint: Uri,
     /// Time to live, how long the message should wait in the server if user is
     /// not online. Some services require this value to be set.
     pub ttl: u32,
-    /// The urgency of the message (very-low | low | normal | high)
+    /// The urgency of the message (very-low | low | normal | high). The `Urgency` header is only sent if set.
     pub urgency: Option<Urgency>,
     /// The topic of the mssage
     pub topic: Option<String>,
     /// The encrypted request payload, if sending any data.
     pub payload: Option<WebPushPayload>,
+    /// Whether to send `Prefer: respond-async`, asking the push service to acknowledge without waiting for delivery.
+    pub prefer_async: bool,
+    /// The `Idempotency-Key` header, letting gateways that support it drop duplicates of a retried message.
+    pub idempotency_key: Option<String>,
+    /// The HTTP method of the request, `POST` unless overridden for a gateway. Only `POST` and `PUT` are valid.
+    pub method: Method,
+    /// The `Content-Encoding` header to send when there is no payload, for servers that expect it on every message.
+    /// Not sent by default.
+    pub empty_body_encoding: Option<ContentEncoding>,
+    /// The VAPID signer of a message signed at send time, see [`WebPushMessageBuilder::set_deferred_vapid_signer`].
+    /// `None` once signed.
+    pub deferred_vapid: Option<DeferredVapid>,
+    /// Headers added with [`WebPushMessageBuilder::add_header`], sent after all the others.
+    pub extra_headers: Vec<(String, String)>,
+    /// The instant after which the clients give up sending the message with `Timeout`, see
+    /// [`WebPushMessageBuilder::set_deadline`].
+    pub deadline: Option<Instant>,
+}
+
+/// A VAPID signer waiting for a message to be sent, see [`WebPushMessageBuilder::set_deferred_vapid_signer`].
+#[derive(Clone)]
+pub struct DeferredVapid {
+    signer: PreparedVapidSigner,
+    vapid_scheme: VapidScheme,
+}
+
+impl std::fmt::Debug for DeferredVapid {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("DeferredVapid")
+            .field("vapid_scheme", &self.vapid_scheme)
+            .finish_non_exhaustive()
+    }
+}
+
+impl WebPushMessage {
+    /// The endpoint URI where the message is sent.
+    pub fn endpoint(&self) -> &Uri {
+        &self.endpoint
+    }
+
+    /// How long the push service keeps the message if the user agent is offline, in seconds.
+    pub fn ttl(&self) -> u32 {
+        self.ttl
+    }
+
+    /// The urgency of the message, or `None` if no `Urgency` header is sent.
+    pub fn urgency(&self) -> Option<Urgency> {
+        self.urgency
+    }
+
+    /// The topic of the message, or `None` if no `Topic` header is sent.
+    pub fn topic(&self) -> Option<&str> {
+        self.topic.as_deref()
+    }
+
+    /// The encrypted payload, or `None` if the message has no payload.
+    pub fn payload_bytes(&self) -> Option<&[u8]> {
+        self.payload.as_ref().map(|payload| payload.content.as_slice())
+    }
+
+    /// The encoding of the encrypted payload, or `None` if the message has no payload.
+    pub fn content_encoding(&self) -> Option<ContentEncoding> {
+        self.payload.as_ref().map(|payload| payload.content_encoding)
+    }
+
+    /// Signs a message built with [`WebPushMessageBuilder::set_deferred_vapid_signer`] for its endpoint, adding the
+    /// VAPID headers to the payload. Does nothing if there is nothing to sign.
+    ///
+    /// The built-in clients call this when sending, custom clients should call it right before
+    /// [`build_request`](crate::request_builder::build_request).
+    pub fn sign_deferred_vapid(&mut self) -> Result<(), WebPushError> {
+        if let Some(deferred) = self.deferred_vapid.take() {
+            let signature = deferred.signer.finalize_for(self.endpoint.host().unwrap_or_default())?;
+
+            if let Some(payload) = &mut self.payload {
+                add_vapid_headers(&signature, deferred.vapid_scheme, &mut payload.crypto_headers);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// All headers the request for this message is sent with, in order, including the encryption and VAPID headers.
+    ///
+    /// Useful for comparing a rejected request against a working one, e.g. a `curl` command.
+    pub fn debug_headers(&self) -> Vec<(String, String)> {
+        crate::clients::request_builder::request_headers(self)
+    }
+
+    /// A stable text dump of the request for this message, for snapshot tests: the request line, the headers sorted
+    /// by lower case name, and the body length.
+    ///
+    /// The values of `Authorization`, `Crypto-Key` and `Encryption` change with every encryption or signature, so
+    /// only their lengths are shown, as is the encrypted body.
+    pub fn to_debug_string(&self) -> String {
+        let mut headers: Vec<(String, String)> = self
+            .debug_headers()
+            .into_iter()
+            .map(|(name, value)| {
+                let name = name.to_ascii_lowercase();
+
+                match name.as_str() {
+                    "authorization" | "crypto-key" | "encryption" => (name, format!("<{} bytes>", value.len())),
+                    _ => (name, value),
+                }
+            })
+            .collect();
+        headers.sort();
+
+        let mut dump = format!("{} {}\n", self.method, self.endpoint);
+
+        for (name, value) in headers {
+            dump.push_str(&format!("{}: {}\n", name, value));
+        }
+
+        dump.push_str(&format!(
+            "<body {} bytes>\n",
+            self.payload_bytes().map_or(0, <[u8]>::len)
+        ));
+
+        dump
+    }
+
+    /// A `curl` command sending the same request as the clients, for reproducing a failed send by hand.
+    ///
+    /// The encrypted body is binary, so the command reads it from `payload.bin` in the working directory. Write
+    /// [`WebPushMessage::payload_bytes`] to that file before running the command. The headers are sent as is, so a
+    /// VAPID signature must be used before it expires.
+    pub fn to_curl(&self) -> String {
+        let mut command = format!("curl -X {} {}", self.method, shell_quote(&self.endpoint.to_string()));
+
+        for (name, value) in self.debug_headers() {
+            command.push_str(&format!(" \\\n  -H {}", shell_quote(&format!("{}: {}", name, value))));
+        }
+
+        if self.payload.is_some() {
+            command.push_str(" \\\n  --data-binary @payload.bin");
+        }
+
+        command
+    }
+}
+
+/// Quotes `value` as a single shell word.
+fn shell_quote(value: &str) -> String {
+    format!("'{}'", value.replace('\'', r"'\''"))
 }
 
 struct WebPushPayloadBuilder<'a> {
-    pub content: &'a [u8],
+    pub content: Cow<'a, [u8]>,
     pub encoding: ContentEncoding,
 }
 
+/// Compression applied to the payload before encryption, see [`WebPushMessageBuilder::set_payload_compressed`].
+#[cfg(feature = "compression")]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum Compression {
+    /// A gzip stream, starting with the magic bytes `1f 8b`.
+    Gzip,
+}
+
 /// The main class for creating a notification payload.
 pub struct WebPushMessageBuilder<'a> {
-    subscription_info: &'a SubscriptionInfo,
+    subscription_info: Cow<'a, SubscriptionInfo>,
+    endpoint: Option<String>,
+    endpoint_query: Vec<(String, String)>,
     payload: Option<WebPushPayloadBuilder<'a>>,
     ttl: u32,
     urgency: Option<Urgency>,
     topic: Option<String>,
+    max_topic_len: usize,
+    prefer_async: bool,
+    idempotency_key: Option<String>,
+    method: Method,
+    extra_headers: Vec<(String, String)>,
+    empty_body_encoding: Option<ContentEncoding>,
+    deadline: Option<Instant>,
     vapid_signature: Option<VapidSignature>,
+    deferred_vapid_signer: Option<PreparedVapidSigner>,
+    vapid_scheme: VapidScheme,
+    legacy_encryption_key: bool,
 }
 
 impl<'a> WebPushMessageBuilder<'a> {
@@ -114,16 +629,51 @@ impl<'a> WebPushMessageBuilder<'a> {
     /// All parameters are from the subscription info given by browser when
     /// subscribing to push notifications.
     pub fn new(subscription_info: &'a SubscriptionInfo) -> WebPushMessageBuilder<'a> {
+        Self::from_cow(Cow::Borrowed(subscription_info))
+    }
+
+    /// Creates a builder like [`WebPushMessageBuilder::new`], taking ownership of the subscription info. The builder
+    /// doesn't borrow the subscription, so it can be stored or moved to another task more easily.
+    pub fn new_owned(subscription_info: SubscriptionInfo) -> WebPushMessageBuilder<'a> {
+        Self::from_cow(Cow::Owned(subscription_info))
+    }
+
+    fn from_cow(subscription_info: Cow<'a, SubscriptionInfo>) -> WebPushMessageBuilder<'a> {
         WebPushMessageBuilder {
             subscription_info,
+            endpoint: None,
+            endpoint_query: Vec::new(),
             ttl: 2_419_200,
             urgency: None,
             topic: None,
+            max_topic_len: MAX_TOPIC_LEN,
+            prefer_async: false,
+            idempotency_key: None,
+            method: Method::POST,
+            extra_headers: Vec::new(),
+            empty_body_encoding: None,
+            deadline: None,
             payload: None,
             vapid_signature: None,
+            deferred_vapid_signer: None,
+            vapid_scheme: VapidScheme::default(),
+            legacy_encryption_key: false,
         }
     }
 
+    /// Sends the message to `endpoint` instead of the endpoint of the subscription, for example to route it through
+    /// a relay. The payload is still encrypted for the subscription. The endpoint is parsed by
+    /// [`WebPushMessageBuilder::build`], which returns `InvalidUri` if it is not a valid URI.
+    pub fn set_endpoint(&mut self, endpoint: &str) {
+        self.endpoint = Some(endpoint.to_string());
+    }
+
+    /// Appends the query parameter `key=value` to the endpoint, for relays that route on query parameters. Both
+    /// are percent-encoded, and a query already in the endpoint is kept. Parameters are appended in the order added.
+    pub fn add_endpoint_query(&mut self, key: &str, value: &str) {
+        self.endpoint_query.push((key.to_string(), value.to_string()));
+    }
+
     /// How long the server should keep the message if it cannot be delivered
     /// currently. If not set, the message is deleted immediately on failed
     /// delivery.
@@ -131,15 +681,37 @@ impl<'a> WebPushMessageBuilder<'a> {
         self.ttl = ttl;
     }
 
+    /// Sets the TTL like [`WebPushMessageBuilder::set_ttl`], from a duration. Rounds down to whole seconds, and
+    /// saturates at `u32::MAX` seconds.
+    pub fn set_ttl_duration(&mut self, ttl: Duration) {
+        self.set_ttl(u32::try_from(ttl.as_secs()).unwrap_or(u32::MAX));
+    }
+
+    /// Gives up sending the message once `deadline` has passed, for notifications that are worthless after a given
+    /// instant. The clients return `Timeout` without sending if the deadline passed while the message was built or
+    /// queued, and abort a send still running at the deadline. A client timeout still applies if it is shorter.
+    pub fn set_deadline(&mut self, deadline: Instant) {
+        self.deadline = Some(deadline);
+    }
+
     /// Urgency indicates to the push service how important a message is to the
     /// user. This can be used by the push service to help conserve the battery
     /// life of a user's device by only waking up for important messages when
     /// battery is low.
     /// Possible values are 'very-low', 'low', 'normal' and 'high'.
+    ///
+    /// If not set, no `Urgency` header is sent and the push service assumes 'normal'. Setting
+    /// `Urgency::Normal` explicitly always sends the header.
     pub fn set_urgency(&mut self, urgency: Urgency) {
         self.urgency = Some(urgency);
     }
 
+    /// Sets the urgency to `requested`, lowered to `max` if it is more urgent. Urgencies are ordered
+    /// `VeryLow < Low < Normal < High`.
+    pub fn set_urgency_capped(&mut self, requested: Urgency, max: Urgency) {
+        self.set_urgency(requested.min(max));
+    }
+
     /// Assign a topic to the push message. A message that has been stored
     /// by the push service can be replaced with new content if the message
     /// has been assigned a topic. If the user agent is offline during the
@@ -152,10 +724,118 @@ impl<'a> WebPushMessageBuilder<'a> {
         self.topic = Some(topic);
     }
 
+    /// Sets a topic derived from `key`, so messages with the same logical key replace each other. The topic is the
+    /// first 24 bytes of the SHA-256 hash of `key` in base64url, always a valid 32 character topic.
+    pub fn set_topic_from_hash(&mut self, key: &str) {
+        let hash = Sha256::digest(key.as_bytes());
+
+        self.set_topic(base64::encode_config(&hash[..24], base64::URL_SAFE_NO_PAD));
+    }
+
+    /// Sets the maximum topic length checked by [`WebPushMessageBuilder::build`]. Defaults to 32, the limit of
+    /// RFC8030. Only raise it for push services known to accept longer topics. The topic must still consist of
+    /// base64url characters.
+    ///
+    /// [`request_builder::validate_request`](crate::request_builder::validate_request) keeps checking the standard
+    /// limit.
+    pub fn set_max_topic_len(&mut self, max_topic_len: usize) {
+        self.max_topic_len = max_topic_len;
+    }
+
+    /// Asks the push service to acknowledge the message without waiting for delivery, with the
+    /// `Prefer: respond-async` header. Reduces latency for senders that don't need delivery confirmation, on
+    /// push services that support it. Off by default.
+    pub fn set_prefer_async(&mut self, prefer_async: bool) {
+        self.prefer_async = prefer_async;
+    }
+
+    /// Sends `key` in the `Idempotency-Key` header, so a gateway can drop duplicates when the message is retried.
+    /// Retry with a clone of the built message, or set the same key again, to keep the key across attempts.
+    ///
+    /// The browser push services (FCM, Mozilla autopush, Apple, WNS) don't document support for this header, it is
+    /// only useful with relays or gateways in front of them that deduplicate on it.
+    ///
+    /// Returns `InvalidHeader` if the key isn't printable ASCII.
+    pub fn set_idempotency_key(&mut self, key: &str) -> Result<(), WebPushError> {
+        validate_header_value(key)?;
+
+        self.idempotency_key = Some(key.to_string());
+        Ok(())
+    }
+
+    /// Sends `Content-Encoding: encoding` with an empty `Content-Length: 0` body if the message has no payload, for
+    /// strict servers that expect the header on every message. By default, bodiless messages have no encoding header.
+    pub fn set_empty_body_encoding(&mut self, encoding: ContentEncoding) {
+        self.empty_body_encoding = Some(encoding);
+    }
+
+    /// Sends the message with the HTTP `method` instead of `POST`, for self-hosted relays that use `PUT` to replace
+    /// messages. Push services only accept `POST`. Returns `InvalidMethod` for methods other than `POST` and `PUT`.
+    pub fn set_method(&mut self, method: Method) -> Result<(), WebPushError> {
+        validate_method(&method)?;
+
+        self.method = method;
+        Ok(())
+    }
+
+    /// Sends the extra header `name: value` after all the others, e.g. an `X-Forwarded-For` header for a relay to
+    /// know the original requester. The header is sent as is, so only add trusted values.
+    ///
+    /// Returns `InvalidHeader` if the name isn't a valid header name, the value isn't printable ASCII, the header is
+    /// longer than 1024 bytes, or it is a header the crate sets itself, such as `TTL` or `Authorization`.
+    pub fn add_header(&mut self, name: &str, value: &str) -> Result<(), WebPushError> {
+        validate_header(name, value)?;
+
+        self.extra_headers.push((name.to_string(), value.to_string()));
+        Ok(())
+    }
+
     /// Add a VAPID signature to the request. To be generated with the
     /// [VapidSignatureBuilder](struct.VapidSignatureBuilder.html).
     pub fn set_vapid_signature(&mut self, vapid_signature: VapidSignature) {
         self.vapid_signature = Some(vapid_signature);
+        self.deferred_vapid_signer = None;
+    }
+
+    /// Signs the message with `signer` when the client sends it instead of now, so `iat` and `exp` are counted from
+    /// the send. Useful for messages that wait in a queue for a while, which could otherwise be sent with an expired
+    /// signature. Replaces a signature set with [`WebPushMessageBuilder::set_vapid_signature`].
+    ///
+    /// Prepare the signer once and clone it for every message:
+    ///
+    /// ```no_run
+    /// # use web_push::{SubscriptionInfo, VapidSignatureBuilder, WebPushMessageBuilder};
+    /// # let subscriptions: Vec<SubscriptionInfo> = Vec::new();
+    /// let signer = VapidSignatureBuilder::from_pem_no_sub("Some PEM".as_bytes()).unwrap().prepare();
+    ///
+    /// for info in &subscriptions {
+    ///     let mut builder = WebPushMessageBuilder::new(info);
+    ///     builder.set_payload_auto(b"Queued for later");
+    ///     builder.set_deferred_vapid_signer(signer.clone());
+    /// }
+    /// ```
+    pub fn set_deferred_vapid_signer(&mut self, signer: PreparedVapidSigner) {
+        self.deferred_vapid_signer = Some(signer);
+        self.vapid_signature = None;
+    }
+
+    /// Removes the VAPID signature set with [`WebPushMessageBuilder::set_vapid_signature`], so the message is sent
+    /// without an `Authorization` header. Useful for local push servers that reject VAPID.
+    pub fn disable_vapid(&mut self) {
+        self.vapid_signature = None;
+        self.deferred_vapid_signer = None;
+    }
+
+    /// Sets the format of the VAPID `Authorization` header. Defaults to [`VapidScheme::Vapid`], use
+    /// [`VapidScheme::for_encoding`] for push services that expect the legacy format with aesgcm.
+    pub fn set_vapid_scheme(&mut self, vapid_scheme: VapidScheme) {
+        self.vapid_scheme = vapid_scheme;
+    }
+
+    /// Also sends the sender's public key in the legacy `Encryption-Key` header, for push implementations from
+    /// before 2016 that don't read `Crypto-Key`. Only applies to the aesgcm encoding. Off by default.
+    pub fn set_legacy_encryption_key(&mut self, legacy_encryption_key: bool) {
+        self.legacy_encryption_key = legacy_encryption_key;
     }
 
     /// If set, the client will get content in the notification. Has a maximum size of
@@ -163,50 +843,1332 @@ impl<'a> WebPushMessageBuilder<'a> {
     ///
     /// Aes128gcm is preferred, if the browser supports it.
     pub fn set_payload(&mut self, encoding: ContentEncoding, content: &'a [u8]) {
-        self.payload = Some(WebPushPayloadBuilder { content, encoding });
+        self.payload = Some(WebPushPayloadBuilder {
+            content: Cow::Borrowed(content),
+            encoding,
+        });
+    }
+
+    /// Sets the payload like [`WebPushMessageBuilder::set_payload`], encrypted with the
+    /// [recommended encoding](SubscriptionInfo::recommended_encoding) of the subscription.
+    pub fn set_payload_auto(&mut self, content: &'a [u8]) {
+        self.set_payload(self.subscription_info.recommended_encoding(), content);
+    }
+
+    /// Sets a payload that is sent unencrypted, with `Content-Encoding: identity`. Requires the `test-util` feature.
+    ///
+    /// **Never use this in production.** Browsers reject unencrypted payloads, and the payload is readable by anyone
+    /// on the way. It only exists for transport level tests against local mock push servers, where it saves setting
+    /// up real subscription keys: the keys of the subscription are not used.
+    #[cfg(feature = "test-util")]
+    pub fn set_payload_plaintext(&mut self, content: &'a [u8]) {
+        self.set_payload(ContentEncoding::Identity, content);
+    }
+
+    /// Sets the payload like [`WebPushMessageBuilder::set_payload`], compressing it first. Requires the
+    /// `compression` feature.
+    ///
+    /// Helps large JSON payloads fit the size limit. Returns `PayloadTooLarge` if the payload is still too large
+    /// after compression. The browser doesn't decompress the payload, so the service worker has to. A gzip payload
+    /// can be detected by its first two bytes being `1f 8b`, which never start a JSON or text payload:
+    ///
+    /// ```js
+    /// self.addEventListener("push", (event) => {
+    ///   const bytes = event.data.arrayBuffer();
+    ///   const gzip = new Uint8Array(bytes, 0, 2).every((b, i) => b === [0x1f, 0x8b][i]);
+    ///   const body = gzip ? new Blob([bytes]).stream().pipeThrough(new DecompressionStream("gzip")) : bytes;
+    ///   event.waitUntil(new Response(body).json().then(showNotification));
+    /// });
+    /// ```
+    #[cfg(feature = "compression")]
+    pub fn set_payload_compressed(
+        &mut self,
+        encoding: ContentEncoding,
+        content: &[u8],
+        compression: Compression,
+    ) -> Result<(), WebPushError> {
+        use std::io::Write;
+
+        let compressed = match compression {
+            Compression::Gzip => {
+                let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::best());
+                encoder.write_all(content)?;
+                encoder.finish()?
+            }
+        };
+
+        if compressed.len() > MAX_PAYLOAD_LEN {
+            return Err(WebPushError::PayloadTooLarge {
+                source: PayloadLimitSource::Local,
+            });
+        }
+
+        self.payload = Some(WebPushPayloadBuilder {
+            content: Cow::Owned(compressed),
+            encoding,
+        });
+
+        Ok(())
     }
 
     /// Builds and if set, encrypts the payload.
     pub fn build(self) -> Result<WebPushMessage, WebPushError> {
-        let endpoint: Uri = self.subscription_info.endpoint.parse()?;
+        self.build_message()
+    }
+
+    /// Builds one message per chunk of `items`, for lists too large for a single payload such as feeds or digests.
+    ///
+    /// The items are split with [`split_payload`](crate::split_payload) into JSON arrays of at most `budget` bytes,
+    /// capped to the payload size limit, and every chunk is encrypted with the encoding of the payload if set, or
+    /// else the [recommended encoding](SubscriptionInfo::recommended_encoding) of the subscription. All the other
+    /// fields are the same for every message. Returns `PayloadTooLarge` if a single item doesn't fit.
+    pub fn build_chunked<T>(mut self, items: &[T], budget: usize) -> Result<Vec<WebPushMessage>, WebPushError>
+    where
+        T: serde::Serialize,
+    {
+        let encoding = self.payload.as_ref().map_or_else(
+            || self.subscription_info.recommended_encoding(),
+            |payload| payload.encoding,
+        );
+
+        split_payload(items, budget.min(MAX_PAYLOAD_LEN))?
+            .into_iter()
+            .map(|chunk| {
+                self.payload = Some(WebPushPayloadBuilder {
+                    content: Cow::Owned(chunk),
+                    encoding,
+                });
+
+                self.build_message()
+            })
+            .collect()
+    }
+
+    fn build_message(&self) -> Result<WebPushMessage, WebPushError> {
+        let endpoint: Uri = self.endpoint().parse()?;
         let topic: Option<String> = self
             .topic
-            .map(|topic| {
-                if topic.len() > 32 {
-                    Err(WebPushError::InvalidTopic)
-                } else if topic.chars().all(is_base64url_char) {
-                    Ok(topic)
-                } else {
-                    Err(WebPushError::InvalidTopic)
-                }
-            })
+            .as_ref()
+            .map(|topic| validate_topic_len(topic, self.max_topic_len).map(|_| topic.clone()))
             .transpose()?;
 
-        if let Some(payload) = self.payload {
-            let p256dh = base64::decode_config(&self.subscription_info.keys.p256dh, base64::URL_SAFE)?;
-            let auth = base64::decode_config(&self.subscription_info.keys.auth, base64::URL_SAFE)?;
+        Ok(WebPushMessage {
+            endpoint,
+            ttl: self.ttl,
+            urgency: self.urgency,
+            topic,
+            payload: self.build_payload()?,
+            prefer_async: self.prefer_async,
+            idempotency_key: self.idempotency_key.clone(),
+            method: self.method.clone(),
+            empty_body_encoding: self.empty_body_encoding,
+            extra_headers: self.extra_headers.clone(),
+            deadline: self.deadline,
+            deferred_vapid: self.deferred_vapid_signer.clone().map(|signer| DeferredVapid {
+                signer,
+                vapid_scheme: self.vapid_scheme,
+            }),
+        })
+    }
+
+    /// Encrypts the payload, if set, without parsing the endpoint or validating the other message fields.
+    ///
+    /// Useful for inspecting the encrypted content, or for pipelines that encrypt now and resolve the
+    /// endpoint later. The endpoint URI is only parsed by [`WebPushMessageBuilder::build`].
+    pub fn build_payload(&self) -> Result<Option<WebPushPayload>, WebPushError> {
+        if let Some(payload) = &self.payload {
+            let (p256dh, auth) = match payload.encoding {
+                #[cfg(feature = "test-util")]
+                ContentEncoding::Identity => (Vec::new(), Vec::new()),
+                _ => {
+                    let p256dh = decode_key(&self.subscription_info.keys.p256dh, &P256DH_LENS)?;
+                    let auth = decode_key(&self.subscription_info.keys.auth, &[AUTH_SECRET_LEN])?;
 
-            let http_ece = HttpEce::new(payload.encoding, &p256dh, &auth, self.vapid_signature);
+                    if auth.len() != AUTH_SECRET_LEN {
+                        return Err(WebPushError::InvalidAuthSecret(auth.len()));
+                    }
 
-            Ok(WebPushMessage {
-                endpoint,
-                ttl: self.ttl,
-                urgency: self.urgency,
-                topic,
-                payload: Some(http_ece.encrypt(payload.content)?),
-            })
+                    (uncompressed_public_key(p256dh)?, auth)
+                }
+            };
+
+            let mut http_ece = HttpEce::new(payload.encoding, &p256dh, &auth, self.vapid_signature.clone());
+            http_ece.set_vapid_scheme(self.vapid_scheme);
+            http_ece.set_legacy_encryption_key(self.legacy_encryption_key);
+
+            Ok(Some(http_ece.encrypt(&payload.content)?))
         } else {
-            Ok(WebPushMessage {
-                endpoint,
-                ttl: self.ttl,
-                urgency: self.urgency,
-                topic,
-                payload: None,
-            })
+            Ok(None)
         }
     }
-}
 
-fn is_base64url_char(c: char) -> bool {
-    c.is_ascii_uppercase() || c.is_ascii_lowercase() || c.is_ascii_digit() || (c == '-' || c == '_')
+    /// Estimates the size in bytes of the HTTP request for this message, including the request line, headers and
+    /// the encrypted body. Builds the message and signs a deferred VAPID signature like a send does, but doesn't
+    /// send anything.
+    ///
+    /// Returns the error of [`build`](Self::build) if the message can't be built.
+    pub fn estimated_request_size(&self) -> Result<usize, WebPushError> {
+        let mut message = self.build_message()?;
+        message.sign_deferred_vapid()?;
+
+        // Request line, e.g. `POST <endpoint> HTTP/1.1\r\n`, and the empty line ending the headers.
+        let request_line =
+            message.method.as_str().len() + 1 + message.endpoint.to_string().len() + " HTTP/1.1\r\n".len();
+        let header_lines: usize = message
+            .debug_headers()
+            .iter()
+            .map(|(name, value)| name.len() + ": ".len() + value.len() + "\r\n".len())
+            .sum();
+        let body_len = message.payload_bytes().map_or(0, <[u8]>::len);
+
+        Ok(request_line + header_lines + "\r\n".len() + body_len)
+    }
+
+    /// The endpoint the message is sent to, the override from [`WebPushMessageBuilder::set_endpoint`] if set, with
+    /// the query parameters from [`WebPushMessageBuilder::add_endpoint_query`].
+    fn endpoint(&self) -> Cow<'_, str> {
+        let endpoint = self.endpoint.as_deref().unwrap_or(&self.subscription_info.endpoint);
+
+        if self.endpoint_query.is_empty() {
+            return Cow::Borrowed(endpoint);
+        }
+
+        let mut endpoint = endpoint.to_string();
+
+        for (key, value) in &self.endpoint_query {
+            match endpoint.find('?') {
+                None => endpoint.push('?'),
+                Some(index) if index + 1 < endpoint.len() && !endpoint.ends_with('&') => endpoint.push('&'),
+                Some(_) => {}
+            }
+
+            endpoint.push_str(&percent_encode(key));
+            endpoint.push('=');
+            endpoint.push_str(&percent_encode(value));
+        }
+
+        Cow::Owned(endpoint)
+    }
+}
+
+/// The length of the auth secret of a subscription, from RFC8291.
+const AUTH_SECRET_LEN: usize = 16;
+
+/// The lengths of a compressed and an uncompressed P-256 public key.
+const P256DH_LENS: [usize; 2] = [33, 65];
+
+/// Decodes a base64url subscription key. Returns `DoubleEncodedKey` if the key has none of the `lens`, but is base64
+/// text that decodes to one of them.
+fn decode_key(key: &str, lens: &[usize]) -> Result<Vec<u8>, WebPushError> {
+    let bytes = base64::decode_config(key, base64::URL_SAFE)?;
+
+    if !lens.contains(&bytes.len()) && decode_base64_text(&bytes).is_some_and(|inner| lens.contains(&inner.len())) {
+        return Err(WebPushError::DoubleEncodedKey);
+    }
+
+    Ok(bytes)
+}
+
+/// Decodes `text` if it is base64 in either the URL safe or the standard alphabet.
+fn decode_base64_text(text: &[u8]) -> Option<Vec<u8>> {
+    base64::decode_config(text, base64::URL_SAFE)
+        .or_else(|_| base64::decode_config(text, base64::STANDARD))
+        .ok()
+}
+
+/// The maximum topic length allowed by RFC8030.
+const MAX_TOPIC_LEN: usize = 32;
+
+/// Checks that the topic has at most 32 characters, all from the base64url alphabet.
+pub(crate) fn validate_topic(topic: &str) -> Result<(), WebPushError> {
+    validate_topic_len(topic, MAX_TOPIC_LEN)
+}
+
+/// Checks that the topic has at most `max_len` characters, all from the base64url alphabet.
+fn validate_topic_len(topic: &str, max_len: usize) -> Result<(), WebPushError> {
+    if topic.len() > max_len {
+        Err(WebPushError::InvalidTopic)
+    } else if topic.chars().all(is_base64url_char) {
+        Ok(())
+    } else {
+        Err(WebPushError::InvalidTopic)
+    }
+}
+
+/// Percent-encodes everything but the unreserved characters of RFC 3986, for use in a query parameter.
+fn percent_encode(value: &str) -> String {
+    value
+        .bytes()
+        .map(|byte| match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => (byte as char).to_string(),
+            _ => format!("%{:02X}", byte),
+        })
+        .collect()
+}
+
+/// Checks that the method is `POST` or `PUT`.
+pub(crate) fn validate_method(method: &Method) -> Result<(), WebPushError> {
+    if *method == Method::POST || *method == Method::PUT {
+        Ok(())
+    } else {
+        Err(WebPushError::InvalidMethod)
+    }
+}
+
+/// The maximum length of an extra header, name and value together.
+const MAX_EXTRA_HEADER_LEN: usize = 1024;
+
+/// The headers the crate sets itself, which can't be added as extra headers.
+const RESERVED_HEADERS: [&str; 12] = [
+    "ttl",
+    "urgency",
+    "topic",
+    "prefer",
+    "idempotency-key",
+    "content-encoding",
+    "content-length",
+    "content-type",
+    "authorization",
+    "crypto-key",
+    "encryption",
+    "encryption-key",
+];
+
+/// Checks that an extra header is a valid header name with a printable ASCII value, at most 1024 bytes long, and
+/// not one of the headers set by the crate.
+pub(crate) fn validate_header(name: &str, value: &str) -> Result<(), WebPushError> {
+    let name = http::header::HeaderName::from_bytes(name.as_bytes()).map_err(|_| WebPushError::InvalidHeader)?;
+
+    if name.as_str().len() + value.len() > MAX_EXTRA_HEADER_LEN
+        || RESERVED_HEADERS.contains(&name.as_str())
+        || name == http::header::HOST
+    {
+        return Err(WebPushError::InvalidHeader);
+    }
+
+    validate_header_value(value)
+}
+
+/// Checks that a header value is printable ASCII, which rules out line breaks smuggling in other headers.
+pub(crate) fn validate_header_value(value: &str) -> Result<(), WebPushError> {
+    if value.bytes().all(|byte| byte == b'\t' || (b' '..=b'~').contains(&byte)) {
+        Ok(())
+    } else {
+        Err(WebPushError::InvalidHeader)
+    }
+}
+
+fn is_base64url_char(c: char) -> bool {
+    c.is_ascii_uppercase() || c.is_ascii_lowercase() || c.is_ascii_digit() || (c == '-' || c == '_')
+}
+
+/// A message to `endpoint` without a payload, for the tests of the clients.
+#[cfg(test)]
+pub(crate) fn test_message(endpoint: &str) -> WebPushMessage {
+    WebPushMessageBuilder::new(&SubscriptionInfo::new(endpoint, "", ""))
+        .build()
+        .unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use crate::http_ece::{ContentEncoding, MAX_PAYLOAD_LEN};
+    use crate::message::{
+        validate_subscriptions, SubscriptionChange, SubscriptionInfo, Urgency, WebPushMessageBuilder, WebPushPayload,
+    };
+    use crate::notification::split_payload;
+    use crate::WebPushError;
+
+    fn subscription_info(endpoint: &str) -> SubscriptionInfo {
+        SubscriptionInfo::new(
+            endpoint,
+            "BLMbF9ffKBiWQLCKvTHb6LO8Nb6dcUh6TItC455vu2kElga6PQvUmaFyCdykxY2nOSSL3yKgfbmFLRTUaGv4yV8",
+            "xS03Fi5ErfTNH_l9WHE9Ig",
+        )
+    }
+
+    #[cfg(feature = "compression")]
+    #[test]
+    fn compresses_the_payload_before_encryption() {
+        use std::io::Read;
+
+        use crate::message::Compression;
+
+        let info = subscription_info("https://example.com/push");
+        let content = br#"{"title":"Hello","body":"Hello"}"#.repeat(200);
+
+        let mut builder = WebPushMessageBuilder::new(&info);
+        builder
+            .set_payload_compressed(ContentEncoding::Aes128Gcm, &content, Compression::Gzip)
+            .unwrap();
+
+        let mut decompressed = Vec::new();
+        flate2::read::GzDecoder::new(&*builder.payload.as_ref().unwrap().content)
+            .read_to_end(&mut decompressed)
+            .unwrap();
+
+        assert_eq!(content, decompressed);
+        assert!(builder.build().unwrap().payload_bytes().unwrap().len() < content.len());
+    }
+
+    #[cfg(feature = "compression")]
+    #[test]
+    fn rejects_a_payload_too_large_after_compression() {
+        use crate::message::Compression;
+
+        let info = subscription_info("https://example.com/push");
+        let content: Vec<u8> = (0..4000).map(|_| jwt_simple::reexports::rand::random()).collect();
+
+        let mut builder = WebPushMessageBuilder::new(&info);
+
+        assert!(matches!(
+            builder.set_payload_compressed(ContentEncoding::Aes128Gcm, &content, Compression::Gzip),
+            Err(WebPushError::PayloadTooLarge {
+                source: crate::PayloadLimitSource::Local,
+            })
+        ));
+    }
+
+    #[cfg(feature = "test-util")]
+    #[test]
+    fn sends_a_plaintext_payload_without_subscription_keys() {
+        let info = SubscriptionInfo::new("https://localhost:8443/push", "p256dh", "auth");
+        let mut builder = WebPushMessageBuilder::new(&info);
+        builder.set_payload_plaintext(b"test");
+
+        let message = builder.build().unwrap();
+
+        assert_eq!(Some(&b"test"[..]), message.payload_bytes());
+        assert!(message
+            .debug_headers()
+            .contains(&("content-encoding".to_string(), "identity".to_string())));
+    }
+
+    #[test]
+    fn dumps_the_request_shape() {
+        let info = subscription_info("https://example.com/push");
+        let mut builder = WebPushMessageBuilder::new(&info);
+        builder.set_ttl(60);
+        builder.set_topic("news".into());
+        builder.set_payload(ContentEncoding::Aes128Gcm, b"test");
+
+        assert_eq!(
+            "POST https://example.com/push\n\
+             content-encoding: aes128gcm\n\
+             content-length: 230\n\
+             content-type: application/octet-stream\n\
+             topic: news\n\
+             ttl: 60\n\
+             <body 230 bytes>\n",
+            builder.build().unwrap().to_debug_string()
+        );
+    }
+
+    #[test]
+    fn appends_query_parameters_to_the_endpoint() {
+        let endpoint_with = |endpoint: &str| {
+            let info = subscription_info(endpoint);
+            let mut builder = WebPushMessageBuilder::new(&info);
+            builder.add_endpoint_query("relay", "eu-1");
+            builder.add_endpoint_query("tag", "a b&c=d/é");
+
+            builder.build().unwrap().endpoint.to_string()
+        };
+
+        assert_eq!(
+            "https://example.com/push?relay=eu-1&tag=a%20b%26c%3Dd%2F%C3%A9",
+            endpoint_with("https://example.com/push")
+        );
+        assert_eq!(
+            "https://example.com/push?id=1&relay=eu-1&tag=a%20b%26c%3Dd%2F%C3%A9",
+            endpoint_with("https://example.com/push?id=1")
+        );
+        assert_eq!(
+            "https://example.com/push?relay=eu-1&tag=a%20b%26c%3Dd%2F%C3%A9",
+            endpoint_with("https://example.com/push?")
+        );
+
+        let info = subscription_info("https://example.com/push?id=1");
+        assert_eq!(
+            "https://example.com/push?id=1",
+            WebPushMessageBuilder::new(&info).build().unwrap().endpoint.to_string()
+        );
+    }
+
+    #[test]
+    fn overrides_the_method() {
+        let info = subscription_info("https://example.com/push");
+        let mut builder = WebPushMessageBuilder::new(&info);
+
+        assert!(matches!(
+            builder.set_method(http::Method::GET),
+            Err(WebPushError::InvalidMethod)
+        ));
+        builder.set_method(http::Method::PUT).unwrap();
+
+        let message = builder.build().unwrap();
+        assert_eq!(http::Method::PUT, message.method);
+
+        let request = crate::request_builder::build_request::<Vec<u8>>(message);
+        assert_eq!(http::Method::PUT, request.method());
+    }
+
+    #[test]
+    fn formats_a_curl_command() {
+        let info = subscription_info("https://example.com/push?user='1'");
+        let mut builder = WebPushMessageBuilder::new(&info);
+        builder.set_ttl(60);
+        builder.set_payload(ContentEncoding::Aes128Gcm, b"test");
+
+        assert_eq!(
+            "curl -X POST 'https://example.com/push?user='\\''1'\\''' \\\n  \
+             -H 'TTL: 60' \\\n  \
+             -H 'content-encoding: aes128gcm' \\\n  \
+             -H 'content-length: 230' \\\n  \
+             -H 'content-type: application/octet-stream' \\\n  \
+             --data-binary @payload.bin",
+            builder.build().unwrap().to_curl()
+        );
+
+        let builder = WebPushMessageBuilder::new(&info);
+        assert!(!builder.build().unwrap().to_curl().contains("--data-binary"));
+    }
+
+    #[test]
+    fn signs_vapid_at_send_time() {
+        let info = subscription_info("https://fcm.googleapis.com/fcm/send/abc");
+        let signer = crate::VapidSignatureBuilder::from_base64_no_sub(
+            "IQ9Ur0ykXoHS9gzfYX0aBjy9lvdrjx_PFUXmie9YRcY",
+            base64::URL_SAFE_NO_PAD,
+        )
+        .unwrap()
+        .prepare();
+        let mut builder = WebPushMessageBuilder::new(&info);
+        builder.set_payload(ContentEncoding::Aes128Gcm, b"test");
+        builder.set_deferred_vapid_signer(signer);
+
+        let mut message = builder.build().unwrap();
+        let authorization = |message: &crate::WebPushMessage| {
+            message
+                .debug_headers()
+                .into_iter()
+                .find(|(name, _)| name == "Authorization")
+        };
+
+        assert!(message.deferred_vapid.is_some());
+        assert!(authorization(&message).is_none());
+
+        message.sign_deferred_vapid().unwrap();
+
+        assert!(message.deferred_vapid.is_none());
+        assert!(authorization(&message).unwrap().1.starts_with("vapid t="));
+    }
+
+    #[test]
+    fn exposes_message_fields() {
+        let info = subscription_info("https://example.com/push");
+        let mut builder = WebPushMessageBuilder::new(&info);
+        builder.set_ttl(60);
+        builder.set_urgency(Urgency::Low);
+        builder.set_topic("news".into());
+
+        let message = builder.build().unwrap();
+
+        assert_eq!("https://example.com/push", message.endpoint());
+        assert_eq!(60, message.ttl());
+        assert_eq!(Some(Urgency::Low), message.urgency());
+        assert_eq!(Some("news"), message.topic());
+    }
+
+    #[test]
+    fn encrypts_for_a_compressed_public_key() {
+        let info = SubscriptionInfo::try_new(
+            "https://example.com/push",
+            "A7MbF9ffKBiWQLCKvTHb6LO8Nb6dcUh6TItC455vu2kE",
+            "xS03Fi5ErfTNH_l9WHE9Ig",
+        )
+        .unwrap();
+        let mut builder = WebPushMessageBuilder::new(&info);
+        builder.set_payload(ContentEncoding::Aes128Gcm, b"test");
+
+        assert!(builder.build().is_ok());
+    }
+
+    #[test]
+    fn derives_a_valid_topic_from_a_key() {
+        let info = subscription_info("https://example.com/push");
+
+        let topic = |key: &str| {
+            let mut builder = WebPushMessageBuilder::new(&info);
+            builder.set_topic_from_hash(key);
+            builder.build().unwrap().topic.unwrap()
+        };
+
+        assert_eq!(topic("chat/42"), topic("chat/42"));
+        assert_ne!(topic("chat/42"), topic("chat/43"));
+        assert_eq!(32, topic("chat/42").len());
+        assert_eq!(32, topic("").len());
+    }
+
+    #[test]
+    fn builds_without_vapid_after_disabling_it() {
+        let info = subscription_info("https://example.com/push");
+        let signature = crate::VapidSignature {
+            auth_t: "eyJ0eXAiOiJKV1QiLCJhbGciOiJFUzI1NiJ9.e30.c2ln".into(),
+            auth_k: vec![4; 65],
+        };
+        let has_authorization =
+            |message: crate::WebPushMessage| message.debug_headers().iter().any(|(name, _)| name == "Authorization");
+
+        let mut builder = WebPushMessageBuilder::new(&info);
+        builder.set_payload(ContentEncoding::Aes128Gcm, b"test");
+        builder.set_vapid_signature(signature.clone());
+        assert!(has_authorization(builder.build().unwrap()));
+
+        let mut builder = WebPushMessageBuilder::new(&info);
+        builder.set_payload(ContentEncoding::Aes128Gcm, b"test");
+        builder.set_vapid_signature(signature);
+        builder.disable_vapid();
+        assert!(!has_authorization(builder.build().unwrap()));
+    }
+
+    #[test]
+    fn recommends_an_encoding_by_host() {
+        let encoding = |endpoint| subscription_info(endpoint).recommended_encoding();
+
+        assert_eq!(
+            ContentEncoding::Aes128Gcm,
+            encoding("https://fcm.googleapis.com/fcm/send/eKClHsXFm9E")
+        );
+        assert_eq!(
+            ContentEncoding::Aes128Gcm,
+            encoding("https://updates.push.services.mozilla.com/wpush/v2/gAAAAA")
+        );
+        assert_eq!(
+            ContentEncoding::Aes128Gcm,
+            encoding("https://web.push.apple.com/QGuQyavXu")
+        );
+        assert_eq!(ContentEncoding::Aes128Gcm, encoding("https://push.example.com/push"));
+        assert_eq!(ContentEncoding::Aes128Gcm, encoding("not a uri"));
+        assert_eq!(
+            ContentEncoding::AesGcm,
+            encoding("https://android.googleapis.com/gcm/send/eKClHsXFm9E")
+        );
+    }
+
+    #[test]
+    fn sets_the_payload_with_the_recommended_encoding() {
+        let info = subscription_info("https://android.googleapis.com/gcm/send/eKClHsXFm9E");
+        let mut builder = WebPushMessageBuilder::new(&info);
+        builder.set_payload_auto(b"test");
+
+        assert_eq!(
+            Some(ContentEncoding::AesGcm),
+            builder.build().unwrap().content_encoding()
+        );
+    }
+
+    #[test]
+    fn rejects_an_auth_secret_of_the_wrong_length() {
+        let info = SubscriptionInfo::new(
+            "https://example.com/push",
+            "BLMbF9ffKBiWQLCKvTHb6LO8Nb6dcUh6TItC455vu2kElga6PQvUmaFyCdykxY2nOSSL3yKgfbmFLRTUaGv4yV8",
+            "AQIDBAUGBwgJCgsMDQ4PEBESExQVFg",
+        );
+        let mut builder = WebPushMessageBuilder::new(&info);
+        builder.set_payload(ContentEncoding::Aes128Gcm, b"test");
+
+        assert!(matches!(builder.build(), Err(WebPushError::InvalidAuthSecret(22))));
+    }
+
+    #[test]
+    fn sets_the_ttl_from_a_duration() {
+        let info = subscription_info("https://example.com/push");
+        let ttl = |duration| {
+            let mut builder = WebPushMessageBuilder::new(&info);
+            builder.set_ttl_duration(duration);
+            builder.build().unwrap().ttl
+        };
+
+        assert_eq!(3600, ttl(Duration::from_secs(60 * 60)));
+        assert_eq!(0, ttl(Duration::from_millis(999)));
+        assert_eq!(1, ttl(Duration::from_millis(1500)));
+        assert_eq!(u32::MAX, ttl(Duration::from_secs(u64::MAX)));
+    }
+
+    #[test]
+    fn builds_a_message_per_chunk() {
+        let info = subscription_info("https://example.com/push");
+        let mut builder = WebPushMessageBuilder::new(&info);
+        builder.set_ttl(60);
+        let items: Vec<String> = (0..1000).map(|i| format!("digest entry number {}", i)).collect();
+
+        let messages = builder.build_chunked(&items, 4096).unwrap();
+
+        let chunks = split_payload(&items, MAX_PAYLOAD_LEN).unwrap();
+
+        assert!(messages.len() > 1);
+        assert_eq!(chunks.len(), messages.len());
+        assert!(chunks.iter().all(|chunk| chunk.len() <= MAX_PAYLOAD_LEN));
+        for message in messages {
+            let payload = message.payload.unwrap();
+
+            assert_eq!(60, message.ttl);
+            assert_eq!(ContentEncoding::Aes128Gcm, payload.content_encoding);
+        }
+
+        // Two items filling the limit but for the closing bracket must go to separate messages.
+        let half = "x".repeat((MAX_PAYLOAD_LEN - 2) / 2 - 2);
+        let messages = WebPushMessageBuilder::new(&info)
+            .build_chunked(&[&half, &half], 4096)
+            .unwrap();
+
+        assert_eq!(2, messages.len());
+    }
+
+    #[test]
+    fn measures_the_encrypted_payload() {
+        let info = subscription_info("https://example.com/push");
+        let mut builder = WebPushMessageBuilder::new(&info);
+        builder.set_payload(ContentEncoding::Aes128Gcm, b"test");
+
+        let payload = builder.build().unwrap().payload.unwrap();
+
+        assert_eq!(payload.content.len(), payload.len());
+        assert_eq!(230, payload.len());
+        assert!(!payload.is_empty());
+    }
+
+    /// The payload from RFC 8291 appendix A.
+    #[test]
+    fn parses_the_aes128gcm_header() {
+        let decode = |value| base64::decode_config(value, base64::URL_SAFE_NO_PAD).unwrap();
+
+        let mut payload = WebPushPayload {
+            content: decode("DGv6ra1nlYgDCS1FRnbzlwAAEABBBP4z9KsN6nGRTbVYI_c7VJSPQTBtkgcy27mlmlMoZIIgDll6e3vCYLocInmYWAmS6TlzAC8wEqKK6PBru3jl7A_yl95bQpu6cVPTpK4Mqgkf1CXztLVBSt2Ks3oZwbuwXPXLWyouBWLVWGNWQexSgSxsj_Qulcy4a-fN"),
+            crypto_headers: Vec::new(),
+            content_encoding: ContentEncoding::Aes128Gcm,
+        };
+
+        let header = payload.aes128gcm_header().unwrap();
+
+        assert_eq!(decode("DGv6ra1nlYgDCS1FRnbzlw"), header.salt);
+        assert_eq!(4096, header.record_size);
+        assert_eq!(
+            decode("BP4z9KsN6nGRTbVYI_c7VJSPQTBtkgcy27mlmlMoZIIgDll6e3vCYLocInmYWAmS6TlzAC8wEqKK6PBru3jl7A8"),
+            header.key_id
+        );
+
+        payload.content.truncate(40);
+        assert_eq!(None, payload.aes128gcm_header());
+
+        payload.content_encoding = ContentEncoding::AesGcm;
+        assert_eq!(None, payload.aes128gcm_header());
+    }
+
+    #[test]
+    fn extracts_the_sender_public_key() {
+        let decode = |value| base64::decode_config(value, base64::URL_SAFE_NO_PAD).unwrap();
+
+        let payload = WebPushPayload {
+            content: decode("DGv6ra1nlYgDCS1FRnbzlwAAEABBBP4z9KsN6nGRTbVYI_c7VJSPQTBtkgcy27mlmlMoZIIgDll6e3vCYLocInmYWAmS6TlzAC8wEqKK6PBru3jl7A_yl95bQpu6cVPTpK4Mqgkf1CXztLVBSt2Ks3oZwbuwXPXLWyouBWLVWGNWQexSgSxsj_Qulcy4a-fN"),
+            crypto_headers: Vec::new(),
+            content_encoding: ContentEncoding::Aes128Gcm,
+        };
+
+        assert_eq!(
+            Some(decode(
+                "BP4z9KsN6nGRTbVYI_c7VJSPQTBtkgcy27mlmlMoZIIgDll6e3vCYLocInmYWAmS6TlzAC8wEqKK6PBru3jl7A8"
+            )),
+            payload.sender_public_key()
+        );
+
+        let payload = WebPushPayload {
+            content: Vec::new(),
+            crypto_headers: vec![
+                ("Crypto-Key", "dh=BNoRDbb84JGm8g5Z5CFxurSqsXWJ11ItfXEWYVLE85Y7CYkDjXsIEc4aqxYaQ1G8BqkXCJ6DPpDrWtdWj_mugHU;p256ecdsa=YmFy".into()),
+                ("Encryption", "salt=lngarbyKfMoi9Z75xYXmkg".into()),
+            ],
+            content_encoding: ContentEncoding::AesGcm,
+        };
+
+        assert_eq!(
+            Some(decode(
+                "BNoRDbb84JGm8g5Z5CFxurSqsXWJ11ItfXEWYVLE85Y7CYkDjXsIEc4aqxYaQ1G8BqkXCJ6DPpDrWtdWj_mugHU"
+            )),
+            payload.sender_public_key()
+        );
+
+        let info = subscription_info("https://example.com/push");
+        let mut builder = WebPushMessageBuilder::new(&info);
+        builder.set_payload(ContentEncoding::AesGcm, b"test");
+        let key = builder.build().unwrap().payload.unwrap().sender_public_key().unwrap();
+
+        assert_eq!(65, key.len());
+        assert_eq!(0x04, key[0]);
+    }
+
+    #[test]
+    fn detects_double_encoded_keys() {
+        let mut info = subscription_info("https://example.com/push");
+        let p256dh = info.keys.p256dh.clone();
+        info.keys.p256dh = base64::encode_config(&p256dh, base64::URL_SAFE_NO_PAD);
+
+        let mut builder = WebPushMessageBuilder::new(&info);
+        builder.set_payload(ContentEncoding::Aes128Gcm, b"test");
+
+        assert!(matches!(builder.build_payload(), Err(WebPushError::DoubleEncodedKey)));
+        assert!(matches!(info.validate(), Err(WebPushError::DoubleEncodedKey)));
+
+        assert!(info.keys.normalize_double_encoding());
+        assert_eq!(p256dh, info.keys.p256dh);
+        assert!(info.validate().is_ok());
+        assert!(!info.keys.normalize_double_encoding());
+    }
+
+    #[test]
+    fn builds_from_an_owned_subscription() {
+        struct Queued {
+            builder: WebPushMessageBuilder<'static>,
+        }
+
+        let queued = {
+            let info = subscription_info("https://example.com/push");
+            let mut builder = WebPushMessageBuilder::new_owned(info);
+            builder.set_ttl(60);
+            builder.set_payload(ContentEncoding::Aes128Gcm, b"test");
+
+            Queued { builder }
+        };
+
+        let message = queued.builder.build().unwrap();
+
+        assert_eq!("https://example.com/push", message.endpoint());
+        assert_eq!(60, message.ttl());
+        assert!(message.payload.is_some());
+    }
+
+    #[test]
+    fn validates_a_batch_of_subscriptions() {
+        let subscriptions = vec![
+            subscription_info("https://example.com/push/1"),
+            subscription_info("not a uri"),
+            SubscriptionInfo::new("https://example.com/push/2", "p256dh", "auth"),
+            subscription_info("https://example.com/push/3"),
+        ];
+
+        let results = validate_subscriptions(&subscriptions);
+
+        assert_eq!(4, results.len());
+        assert!(results[0].is_ok());
+        assert!(matches!(results[1], Err(WebPushError::InvalidUri)));
+        assert!(matches!(results[2], Err(WebPushError::InvalidCryptoKeys)));
+        assert!(results[3].is_ok());
+    }
+
+    #[test]
+    fn collects_every_problem_of_a_subscription() {
+        assert!(subscription_info("https://example.com/push/1").validate_all().is_ok());
+
+        let info = SubscriptionInfo::new("not a uri", "p256dh", "");
+
+        match info.validate_all() {
+            Err(WebPushError::InvalidSubscription { reasons }) => assert_eq!(
+                vec![
+                    "endpoint: invalid uri provided",
+                    "p256dh: request has invalid cryptographic keys",
+                    "auth: missing",
+                ],
+                reasons
+            ),
+            other => panic!("expected InvalidSubscription, got {:?}", other),
+        }
+        assert!(matches!(info.validate(), Err(WebPushError::InvalidUri)));
+    }
+
+    #[test]
+    fn sends_validated_extra_headers() {
+        let info = subscription_info("https://example.com/push");
+        let mut builder = WebPushMessageBuilder::new(&info);
+        builder.set_ttl(60);
+        builder
+            .add_header("X-Forwarded-For", "203.0.113.7, 198.51.100.1")
+            .unwrap();
+
+        let message = builder.build().unwrap();
+
+        assert_eq!(
+            vec![
+                ("TTL".to_string(), "60".to_string()),
+                ("X-Forwarded-For".to_string(), "203.0.113.7, 198.51.100.1".to_string())
+            ],
+            message.debug_headers()
+        );
+
+        let request = crate::request_builder::build_request::<Vec<u8>>(message);
+        assert_eq!("203.0.113.7, 198.51.100.1", request.headers()["x-forwarded-for"]);
+    }
+
+    #[test]
+    fn rejects_invalid_extra_headers() {
+        let info = subscription_info("https://example.com/push");
+        let mut builder = WebPushMessageBuilder::new(&info);
+
+        for (name, value) in [
+            ("X-Forwarded-For", "203.0.113.7\r\nAuthorization: vapid"),
+            ("X-Forwarded-For", "caf\u{e9}"),
+            ("X Forwarded For", "203.0.113.7"),
+            ("Authorization", "vapid t=forged"),
+            ("ttl", "0"),
+            ("X-Forwarded-For", &"1".repeat(1024)),
+        ] {
+            assert!(
+                matches!(builder.add_header(name, value), Err(WebPushError::InvalidHeader)),
+                "{}: {}",
+                name,
+                value
+            );
+        }
+
+        assert!(builder.build().unwrap().extra_headers.is_empty());
+    }
+
+    #[test]
+    fn rejects_an_invalid_idempotency_key() {
+        let info = subscription_info("https://example.com/push");
+        let mut builder = WebPushMessageBuilder::new(&info);
+
+        assert!(matches!(
+            builder.set_idempotency_key("abc\r\nX-Evil: 1"),
+            Err(WebPushError::InvalidHeader)
+        ));
+        assert!(builder.build().unwrap().idempotency_key.is_none());
+    }
+
+    #[test]
+    fn lists_the_request_headers() {
+        let info = subscription_info("https://example.com/push");
+        let mut builder = WebPushMessageBuilder::new(&info);
+        builder.set_ttl(60);
+        builder.set_urgency(Urgency::High);
+        builder.set_payload(ContentEncoding::Aes128Gcm, b"test");
+
+        let headers = builder.build().unwrap().debug_headers();
+        let names: Vec<&str> = headers.iter().map(|(name, _)| name.as_str()).collect();
+
+        assert_eq!(
+            vec!["TTL", "Urgency", "content-encoding", "content-length", "content-type"],
+            names
+        );
+        assert_eq!(("TTL".to_string(), "60".to_string()), headers[0]);
+        assert_eq!(("content-encoding".to_string(), "aes128gcm".to_string()), headers[2]);
+    }
+
+    #[test]
+    fn creates_checked_subscription_info() {
+        let info = SubscriptionInfo::try_new(
+            "https://example.com/push",
+            "BLMbF9ffKBiWQLCKvTHb6LO8Nb6dcUh6TItC455vu2kElga6PQvUmaFyCdykxY2nOSSL3yKgfbmFLRTUaGv4yV8",
+            "xS03Fi5ErfTNH_l9WHE9Ig",
+        );
+
+        assert_eq!(subscription_info("https://example.com/push"), info.unwrap());
+    }
+
+    #[test]
+    fn rejects_subscription_info_with_bad_endpoint() {
+        let info = SubscriptionInfo::try_new(
+            "not a uri",
+            "BLMbF9ffKBiWQLCKvTHb6LO8Nb6dcUh6TItC455vu2kElga6PQvUmaFyCdykxY2nOSSL3yKgfbmFLRTUaGv4yV8",
+            "xS03Fi5ErfTNH_l9WHE9Ig",
+        );
+
+        assert!(matches!(info, Err(WebPushError::InvalidUri)));
+    }
+
+    #[test]
+    fn rejects_subscription_info_with_short_auth() {
+        let info = SubscriptionInfo::try_new(
+            "https://example.com/push",
+            "BLMbF9ffKBiWQLCKvTHb6LO8Nb6dcUh6TItC455vu2kElga6PQvUmaFyCdykxY2nOSSL3yKgfbmFLRTUaGv4yV8",
+            "xS03Fi5ErfTNH_l9",
+        );
+
+        assert!(matches!(info, Err(WebPushError::InvalidCryptoKeys)));
+    }
+
+    #[test]
+    fn rejects_subscription_info_with_bad_public_key() {
+        let info = SubscriptionInfo::try_new(
+            "https://example.com/push",
+            "xS03Fi5ErfTNH_l9WHE9Ig",
+            "xS03Fi5ErfTNH_l9WHE9Ig",
+        );
+
+        assert!(matches!(info, Err(WebPushError::InvalidCryptoKeys)));
+    }
+
+    #[test]
+    fn builds_subscription_info_from_raw_key_bytes() {
+        let info = subscription_info("https://example.com/push");
+        let p256dh = base64::decode_config(&info.keys.p256dh, base64::URL_SAFE).unwrap();
+        let auth = base64::decode_config(&info.keys.auth, base64::URL_SAFE).unwrap();
+
+        let from_bytes = SubscriptionInfo::from_bytes("https://example.com/push", &p256dh, &auth);
+
+        assert_eq!(info, from_bytes);
+
+        let mut builder = WebPushMessageBuilder::new(&from_bytes);
+        builder.set_payload(ContentEncoding::Aes128Gcm, "test".as_bytes());
+        let mut base64_builder = WebPushMessageBuilder::new(&info);
+        base64_builder.set_payload(ContentEncoding::Aes128Gcm, "test".as_bytes());
+
+        assert_eq!(
+            base64_builder.build_payload().unwrap().unwrap().content.len(),
+            builder.build_payload().unwrap().unwrap().content.len()
+        );
+    }
+
+    #[test]
+    fn caps_urgency_at_the_maximum() {
+        let info = subscription_info("https://example.com/push");
+        let capped = |requested, max| {
+            let mut builder = WebPushMessageBuilder::new(&info);
+            builder.set_urgency_capped(requested, max);
+            builder.build().unwrap().urgency
+        };
+
+        assert_eq!(Some(Urgency::Normal), capped(Urgency::High, Urgency::Normal));
+        assert_eq!(Some(Urgency::Low), capped(Urgency::Low, Urgency::Normal));
+        assert_eq!(Some(Urgency::VeryLow), capped(Urgency::Normal, Urgency::VeryLow));
+        assert_eq!(Some(Urgency::High), capped(Urgency::High, Urgency::High));
+    }
+
+    #[test]
+    fn orders_urgencies_from_very_low_to_high() {
+        assert!(Urgency::VeryLow < Urgency::Low);
+        assert!(Urgency::Low < Urgency::Normal);
+        assert!(Urgency::Normal < Urgency::High);
+    }
+
+    #[test]
+    fn accepts_topic_at_the_default_limit() {
+        let info = subscription_info("https://example.com/push");
+
+        let mut builder = WebPushMessageBuilder::new(&info);
+        builder.set_topic("a".repeat(32));
+        assert!(builder.build().is_ok());
+
+        let mut builder = WebPushMessageBuilder::new(&info);
+        builder.set_topic("a".repeat(33));
+        assert!(matches!(builder.build(), Err(WebPushError::InvalidTopic)));
+    }
+
+    #[test]
+    fn accepts_longer_topic_with_raised_limit() {
+        let info = subscription_info("https://example.com/push");
+
+        let mut builder = WebPushMessageBuilder::new(&info);
+        builder.set_max_topic_len(64);
+        builder.set_topic("a".repeat(64));
+        assert_eq!(Some("a".repeat(64)), builder.build().unwrap().topic);
+
+        let mut builder = WebPushMessageBuilder::new(&info);
+        builder.set_max_topic_len(64);
+        builder.set_topic("a".repeat(65));
+        assert!(matches!(builder.build(), Err(WebPushError::InvalidTopic)));
+
+        let mut builder = WebPushMessageBuilder::new(&info);
+        builder.set_max_topic_len(64);
+        builder.set_topic("not base64url!".into());
+        assert!(matches!(builder.build(), Err(WebPushError::InvalidTopic)));
+    }
+
+    #[test]
+    fn exposes_payload_of_built_message() {
+        let info = subscription_info("https://example.com/push");
+        let mut builder = WebPushMessageBuilder::new(&info);
+        builder.set_payload(ContentEncoding::Aes128Gcm, "test".as_bytes());
+
+        let message = builder.build().unwrap();
+
+        assert_eq!(Some(230), message.payload_bytes().map(<[u8]>::len));
+        assert_eq!(Some(ContentEncoding::Aes128Gcm), message.content_encoding());
+    }
+
+    #[test]
+    fn exposes_no_payload_of_empty_message() {
+        let info = subscription_info("https://example.com/push");
+        let message = WebPushMessageBuilder::new(&info).build().unwrap();
+
+        assert_eq!(None, message.payload_bytes());
+        assert_eq!(None, message.content_encoding());
+    }
+
+    #[test]
+    fn sends_to_overridden_endpoint() {
+        let info = subscription_info("https://fcm.googleapis.com/fcm/send/eKClHsXFm9E");
+        let mut builder = WebPushMessageBuilder::new(&info);
+        builder.set_endpoint("https://relay.example.com/push/eKClHsXFm9E");
+        builder.set_payload(ContentEncoding::Aes128Gcm, "test".as_bytes());
+
+        let message = builder.build().unwrap();
+
+        assert_eq!("https://relay.example.com/push/eKClHsXFm9E", message.endpoint);
+        assert!(message.payload.is_some());
+    }
+
+    #[test]
+    fn rejects_invalid_overridden_endpoint() {
+        let info = subscription_info("https://fcm.googleapis.com/fcm/send/eKClHsXFm9E");
+        let mut builder = WebPushMessageBuilder::new(&info);
+        builder.set_endpoint("not a uri");
+
+        assert!(matches!(builder.build(), Err(WebPushError::InvalidUri)));
+    }
+
+    #[test]
+    fn builds_payload_without_a_valid_endpoint() {
+        let info = subscription_info("not a uri");
+        let mut builder = WebPushMessageBuilder::new(&info);
+        builder.set_payload(ContentEncoding::Aes128Gcm, "test".as_bytes());
+
+        let payload = builder.build_payload().unwrap().unwrap();
+
+        assert_eq!(ContentEncoding::Aes128Gcm, payload.content_encoding);
+        assert!(!payload.content.is_empty());
+        assert!(matches!(builder.build(), Err(WebPushError::InvalidUri)));
+    }
+
+    #[test]
+    fn builds_no_payload_when_unset() {
+        let info = subscription_info("not a uri");
+        let builder = WebPushMessageBuilder::new(&info);
+
+        assert_eq!(None, builder.build_payload().unwrap());
+    }
+
+    #[test]
+    fn estimates_request_size_with_payload() {
+        let info = subscription_info("https://fcm.googleapis.com/fcm/send/eKClHsXFm9E");
+        let mut builder = WebPushMessageBuilder::new(&info);
+        builder.set_payload(ContentEncoding::Aes128Gcm, "test".as_bytes());
+
+        let estimate = builder.estimated_request_size().unwrap();
+        let body_len = builder.build_payload().unwrap().unwrap().content.len();
+
+        assert!(estimate > body_len);
+        assert!(estimate < body_len + 512);
+    }
+
+    #[test]
+    fn estimates_request_size_without_payload() {
+        let info = subscription_info("https://fcm.googleapis.com/fcm/send/eKClHsXFm9E");
+        let mut builder = WebPushMessageBuilder::new(&info);
+        let empty_estimate = builder.estimated_request_size().unwrap();
+
+        builder.set_payload(ContentEncoding::Aes128Gcm, "test".as_bytes());
+
+        assert!(empty_estimate > 0);
+        assert!(empty_estimate < builder.estimated_request_size().unwrap());
+    }
+
+    #[test]
+    fn estimates_request_size_from_the_sent_headers() {
+        let info = subscription_info("https://fcm.googleapis.com/fcm/send/eKClHsXFm9E");
+        let mut builder = WebPushMessageBuilder::new(&info);
+        builder.set_empty_body_encoding(ContentEncoding::Aes128Gcm);
+
+        let request_line = "POST https://fcm.googleapis.com/fcm/send/eKClHsXFm9E HTTP/1.1\r\n".len();
+        let headers = "TTL: 2419200\r\ncontent-encoding: aes128gcm\r\ncontent-length: 0\r\n\r\n".len();
+
+        assert_eq!(request_line + headers, builder.estimated_request_size().unwrap());
+    }
+
+    #[test]
+    fn estimates_request_size_with_a_deferred_vapid_signature() {
+        let info = subscription_info("https://fcm.googleapis.com/fcm/send/eKClHsXFm9E");
+        let mut builder = WebPushMessageBuilder::new(&info);
+        builder.set_payload(ContentEncoding::Aes128Gcm, "test".as_bytes());
+        let unsigned_estimate = builder.estimated_request_size().unwrap();
+
+        builder.set_deferred_vapid_signer(
+            crate::VapidSignatureBuilder::from_base64_no_sub(
+                "IQ9Ur0ykXoHS9gzfYX0aBjy9lvdrjx_PFUXmie9YRcY",
+                base64::URL_SAFE_NO_PAD,
+            )
+            .unwrap()
+            .prepare(),
+        );
+
+        assert!(builder.estimated_request_size().unwrap() > unsigned_estimate + 200);
+    }
+
+    #[test]
+    fn fails_to_estimate_the_request_size_of_an_invalid_message() {
+        let info = subscription_info("not a uri");
+        let builder = WebPushMessageBuilder::new(&info);
+
+        assert!(matches!(
+            builder.estimated_request_size(),
+            Err(WebPushError::InvalidUri)
+        ));
+    }
+
+    #[test]
+    fn deserializes_flat_keys_subscription_info() {
+        let json = r#"{
+            "endpoint": "https://fcm.googleapis.com/fcm/send/eKClHsXFm9E",
+            "p256dh": "BGa4N1PI79lboMR_YrwCiCsgp35DRvedt7opHcf0yM3iOBTSoQYqQLwWxAfRKE6tsDnReWmhsImkhDF_DBdkNSU",
+            "auth": "EvcWjEgzr4rbvhfi3yds0A"
+        }"#;
+
+        let info = SubscriptionInfo::from_json_str(json).unwrap();
+
+        assert_eq!("https://fcm.googleapis.com/fcm/send/eKClHsXFm9E", info.endpoint);
+        assert_eq!("EvcWjEgzr4rbvhfi3yds0A", info.keys.auth);
+    }
+
+    #[test]
+    fn deserializes_endpoint_url_subscription_info() {
+        let json = r#"{
+            "endpoint_url": "https://updates.push.services.mozilla.com/wpush/v2/gAAAAABaso4Vajy4STM25r5y5oFfyN451rUmES6mhQ",
+            "expirationTime": null,
+            "keys": {
+                "p256dh": "BGa4N1PI79lboMR_YrwCiCsgp35DRvedt7opHcf0yM3iOBTSoQYqQLwWxAfRKE6tsDnReWmhsImkhDF_DBdkNSU",
+                "auth": "EvcWjEgzr4rbvhfi3yds0A"
+            }
+        }"#;
+
+        let info: SubscriptionInfo = serde_json::from_str(json).unwrap();
+
+        assert_eq!(
+            "https://updates.push.services.mozilla.com/wpush/v2/gAAAAABaso4Vajy4STM25r5y5oFfyN451rUmES6mhQ",
+            info.endpoint
+        );
+        assert_eq!(
+            "BGa4N1PI79lboMR_YrwCiCsgp35DRvedt7opHcf0yM3iOBTSoQYqQLwWxAfRKE6tsDnReWmhsImkhDF_DBdkNSU",
+            info.keys.p256dh
+        );
+    }
+
+    #[test]
+    fn rejects_subscription_info_without_keys() {
+        let json = r#"{"endpoint": "https://fcm.googleapis.com/fcm/send/eKClHsXFm9E"}"#;
+
+        assert!(matches!(
+            SubscriptionInfo::from_json_str(json),
+            Err(WebPushError::MissingCryptoKeys)
+        ));
+        assert!(matches!(
+            SubscriptionInfo::from_json_str("not json"),
+            Err(WebPushError::InvalidSubscription { reasons }) if reasons[0].starts_with("json: expected ident")
+        ));
+        assert!(matches!(
+            SubscriptionInfo::from_json_str(r#"{"endpoint": 7}"#),
+            Err(WebPushError::InvalidSubscription { .. })
+        ));
+    }
+
+    #[test]
+    fn parses_an_array_of_subscriptions() {
+        let json = r#"[
+            {
+                "endpoint": "https://fcm.googleapis.com/fcm/send/eKClHsXFm9E",
+                "keys": {"p256dh": "BGa4N1PI79lboMR_YrwCiCsgp35DRvedt7opHcf0yM3iOBTSoQYqQLwWxAfRKE6tsDnReWmhsImkhDF_DBdkNSU", "auth": "EvcWjEgzr4rbvhfi3yds0A"}
+            },
+            {
+                "endpointUrl": "https://updates.push.services.mozilla.com/wpush/v2/gAAAAABaso4Vajy4STM25r5y5oFfyN451rUmES6mhQ",
+                "p256dh": "BLMbF9ffKBiWQLCKvTHb6LO8Nb6dcUh6TItC455vu2kElga6PQvUmaFyCdykxY2nOSSL3yKgfbmFLRTUaGv4yV8",
+                "auth": "xS03Fi5ErfTNH_l9WHE9Ig"
+            }
+        ]"#;
+
+        for lenient in [false, true] {
+            let subscriptions = SubscriptionInfo::vec_from_json(json, lenient).unwrap();
+
+            assert_eq!(2, subscriptions.len());
+            assert_eq!("EvcWjEgzr4rbvhfi3yds0A", subscriptions[0].keys.auth);
+            assert_eq!("xS03Fi5ErfTNH_l9WHE9Ig", subscriptions[1].keys.auth);
+        }
+
+        assert!(matches!(
+            SubscriptionInfo::vec_from_json("{}", true),
+            Err(WebPushError::InvalidSubscription { .. })
+        ));
+        assert!(matches!(
+            SubscriptionInfo::vec_from_json(r#"["not a subscription"]"#, false),
+            Err(WebPushError::InvalidSubscription { .. })
+        ));
+    }
+
+    #[test]
+    fn skips_malformed_subscriptions_only_when_lenient() {
+        let json = r#"[
+            {"endpoint": "https://fcm.googleapis.com/fcm/send/eKClHsXFm9E"},
+            "not a subscription",
+            {
+                "endpoint": "https://fcm.googleapis.com/fcm/send/fSTRzWzHDUk",
+                "keys": {"p256dh": "BGa4N1PI79lboMR_YrwCiCsgp35DRvedt7opHcf0yM3iOBTSoQYqQLwWxAfRKE6tsDnReWmhsImkhDF_DBdkNSU", "auth": "EvcWjEgzr4rbvhfi3yds0A"}
+            }
+        ]"#;
+
+        assert!(matches!(
+            SubscriptionInfo::vec_from_json(json, false),
+            Err(WebPushError::MissingCryptoKeys)
+        ));
+
+        let subscriptions = SubscriptionInfo::vec_from_json(json, true).unwrap();
+
+        assert_eq!(1, subscriptions.len());
+        assert_eq!(
+            "https://fcm.googleapis.com/fcm/send/fSTRzWzHDUk",
+            subscriptions[0].endpoint
+        );
+    }
+
+    #[test]
+    fn parses_a_subscription_change() {
+        let json = r#"{
+            "oldSubscription": {
+                "endpoint": "https://updates.push.services.mozilla.com/wpush/v2/gAAAAABaso4Vajy4STM25r5y5oFfyN451rUmES6mhQ",
+                "expirationTime": 1760000000000,
+                "keys": {
+                    "p256dh": "BLMbF9ffKBiWQLCKvTHb6LO8Nb6dcUh6TItC455vu2kElga6PQvUmaFyCdykxY2nOSSL3yKgfbmFLRTUaGv4yV8",
+                    "auth": "xS03Fi5ErfTNH_l9WHE9Ig"
+                }
+            },
+            "newSubscription": {
+                "endpoint": "https://updates.push.services.mozilla.com/wpush/v2/gAAAAABbZ7ep4W5bb3p0wL6BCDthZmsxqZbq5U",
+                "expirationTime": null,
+                "keys": {
+                    "p256dh": "BGa4N1PI79lboMR_YrwCiCsgp35DRvedt7opHcf0yM3iOBTSoQYqQLwWxAfRKE6tsDnReWmhsImkhDF_DBdkNSU",
+                    "auth": "EvcWjEgzr4rbvhfi3yds0A"
+                }
+            }
+        }"#;
+
+        let change = SubscriptionChange::from_json_str(json).unwrap();
+
+        assert_eq!("xS03Fi5ErfTNH_l9WHE9Ig", change.old.unwrap().keys.auth);
+        assert_eq!(
+            "https://updates.push.services.mozilla.com/wpush/v2/gAAAAABbZ7ep4W5bb3p0wL6BCDthZmsxqZbq5U",
+            change.new.unwrap().endpoint
+        );
+
+        let change = SubscriptionChange::from_json_str(r#"{"oldSubscription": null}"#).unwrap();
+        assert_eq!(None, change.old);
+        assert_eq!(None, change.new);
+
+        assert!(matches!(
+            SubscriptionChange::from_json_str(r#"{"newSubscription": {"endpoint": "https://example.com"}}"#),
+            Err(WebPushError::MissingCryptoKeys)
+        ));
+        assert!(matches!(
+            SubscriptionChange::from_json_str("[]"),
+            Err(WebPushError::InvalidSubscription { .. })
+        ));
+    }
 }
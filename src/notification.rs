@@ -0,0 +1,289 @@
+//! Declarative push notification payloads.
+
+use serde::Serialize;
+
+use crate::error::{PayloadLimitSource, WebPushError};
+
+/// Magic number identifying a declarative push payload.
+const DECLARATIVE_WEB_PUSH: u16 = 8030;
+
+/// Maximum number of actions browsers display on a notification.
+pub const MAX_NOTIFICATION_ACTIONS: usize = 2;
+
+/// A notification the browser can display without running the service worker, see
+/// [Declarative Web Push](https://github.com/WebKit/explainers/tree/main/DeclarativeWebPush).
+///
+/// Serialize it with [`Notification::to_payload`], and send the result with
+/// [`WebPushMessageBuilder::set_payload`](crate::WebPushMessageBuilder::set_payload).
+///
+/// # Example
+///
+/// ```
+/// # use web_push::{Notification, NotificationAction};
+/// let mut notification = Notification::new("New message", "https://example.com/inbox");
+/// notification.body = Some("You have a new message".into());
+/// notification.actions = vec![
+///     NotificationAction::new("Reply", "reply", "https://example.com/reply"),
+///     NotificationAction::new("Archive", "archive", "https://example.com/archive").with_icon("https://example.com/a.png"),
+/// ];
+///
+/// let payload = notification.to_payload().unwrap();
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq, Default, Serialize)]
+pub struct Notification {
+    /// The title of the notification.
+    pub title: String,
+    /// The URL opened when the notification is clicked.
+    pub navigate: String,
+    /// The body text of the notification.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub body: Option<String>,
+    /// The language of the notification, as a BCP 47 tag.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub lang: Option<String>,
+    /// Notifications with the same tag replace each other.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tag: Option<String>,
+    /// The URL of the icon of the notification.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub icon: Option<String>,
+    /// Whether the notification is shown without sound or vibration.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub silent: Option<bool>,
+    /// The buttons shown on the notification, at most [`MAX_NOTIFICATION_ACTIONS`].
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub actions: Vec<NotificationAction>,
+    /// The URL opened when the notification is clicked, under the name of earlier drafts of the spec, for browsers
+    /// still reading it. `navigate` is always sent.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub default_action_url: Option<String>,
+    /// Whether the service worker may change the notification before it is shown. Sent next to the notification in
+    /// the payload, as `mutable`.
+    #[serde(skip)]
+    pub mutable: Option<bool>,
+}
+
+/// A button on a [`Notification`].
+#[derive(Debug, Clone, PartialEq, Eq, Default, Serialize)]
+pub struct NotificationAction {
+    /// The label of the button.
+    pub title: String,
+    /// Identifies the action to the service worker.
+    pub action: String,
+    /// The URL opened when the button is clicked.
+    pub navigate: String,
+    /// The URL of the icon of the button.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub icon: Option<String>,
+}
+
+/// The declarative push payload wrapping the notification.
+#[derive(Serialize)]
+struct DeclarativePayload<'a> {
+    web_push: u16,
+    notification: &'a Notification,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    mutable: Option<bool>,
+}
+
+impl Notification {
+    /// Creates a notification with the required title and navigation URL.
+    pub fn new<S>(title: S, navigate: S) -> Notification
+    where
+        S: Into<String>,
+    {
+        Notification {
+            title: title.into(),
+            navigate: navigate.into(),
+            ..Default::default()
+        }
+    }
+
+    /// Serializes the notification into a declarative push payload.
+    ///
+    /// Returns `TooManyActions` if the notification has more than [`MAX_NOTIFICATION_ACTIONS`] actions.
+    pub fn to_payload(&self) -> Result<Vec<u8>, WebPushError> {
+        if self.actions.len() > MAX_NOTIFICATION_ACTIONS {
+            return Err(WebPushError::TooManyActions);
+        }
+
+        Ok(serde_json::to_vec(&DeclarativePayload {
+            web_push: DECLARATIVE_WEB_PUSH,
+            notification: self,
+            mutable: self.mutable,
+        })?)
+    }
+}
+
+impl NotificationAction {
+    /// Creates an action with the required label, identifier and navigation URL.
+    pub fn new<S>(title: S, action: S, navigate: S) -> NotificationAction
+    where
+        S: Into<String>,
+    {
+        NotificationAction {
+            title: title.into(),
+            action: action.into(),
+            navigate: navigate.into(),
+            icon: None,
+        }
+    }
+
+    /// Sets the icon of the button.
+    pub fn with_icon<S>(mut self, icon: S) -> NotificationAction
+    where
+        S: Into<String>,
+    {
+        self.icon = Some(icon.into());
+        self
+    }
+}
+
+/// Splits `items` into JSON arrays of at most `budget` bytes each, keeping the order of the items. Useful for feeds
+/// or digests that are too large for one push message, see
+/// [`WebPushMessageBuilder::build_chunked`](crate::WebPushMessageBuilder::build_chunked).
+///
+/// The chunks are bare JSON arrays of the items, not declarative [`Notification`] payloads, so the service worker
+/// has to handle them itself.
+///
+/// Returns `PayloadTooLarge` if a single item doesn't fit the budget on its own.
+pub fn split_payload<T>(items: &[T], budget: usize) -> Result<Vec<Vec<u8>>, WebPushError>
+where
+    T: Serialize,
+{
+    let mut chunks = Vec::new();
+    let mut chunk = vec![b'['];
+
+    for item in items {
+        let item = serde_json::to_vec(item)?;
+
+        if item.len() + 2 > budget {
+            return Err(WebPushError::PayloadTooLarge {
+                source: PayloadLimitSource::Local,
+            });
+        }
+
+        // Appending to a non-empty chunk adds a comma before the item and the closing bracket after it.
+        if chunk.len() > 1 && chunk.len() + item.len() + 2 > budget {
+            chunk.push(b']');
+            chunks.push(std::mem::replace(&mut chunk, vec![b'[']));
+        }
+
+        if chunk.len() > 1 {
+            chunk.push(b',');
+        }
+
+        chunk.extend_from_slice(&item);
+    }
+
+    if chunk.len() > 1 {
+        chunk.push(b']');
+        chunks.push(chunk);
+    }
+
+    Ok(chunks)
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use crate::error::WebPushError;
+    use crate::notification::{split_payload, Notification, NotificationAction};
+
+    #[test]
+    fn serializes_a_notification_with_two_actions() {
+        let mut notification = Notification::new("New message", "https://example.com/inbox");
+        notification.actions = vec![
+            NotificationAction::new("Reply", "reply", "https://example.com/reply"),
+            NotificationAction::new("Archive", "archive", "https://example.com/archive")
+                .with_icon("https://example.com/archive.png"),
+        ];
+
+        let payload: serde_json::Value = serde_json::from_slice(&notification.to_payload().unwrap()).unwrap();
+
+        assert_eq!(
+            json!({
+                "web_push": 8030,
+                "notification": {
+                    "title": "New message",
+                    "navigate": "https://example.com/inbox",
+                    "actions": [
+                        {"title": "Reply", "action": "reply", "navigate": "https://example.com/reply"},
+                        {
+                            "title": "Archive",
+                            "action": "archive",
+                            "navigate": "https://example.com/archive",
+                            "icon": "https://example.com/archive.png"
+                        }
+                    ]
+                }
+            }),
+            payload
+        );
+    }
+
+    #[test]
+    fn serializes_mutable_and_default_action_url_only_when_set() {
+        let mut notification = Notification::new("New message", "https://example.com/inbox");
+
+        let payload: serde_json::Value = serde_json::from_slice(&notification.to_payload().unwrap()).unwrap();
+        assert!(payload.get("mutable").is_none());
+        assert!(payload["notification"].get("default_action_url").is_none());
+
+        notification.mutable = Some(true);
+        notification.default_action_url = Some("https://example.com/inbox".into());
+
+        let payload: serde_json::Value = serde_json::from_slice(&notification.to_payload().unwrap()).unwrap();
+        assert_eq!(
+            json!({
+                "web_push": 8030,
+                "notification": {
+                    "title": "New message",
+                    "navigate": "https://example.com/inbox",
+                    "default_action_url": "https://example.com/inbox"
+                },
+                "mutable": true
+            }),
+            payload
+        );
+    }
+
+    #[test]
+    fn rejects_more_than_two_actions() {
+        let mut notification = Notification::new("New message", "https://example.com/inbox");
+        notification.actions = vec![NotificationAction::new("Open", "open", "https://example.com"); 3];
+
+        assert!(matches!(notification.to_payload(), Err(WebPushError::TooManyActions)));
+    }
+
+    #[test]
+    fn splits_a_list_under_the_budget() {
+        let items: Vec<String> = (0..100).map(|i| format!("item {}", i)).collect();
+
+        let chunks = split_payload(&items, 64).unwrap();
+
+        assert!(chunks.len() > 1);
+        assert!(chunks.iter().all(|chunk| chunk.len() <= 64));
+
+        let joined: Vec<String> = chunks
+            .iter()
+            .flat_map(|chunk| serde_json::from_slice::<Vec<String>>(chunk).unwrap())
+            .collect();
+
+        assert_eq!(items, joined);
+        assert!(split_payload::<String>(&[], 64).unwrap().is_empty());
+        assert_eq!(
+            vec![br#"["aa","bbb"]"#.to_vec()],
+            split_payload(&["aa", "bbb"], 12).unwrap()
+        );
+        assert_eq!(
+            vec![br#"["aa"]"#.to_vec(), br#"["bbb"]"#.to_vec()],
+            split_payload(&["aa", "bbb"], 11).unwrap()
+        );
+        assert!(matches!(
+            split_payload(&["x".repeat(64)], 64),
+            Err(WebPushError::PayloadTooLarge { .. })
+        ));
+    }
+}
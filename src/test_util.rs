@@ -0,0 +1,280 @@
+//! Helpers for testing code that sends push notifications. Requires the `test-util` feature.
+
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::JoinHandle;
+
+/// A request received by a [`TestPushServer`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RecordedRequest {
+    /// The request method, `POST` for push messages.
+    pub method: String,
+    /// The path of the request URI, identifying the subscription.
+    pub path: String,
+    /// The headers in the order received, with lower case names.
+    pub headers: Vec<(String, String)>,
+    /// The request body, the encrypted payload.
+    pub body: Vec<u8>,
+}
+
+impl RecordedRequest {
+    /// The value of the first header named `name`, ignoring case.
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(header, _)| header.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+}
+
+/// The response a [`TestPushServer`] sends to every request.
+#[derive(Debug, Clone)]
+struct TestResponse {
+    status: u16,
+    retry_after: Option<u64>,
+    body: String,
+}
+
+/// A push service on `127.0.0.1` for integration tests, recording every request it receives.
+///
+/// Responds `201 Created` by default, change it with [`TestPushServer::respond_with`]. The server is plain HTTP and
+/// serves requests on a background thread until dropped.
+///
+/// ```
+/// # use web_push::test_util::TestPushServer;
+/// # use web_push::*;
+/// # #[tokio::main]
+/// # async fn main() {
+/// let server = TestPushServer::start().unwrap();
+///
+/// let info = SubscriptionInfo::new(server.endpoint("/push/1"), "p256dh".into(), "auth".into());
+/// let message = WebPushMessageBuilder::new(&info).build().unwrap();
+/// IsahcWebPushClient::new().unwrap().send(message).await.unwrap();
+///
+/// assert_eq!(Some("2419200"), server.requests()[0].header("TTL"));
+/// # }
+/// ```
+pub struct TestPushServer {
+    addr: SocketAddr,
+    requests: Arc<Mutex<Vec<RecordedRequest>>>,
+    response: Arc<Mutex<TestResponse>>,
+    stopped: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl TestPushServer {
+    /// Binds a server to a free port on `127.0.0.1`.
+    pub fn start() -> io::Result<TestPushServer> {
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        let addr = listener.local_addr()?;
+
+        let requests = Arc::new(Mutex::new(Vec::new()));
+        let response = Arc::new(Mutex::new(TestResponse {
+            status: 201,
+            retry_after: None,
+            body: String::new(),
+        }));
+        let stopped = Arc::new(AtomicBool::new(false));
+
+        let thread = {
+            let requests = requests.clone();
+            let response = response.clone();
+            let stopped = stopped.clone();
+
+            std::thread::spawn(move || {
+                for stream in listener.incoming() {
+                    if stopped.load(Ordering::SeqCst) {
+                        break;
+                    }
+
+                    if let Ok(stream) = stream {
+                        let response = response.lock().unwrap().clone();
+
+                        if let Ok(request) = serve(stream, &response) {
+                            requests.lock().unwrap().push(request);
+                        }
+                    }
+                }
+            })
+        };
+
+        Ok(TestPushServer {
+            addr,
+            requests,
+            response,
+            stopped,
+            thread: Some(thread),
+        })
+    }
+
+    /// The URL of the server with `path`, to use as a subscription endpoint.
+    pub fn endpoint(&self, path: &str) -> String {
+        format!("http://{}{}", self.addr, path)
+    }
+
+    /// Responds to the following requests with `status`, and the `Retry-After` header in seconds if given. Error
+    /// responses have a JSON body in the format of Mozilla's push service.
+    pub fn respond_with(&self, status: u16, retry_after: Option<u64>) {
+        let body = if status < 300 {
+            String::new()
+        } else {
+            format!(
+                r#"{{"code":{},"errno":999,"error":"test error","message":"test error"}}"#,
+                status
+            )
+        };
+
+        *self.response.lock().unwrap() = TestResponse {
+            status,
+            retry_after,
+            body,
+        };
+    }
+
+    /// All requests received so far, in order.
+    pub fn requests(&self) -> Vec<RecordedRequest> {
+        self.requests.lock().unwrap().clone()
+    }
+}
+
+impl Drop for TestPushServer {
+    fn drop(&mut self) {
+        self.stopped.store(true, Ordering::SeqCst);
+
+        // Wakes up the blocking accept, so the thread sees the flag.
+        let _ = TcpStream::connect(self.addr);
+
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+/// Reads one request from `stream` and sends `response`, closing the connection.
+fn serve(stream: TcpStream, response: &TestResponse) -> io::Result<RecordedRequest> {
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut stream = stream;
+
+    let mut line = String::new();
+    reader.read_line(&mut line)?;
+
+    let mut request_line = line.split_whitespace();
+    let method = request_line.next().unwrap_or_default().to_string();
+    let path = request_line.next().unwrap_or_default().to_string();
+
+    let mut headers = Vec::new();
+
+    loop {
+        line.clear();
+
+        if reader.read_line(&mut line)? == 0 || line.trim_end().is_empty() {
+            break;
+        }
+
+        if let Some((name, value)) = line.split_once(':') {
+            headers.push((name.trim().to_ascii_lowercase(), value.trim().to_string()));
+        }
+    }
+
+    let mut request = RecordedRequest {
+        method,
+        path,
+        headers,
+        body: Vec::new(),
+    };
+
+    if request
+        .header("expect")
+        .is_some_and(|expect| expect.eq_ignore_ascii_case("100-continue"))
+    {
+        stream.write_all(b"HTTP/1.1 100 Continue\r\n\r\n")?;
+    }
+
+    let content_length = request
+        .header("content-length")
+        .and_then(|length| length.parse().ok())
+        .unwrap_or(0);
+    request.body = vec![0; content_length];
+    reader.read_exact(&mut request.body)?;
+
+    let mut head = format!(
+        "HTTP/1.1 {} Test\r\nContent-Length: {}\r\nConnection: close\r\n",
+        response.status,
+        response.body.len()
+    );
+
+    if let Some(retry_after) = response.retry_after {
+        head.push_str(&format!("Retry-After: {}\r\n", retry_after));
+    }
+
+    stream.write_all(format!("{}\r\n{}", head, response.body).as_bytes())?;
+
+    Ok(request)
+}
+
+#[cfg(all(test, feature = "isahc-client"))]
+mod tests {
+    use std::fs::File;
+    use std::time::Duration;
+
+    use crate::test_util::TestPushServer;
+    use crate::{
+        ContentEncoding, IsahcWebPushClient, SubscriptionInfo, VapidSignatureBuilder, WebPushClient, WebPushError,
+        WebPushMessageBuilder,
+    };
+
+    fn subscription_info(server: &TestPushServer) -> SubscriptionInfo {
+        SubscriptionInfo::new(
+            server.endpoint("/push/1"),
+            "BLMbF9ffKBiWQLCKvTHb6LO8Nb6dcUh6TItC455vu2kElga6PQvUmaFyCdykxY2nOSSL3yKgfbmFLRTUaGv4yV8".into(),
+            "xS03Fi5ErfTNH_l9WHE9Ig".into(),
+        )
+    }
+
+    #[tokio::test]
+    async fn records_a_sent_message() {
+        let server = TestPushServer::start().unwrap();
+        let info = subscription_info(&server);
+
+        let key = File::open("resources/vapid_test_key.pem").unwrap();
+        let signature = VapidSignatureBuilder::from_pem(key, &info).unwrap().build().unwrap();
+
+        let mut builder = WebPushMessageBuilder::new(&info);
+        builder.set_ttl(60);
+        builder.set_payload(ContentEncoding::Aes128Gcm, b"test");
+        builder.set_vapid_signature(signature);
+
+        IsahcWebPushClient::new()
+            .unwrap()
+            .send(builder.build().unwrap())
+            .await
+            .unwrap();
+
+        let requests = server.requests();
+        assert_eq!(1, requests.len());
+        assert_eq!("POST", requests[0].method);
+        assert_eq!("/push/1", requests[0].path);
+        assert_eq!(Some("60"), requests[0].header("TTL"));
+        assert!(requests[0].header("Authorization").unwrap().starts_with("vapid t="));
+        assert_eq!(230, requests[0].body.len());
+    }
+
+    #[tokio::test]
+    async fn returns_the_configured_retry_after() {
+        let server = TestPushServer::start().unwrap();
+        server.respond_with(503, Some(30));
+
+        let info = subscription_info(&server);
+        let result = IsahcWebPushClient::new()
+            .unwrap()
+            .send(WebPushMessageBuilder::new(&info).build().unwrap())
+            .await;
+
+        assert!(matches!(
+            result,
+            Err(WebPushError::ServerError { retry_after: Some(retry_after), .. }) if retry_after == Duration::from_secs(30)
+        ));
+    }
+}
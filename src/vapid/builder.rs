@@ -1,5 +1,6 @@
 use std::collections::BTreeMap;
 use std::io::Read;
+use std::time::{SystemTime, UNIX_EPOCH};
 
 use http::uri::Uri;
 use jwt_simple::prelude::*;
@@ -73,13 +74,16 @@ use crate::vapid::{VapidKey, VapidSignature, VapidSigner};
 /// let signature = sig_builder.build().unwrap();
 /// # }
 /// ```
-
 pub struct VapidSignatureBuilder<'a> {
     claims: Claims,
     key: VapidKey,
     subscription_info: &'a SubscriptionInfo,
+    strict_claims: bool,
 }
 
+/// The claims allowed by [`VapidSignatureBuilder::strict_claims`].
+const STANDARD_CLAIMS: [&str; 5] = ["aud", "exp", "sub", "nbf", "iat"];
+
 impl<'a> VapidSignatureBuilder<'a> {
     /// Creates a new builder from a PEM formatted private key.
     ///
@@ -111,6 +115,23 @@ impl<'a> VapidSignatureBuilder<'a> {
         })
     }
 
+    /// Creates a new builder from a PEM formatted private key in a string, like [`from_pem`](Self::from_pem).
+    ///
+    /// Literal `\n` escapes are read as line breaks, so a PEM stored on a single line in an environment variable
+    /// can be passed as is.
+    pub fn from_pem_str(
+        pk_pem: &str,
+        subscription_info: &'a SubscriptionInfo,
+    ) -> Result<VapidSignatureBuilder<'a>, WebPushError> {
+        Self::from_pem(unescape_newlines(pk_pem).as_bytes(), subscription_info)
+    }
+
+    /// Creates a new builder from a PEM formatted private key in a string, like
+    /// [`from_pem_no_sub`](Self::from_pem_no_sub). Literal `\n` escapes are read as line breaks.
+    pub fn from_pem_str_no_sub(pk_pem: &str) -> Result<PartialVapidSignatureBuilder, WebPushError> {
+        Self::from_pem_no_sub(unescape_newlines(pk_pem).as_bytes())
+    }
+
     /// Creates a new builder from a DER formatted private key.
     pub fn from_der<R: Read>(
         mut pk_der: R,
@@ -148,6 +169,50 @@ impl<'a> VapidSignatureBuilder<'a> {
         })
     }
 
+    /// Creates a new builder from an already loaded `jwt_simple` key pair, avoiding a round trip through PEM or DER.
+    pub fn from_key_pair(key: ES256KeyPair, subscription_info: &'a SubscriptionInfo) -> VapidSignatureBuilder<'a> {
+        Self::from_ec(key, subscription_info)
+    }
+
+    /// Creates a new builder from an already loaded `jwt_simple` key pair. This function doesn't take a subscription,
+    /// allowing the reuse of one builder for multiple messages by cloning the resulting builder.
+    pub fn from_key_pair_no_sub(key: ES256KeyPair) -> PartialVapidSignatureBuilder {
+        PartialVapidSignatureBuilder {
+            key: VapidKey::new(key),
+        }
+    }
+
+    /// Creates a new builder from a password protected PKCS#12 (PFX) bundle containing a P-256 private key.
+    ///
+    /// Requires the `pkcs12` feature. Returns `InvalidCryptoKeys` if the password is wrong, or if the
+    /// bundle doesn't contain an EC private key on the P-256 curve.
+    #[cfg(feature = "pkcs12")]
+    pub fn from_pkcs12<R: Read>(
+        pk_pkcs12: R,
+        password: &str,
+        subscription_info: &'a SubscriptionInfo,
+    ) -> Result<VapidSignatureBuilder<'a>, WebPushError> {
+        let pr_key = Self::read_pkcs12(pk_pkcs12, password)?;
+
+        Ok(Self::from_ec(pr_key, subscription_info))
+    }
+
+    /// Creates a new builder from a password protected PKCS#12 (PFX) bundle. This function doesn't take a
+    /// subscription, allowing the reuse of one builder for multiple messages by cloning the resulting builder.
+    ///
+    /// Requires the `pkcs12` feature.
+    #[cfg(feature = "pkcs12")]
+    pub fn from_pkcs12_no_sub<R: Read>(
+        pk_pkcs12: R,
+        password: &str,
+    ) -> Result<PartialVapidSignatureBuilder, WebPushError> {
+        let pr_key = Self::read_pkcs12(pk_pkcs12, password)?;
+
+        Ok(PartialVapidSignatureBuilder {
+            key: VapidKey::new(pr_key),
+        })
+    }
+
     /// Creates a new builder from a raw base64 encoded private key. This isn't the base64 from a key
     /// generated by openssl, but rather the literal bytes of the private key itself. This is the kind
     /// of key given to you by most VAPID key generator sites, and also the kind used in the API of other
@@ -206,19 +271,114 @@ impl<'a> VapidSignatureBuilder<'a> {
         self.claims.custom.insert(key.to_string(), val.into());
     }
 
+    /// Add all claims from a JSON object to the signature, as if [`add_claim`](Self::add_claim) was called for each
+    /// of them. Useful when the claims come from configuration.
+    ///
+    /// Reserved claims in the map are treated the same as when added one by one: `aud` and `exp` override the
+    /// defaults, and `sub` replaces the default subject.
+    pub fn add_claims(&mut self, claims: serde_json::Map<String, Value>) {
+        self.claims.custom.extend(claims);
+    }
+
+    /// Sets the `aud` claim, overriding the origin of the subscription endpoint. Returns `InvalidClaims` if
+    /// `audience` is not an origin like `https://push.example.com`.
+    ///
+    /// This and the other typed setters cover the standard VAPID claims. Other claims are added with
+    /// [`add_claim`](Self::add_claim).
+    pub fn set_audience(&mut self, audience: &str) -> Result<(), WebPushError> {
+        let uri: Uri = audience.parse().map_err(|_| WebPushError::InvalidClaims)?;
+
+        if uri.scheme().is_none() || uri.host().is_none() || !matches!(uri.path(), "" | "/") || uri.query().is_some() {
+            return Err(WebPushError::InvalidClaims);
+        }
+
+        self.add_claim("aud", audience.trim_end_matches('/'));
+        Ok(())
+    }
+
+    /// Sets the `exp` claim, overriding the default of twelve hours from now. Returns `InvalidClaims` if `expiry`
+    /// is not in the future, or more than 24 hours ahead, which push services reject.
+    pub fn set_expiry(&mut self, expiry: SystemTime) -> Result<(), WebPushError> {
+        let valid_for = expiry
+            .duration_since(SystemTime::now())
+            .map_err(|_| WebPushError::InvalidClaims)?;
+
+        if valid_for > std::time::Duration::from_secs(24 * 60 * 60) {
+            return Err(WebPushError::InvalidClaims);
+        }
+
+        self.add_claim("exp", unix_seconds(expiry)?);
+        Ok(())
+    }
+
+    /// Sets the `sub` claim, a contact for the push service operator. Returns `InvalidClaims` if `subject` is not a
+    /// `mailto:` or `https:` URL.
+    pub fn set_subject(&mut self, subject: &str) -> Result<(), WebPushError> {
+        if !(subject.starts_with("mailto:") || subject.starts_with("https:")) {
+            return Err(WebPushError::InvalidClaims);
+        }
+
+        self.add_claim("sub", subject.to_string());
+        Ok(())
+    }
+
+    /// Sets the `nbf` claim, the time before which the token is not valid. Defaults to now. Returns
+    /// `InvalidClaims` if `not_before` is before the Unix epoch.
+    ///
+    /// [`PreparedVapidSigner`] ignores this, as it sets `nbf` to the time of every signature.
+    pub fn set_not_before(&mut self, not_before: SystemTime) -> Result<(), WebPushError> {
+        self.claims.invalid_before = Some(Duration::from_secs(unix_seconds(not_before)?));
+        Ok(())
+    }
+
+    /// Sets the `iat` claim, the time the token was issued. Defaults to now. Returns `InvalidClaims` if `issued_at`
+    /// is before the Unix epoch.
+    ///
+    /// Useful to backdate tokens a little for push services with a clock behind ours, which reject tokens issued
+    /// in their future. [`PreparedVapidSigner`] ignores this, as it sets `iat` to the time of every signature and
+    /// `exp` to twelve hours after it.
+    pub fn set_issued_at(&mut self, issued_at: SystemTime) -> Result<(), WebPushError> {
+        self.claims.issued_at = Some(Duration::from_secs(unix_seconds(issued_at)?));
+        Ok(())
+    }
+
+    /// Makes [`build`](Self::build) return `InvalidClaims` if a claim other than `aud`, `exp`, `sub`, `nbf` or `iat`
+    /// was added, for push services that reject tokens with unknown claims. Guards against shipping debug claims
+    /// to production. Off by default.
+    pub fn strict_claims(&mut self) {
+        self.strict_claims = true;
+    }
+
     /// Builds a signature to be used in [WebPushMessageBuilder](struct.WebPushMessageBuilder.html).
     pub fn build(self) -> Result<VapidSignature, WebPushError> {
+        if self.strict_claims
+            && self
+                .claims
+                .custom
+                .keys()
+                .any(|claim| !STANDARD_CLAIMS.contains(&claim.as_str()))
+        {
+            return Err(WebPushError::InvalidClaims);
+        }
+
         let endpoint: Uri = self.subscription_info.endpoint.parse()?;
         let signature = VapidSigner::sign(self.key, &endpoint, self.claims)?;
 
         Ok(signature)
     }
 
+    /// Splits off the key and claims into a signer that is bound to a push service host later, see
+    /// [`PreparedVapidSigner`]. The subscription info of this builder is not used.
+    pub fn prepare(self) -> PreparedVapidSigner {
+        PreparedVapidSigner::new(self.key, self.claims)
+    }
+
     fn from_ec(ec_key: ES256KeyPair, subscription_info: &'a SubscriptionInfo) -> VapidSignatureBuilder<'a> {
         VapidSignatureBuilder {
             claims: jwt_simple::prelude::Claims::with_custom_claims(BTreeMap::new(), Duration::from_hours(12)),
             key: VapidKey::new(ec_key),
             subscription_info,
+            strict_claims: false,
         }
     }
 
@@ -243,6 +403,37 @@ impl<'a> VapidSignatureBuilder<'a> {
             Err(WebPushError::MissingCryptoKeys)
         }
     }
+
+    /// Decrypts the PKCS#12 bundle, then returns the contained P-256 private key.
+    #[cfg(feature = "pkcs12")]
+    fn read_pkcs12<R: Read>(mut input: R, password: &str) -> Result<ES256KeyPair, WebPushError> {
+        use openssl::nid::Nid;
+        use openssl::pkcs12::Pkcs12;
+
+        let mut der: Vec<u8> = Vec::new();
+        input.read_to_end(&mut der)?;
+
+        let parsed = Pkcs12::from_der(&der)
+            .and_then(|bundle| bundle.parse2(password))
+            .map_err(|_| WebPushError::InvalidCryptoKeys)?;
+
+        let ec_key = parsed
+            .pkey
+            .ok_or(WebPushError::MissingCryptoKeys)?
+            .ec_key()
+            .map_err(|_| WebPushError::InvalidCryptoKeys)?;
+
+        if ec_key.group().curve_name() != Some(Nid::X9_62_PRIME256V1) {
+            return Err(WebPushError::InvalidCryptoKeys);
+        }
+
+        let scalar = ec_key
+            .private_key()
+            .to_vec_padded(32)
+            .map_err(|_| WebPushError::InvalidCryptoKeys)?;
+
+        ES256KeyPair::from_bytes(&scalar).map_err(|_| WebPushError::InvalidCryptoKeys)
+    }
 }
 
 /// A [`VapidSignatureBuilder`] without VAPID subscription info.
@@ -281,9 +472,19 @@ impl PartialVapidSignatureBuilder {
             key: self.key,
             claims: jwt_simple::prelude::Claims::with_custom_claims(BTreeMap::new(), Duration::from_hours(12)),
             subscription_info,
+            strict_claims: false,
         }
     }
 
+    /// Creates a signer with the default claims, that is bound to a push service host later. See
+    /// [`PreparedVapidSigner`].
+    pub fn prepare(self) -> PreparedVapidSigner {
+        PreparedVapidSigner::new(
+            self.key,
+            jwt_simple::prelude::Claims::with_custom_claims(BTreeMap::new(), Duration::from_hours(12)),
+        )
+    }
+
     /// Gets the uncompressed public key bytes derived from the private key used for this VAPID signature.
     ///
     /// Base64 encode these bytes to get the key to send to the client.
@@ -292,13 +493,71 @@ impl PartialVapidSignatureBuilder {
     }
 }
 
+/// A VAPID key with its claims, waiting for the audience of the push service host to sign for.
+///
+/// Created with [`VapidSignatureBuilder::prepare`] or [`PartialVapidSignatureBuilder::prepare`], so key parsing and
+/// claim setup can happen before the target host is known. The expiry is twelve hours from the call to
+/// [`PreparedVapidSigner::finalize_for`], unless an `exp` claim was added.
+///
+/// # Example
+///
+/// ```no_run
+/// # use web_push::VapidSignatureBuilder;
+/// let signer = VapidSignatureBuilder::from_pem_no_sub("Some PEM".as_bytes()).unwrap().prepare();
+///
+/// let signature = signer.finalize_for("fcm.googleapis.com").unwrap();
+/// ```
+#[derive(Clone)]
+pub struct PreparedVapidSigner {
+    key: VapidKey,
+    claims: Claims,
+}
+
+impl PreparedVapidSigner {
+    fn new(key: VapidKey, claims: Claims) -> PreparedVapidSigner {
+        PreparedVapidSigner { key, claims }
+    }
+
+    /// Signs the claims with the audience `https://{host}`.
+    pub fn finalize_for(&self, host: &str) -> Result<VapidSignature, WebPushError> {
+        let endpoint: Uri = format!("https://{}", host).parse()?;
+
+        let now = Clock::now_since_epoch();
+        let mut claims = self.claims.clone();
+        claims.issued_at = Some(now);
+        claims.invalid_before = Some(now);
+        claims.expires_at = Some(now + Duration::from_hours(12));
+
+        VapidSigner::sign(self.key.clone(), &endpoint, claims)
+    }
+
+    /// Gets the uncompressed public key bytes derived from the private key used for this VAPID signature.
+    pub fn get_public_key(&self) -> Vec<u8> {
+        self.key.public_key()
+    }
+}
+
+/// Replaces literal `\n` escapes with line breaks.
+fn unescape_newlines(pem: &str) -> String {
+    pem.replace("\\n", "\n")
+}
+
+/// Seconds since the Unix epoch, as used in JWT claims.
+fn unix_seconds(time: SystemTime) -> Result<u64, WebPushError> {
+    time.duration_since(UNIX_EPOCH)
+        .map(|since| since.as_secs())
+        .map_err(|_| WebPushError::InvalidClaims)
+}
+
 #[cfg(test)]
 mod tests {
     use std::fs::File;
 
     use ::lazy_static::lazy_static;
+    use jwt_simple::prelude::ES256KeyPair;
 
     use crate::message::SubscriptionInfo;
+    use crate::vapid::signer::jwt_claims;
     use crate::vapid::VapidSignatureBuilder;
 
     lazy_static! {
@@ -306,6 +565,11 @@ mod tests {
         static ref PRIVATE_DER: File = File::open("resources/vapid_test_key.der").unwrap();
     }
 
+    #[cfg(feature = "pkcs12")]
+    lazy_static! {
+        static ref PRIVATE_PKCS12: Vec<u8> = std::fs::read("resources/vapid_test_key.p12").unwrap();
+    }
+
     lazy_static! {
         static ref SUBSCRIPTION_INFO: SubscriptionInfo =
             serde_json::from_value(
@@ -321,6 +585,163 @@ mod tests {
 
     static PRIVATE_BASE64: &str = "IQ9Ur0ykXoHS9gzfYX0aBjy9lvdrjx_PFUXmie9YRcY";
 
+    #[test]
+    fn test_builder_strict_claims() {
+        let builder = || {
+            let key = File::open("resources/vapid_test_key.pem").unwrap();
+            let mut builder = VapidSignatureBuilder::from_pem(key, &SUBSCRIPTION_INFO).unwrap();
+            builder.add_claim("sub", "mailto:ops@example.com");
+            builder.add_claim("foo", "bar");
+            builder
+        };
+
+        assert!(builder().build().is_ok());
+
+        let mut strict = builder();
+        strict.strict_claims();
+        assert!(matches!(strict.build(), Err(crate::WebPushError::InvalidClaims)));
+
+        let key = File::open("resources/vapid_test_key.pem").unwrap();
+        let mut strict = VapidSignatureBuilder::from_pem(key, &SUBSCRIPTION_INFO).unwrap();
+        strict.strict_claims();
+        strict.add_claim("sub", "mailto:ops@example.com");
+        strict
+            .set_expiry(std::time::SystemTime::now() + std::time::Duration::from_secs(60))
+            .unwrap();
+        assert!(strict.build().is_ok());
+    }
+
+    #[test]
+    fn test_builder_add_claims() {
+        let key = File::open("resources/vapid_test_key.pem").unwrap();
+        let mut builder = VapidSignatureBuilder::from_pem(key, &SUBSCRIPTION_INFO).unwrap();
+        let claims = serde_json::json!({"sub": "mailto:ops@example.com", "foo": "bar"});
+        builder.add_claims(claims.as_object().unwrap().clone());
+
+        let signature = builder.build().unwrap();
+        let payload = jwt_claims(&signature);
+
+        assert_eq!("mailto:ops@example.com", payload["sub"]);
+        assert_eq!("bar", payload["foo"]);
+        assert_eq!("https://updates.push.services.mozilla.com", payload["aud"]);
+    }
+
+    #[test]
+    fn test_builder_from_escaped_pem_str() {
+        let pem = std::fs::read_to_string("resources/vapid_test_key.pem").unwrap();
+        let escaped = pem.trim_end().replace('\n', "\\n");
+        assert!(!escaped.contains('\n'));
+
+        let builder = VapidSignatureBuilder::from_pem_str(&escaped, &SUBSCRIPTION_INFO).unwrap();
+        let signature = builder.build().unwrap();
+
+        let expected = VapidSignatureBuilder::from_pem_str_no_sub(&pem).unwrap();
+        assert_eq!(expected.get_public_key(), signature.auth_k);
+    }
+
+    #[test]
+    fn test_builder_sets_typed_claims() {
+        let not_before = std::time::UNIX_EPOCH + std::time::Duration::from_secs(1_700_000_000);
+        let expiry = std::time::SystemTime::now() + std::time::Duration::from_secs(3600);
+
+        let key = File::open("resources/vapid_test_key.pem").unwrap();
+        let mut builder = VapidSignatureBuilder::from_pem(key, &SUBSCRIPTION_INFO).unwrap();
+        builder.set_not_before(not_before).unwrap();
+        builder.set_expiry(expiry).unwrap();
+        builder.set_subject("mailto:ops@example.com").unwrap();
+        builder.set_audience("https://push.example.com/").unwrap();
+
+        let signature = builder.build().unwrap();
+        let payload = jwt_claims(&signature);
+
+        assert_eq!(1_700_000_000, payload["nbf"]);
+        assert_eq!(
+            expiry.duration_since(std::time::UNIX_EPOCH).unwrap().as_secs(),
+            payload["exp"]
+        );
+        assert_eq!("mailto:ops@example.com", payload["sub"]);
+        assert_eq!("https://push.example.com", payload["aud"]);
+    }
+
+    #[test]
+    fn test_builder_sets_issued_at() {
+        let issued_at = std::time::SystemTime::now() - std::time::Duration::from_secs(30);
+
+        let key = File::open("resources/vapid_test_key.pem").unwrap();
+        let mut builder = VapidSignatureBuilder::from_pem(key, &SUBSCRIPTION_INFO).unwrap();
+        builder.set_issued_at(issued_at).unwrap();
+
+        let signature = builder.build().unwrap();
+        let payload = jwt_claims(&signature);
+
+        assert_eq!(
+            issued_at.duration_since(std::time::UNIX_EPOCH).unwrap().as_secs(),
+            payload["iat"]
+        );
+    }
+
+    #[test]
+    fn test_builder_rejects_invalid_typed_claims() {
+        let key = File::open("resources/vapid_test_key.pem").unwrap();
+        let mut builder = VapidSignatureBuilder::from_pem(key, &SUBSCRIPTION_INFO).unwrap();
+        let now = std::time::SystemTime::now();
+
+        assert!(builder.set_audience("https://push.example.com/path").is_err());
+        assert!(builder.set_audience("push.example.com").is_err());
+        assert!(builder.set_subject("ops@example.com").is_err());
+        assert!(builder.set_expiry(now - std::time::Duration::from_secs(60)).is_err());
+        assert!(builder
+            .set_expiry(now + std::time::Duration::from_secs(25 * 60 * 60))
+            .is_err());
+    }
+
+    #[test]
+    fn test_prepared_signer_finalizes_for_host() {
+        let key = File::open("resources/vapid_test_key.pem").unwrap();
+        let mut builder = VapidSignatureBuilder::from_pem(key, &SUBSCRIPTION_INFO).unwrap();
+        builder.add_claim("sub", "mailto:ops@example.com");
+
+        let signer = builder.prepare();
+        let signature = signer.finalize_for("fcm.googleapis.com").unwrap();
+
+        let payload = jwt_claims(&signature);
+
+        assert_eq!("https://fcm.googleapis.com", payload["aud"]);
+        assert_eq!("mailto:ops@example.com", payload["sub"]);
+        assert_eq!(signer.get_public_key(), signature.auth_k);
+    }
+
+    #[test]
+    fn test_prepared_signer_ignores_issued_at() {
+        let key = File::open("resources/vapid_test_key.pem").unwrap();
+        let mut builder = VapidSignatureBuilder::from_pem(key, &SUBSCRIPTION_INFO).unwrap();
+        builder
+            .set_issued_at(std::time::SystemTime::now() + std::time::Duration::from_secs(13 * 60 * 60))
+            .unwrap();
+
+        let signature = builder.prepare().finalize_for("fcm.googleapis.com").unwrap();
+
+        let payload = jwt_claims(&signature);
+
+        assert_eq!(
+            12 * 60 * 60,
+            payload["exp"].as_u64().unwrap() - payload["iat"].as_u64().unwrap()
+        );
+    }
+
+    #[test]
+    fn test_partial_builder_prepares_signer() {
+        let signer = VapidSignatureBuilder::from_base64_no_sub(PRIVATE_BASE64, base64::URL_SAFE_NO_PAD)
+            .unwrap()
+            .prepare();
+
+        assert!(signer.finalize_for("updates.push.services.mozilla.com").is_ok());
+        assert!(matches!(
+            signer.finalize_for("not a host"),
+            Err(crate::error::WebPushError::InvalidUri)
+        ));
+    }
+
     #[test]
     fn test_builder_from_pem() {
         let builder = VapidSignatureBuilder::from_pem(&*PRIVATE_PEM, &SUBSCRIPTION_INFO).unwrap();
@@ -360,4 +781,43 @@ mod tests {
 
         assert!(!signature.auth_t.is_empty());
     }
+
+    #[test]
+    fn test_builder_from_key_pair() {
+        let key =
+            ES256KeyPair::from_bytes(&base64::decode_config(PRIVATE_BASE64, base64::URL_SAFE_NO_PAD).unwrap()).unwrap();
+        let builder = VapidSignatureBuilder::from_key_pair(key, &SUBSCRIPTION_INFO);
+        let signature = builder.build().unwrap();
+
+        assert_eq!(
+            "BMjQIp55pdbU8pfCBKyXcZjlmER_mXt5LqNrN1hrXbdBS5EnhIbMu3Au-RV53iIpztzNXkGI56BFB1udQ8Bq_H4",
+            base64::encode_config(&signature.auth_k, base64::URL_SAFE_NO_PAD)
+        );
+
+        assert!(!signature.auth_t.is_empty());
+    }
+
+    #[cfg(feature = "pkcs12")]
+    #[test]
+    fn test_builder_from_pkcs12() {
+        let builder =
+            VapidSignatureBuilder::from_pkcs12(PRIVATE_PKCS12.as_slice(), "web-push", &SUBSCRIPTION_INFO).unwrap();
+        let signature = builder.build().unwrap();
+
+        assert_eq!(
+            "BMo1HqKF6skMZYykrte9duqYwBD08mDQKTunRkJdD3sTJ9E-yyN6sJlPWTpKNhp-y2KeS6oANHF-q3w37bClb7U",
+            base64::encode_config(&signature.auth_k, base64::URL_SAFE_NO_PAD)
+        );
+
+        assert!(!signature.auth_t.is_empty());
+    }
+
+    #[cfg(feature = "pkcs12")]
+    #[test]
+    fn test_builder_from_pkcs12_wrong_password() {
+        assert!(matches!(
+            VapidSignatureBuilder::from_pkcs12(PRIVATE_PKCS12.as_slice(), "wrong", &SUBSCRIPTION_INFO),
+            Err(crate::WebPushError::InvalidCryptoKeys)
+        ));
+    }
 }
@@ -0,0 +1,156 @@
+//! Caching of built VAPID signatures.
+
+use std::collections::HashMap;
+use std::sync::{Mutex, PoisonError};
+use std::time::{Duration, SystemTime};
+
+use crate::error::WebPushError;
+use crate::message::SubscriptionInfo;
+use crate::vapid::builder::PartialVapidSignatureBuilder;
+use crate::vapid::VapidSignature;
+
+/// Signatures expiring sooner than this are rebuilt instead of served from the cache.
+const EXPIRY_LEEWAY: Duration = Duration::from_secs(5 * 60);
+
+/// A thread-safe cache of VAPID signatures, keyed by the signing key and the audience.
+///
+/// Signing with ES256 is relatively expensive, and a signature is valid for every subscription sharing the same
+/// push service host. The cache reuses a signature until it gets close to the expiry in its JWT `exp` claim, so a
+/// stale token is never served. Multiple keys can share one cache, which is useful during key rotation.
+///
+/// # Example
+///
+/// ```no_run
+/// # use web_push::{VapidSignatureBuilder, VapidSignatureCache};
+/// let builder = VapidSignatureBuilder::from_pem_no_sub("Some PEM".as_bytes()).unwrap();
+/// let cache = VapidSignatureCache::new();
+///
+/// let signature = cache.signature_for(&builder, "fcm.googleapis.com").unwrap();
+/// ```
+#[derive(Default)]
+pub struct VapidSignatureCache {
+    signatures: Mutex<HashMap<(Vec<u8>, String), CachedSignature>>,
+}
+
+struct CachedSignature {
+    signature: VapidSignature,
+    expires_at: SystemTime,
+}
+
+impl VapidSignatureCache {
+    /// Creates an empty cache.
+    pub fn new() -> VapidSignatureCache {
+        Self::default()
+    }
+
+    /// Gets the signature for the push service `host` signed with the key of `builder`. Returns the cached
+    /// signature if it is still valid, otherwise signs a new one and caches it.
+    pub fn signature_for(
+        &self,
+        builder: &PartialVapidSignatureBuilder,
+        host: &str,
+    ) -> Result<VapidSignature, WebPushError> {
+        self.signature_at(builder, host, SystemTime::now())
+    }
+
+    /// Removes all cached signatures.
+    pub fn clear(&self) {
+        self.signatures.lock().unwrap_or_else(PoisonError::into_inner).clear();
+    }
+
+    fn signature_at(
+        &self,
+        builder: &PartialVapidSignatureBuilder,
+        host: &str,
+        now: SystemTime,
+    ) -> Result<VapidSignature, WebPushError> {
+        let audience = format!("https://{}", host);
+        let cache_key = (builder.get_public_key(), audience);
+
+        let mut signatures = self.signatures.lock().unwrap_or_else(PoisonError::into_inner);
+
+        if let Some(cached) = signatures.get(&cache_key) {
+            if cached.expires_at > now + EXPIRY_LEEWAY {
+                return Ok(cached.signature.clone());
+            }
+        }
+
+        let subscription_info = SubscriptionInfo::new(cache_key.1.as_str(), "", "");
+        let signature = builder.clone().add_sub_info(&subscription_info).build()?;
+        let expires_at = signature.expires_at().ok_or(WebPushError::InvalidClaims)?;
+
+        signatures.insert(
+            cache_key,
+            CachedSignature {
+                signature: signature.clone(),
+                expires_at,
+            },
+        );
+
+        Ok(signature)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, SystemTime};
+
+    use crate::vapid::cache::{CachedSignature, VapidSignatureCache};
+    use crate::vapid::{VapidSignature, VapidSignatureBuilder};
+
+    static PRIVATE_BASE64: &str = "IQ9Ur0ykXoHS9gzfYX0aBjy9lvdrjx_PFUXmie9YRcY";
+    static OTHER_PRIVATE_BASE64: &str = "ODPZpCZ8hnkfbnEndTbvEiLtEJ88vQUUcd6DkhKY240";
+
+    #[test]
+    fn reuses_signature_for_same_host() {
+        let builder = VapidSignatureBuilder::from_base64_no_sub(PRIVATE_BASE64, base64::URL_SAFE_NO_PAD).unwrap();
+        let cache = VapidSignatureCache::new();
+
+        let first = cache.signature_for(&builder, "fcm.googleapis.com").unwrap();
+        let second = cache.signature_for(&builder, "fcm.googleapis.com").unwrap();
+
+        assert_eq!(first, second);
+        assert_eq!(1, cache.signatures.lock().unwrap().len());
+    }
+
+    #[test]
+    fn caches_per_key_and_host() {
+        let builder = VapidSignatureBuilder::from_base64_no_sub(PRIVATE_BASE64, base64::URL_SAFE_NO_PAD).unwrap();
+        let other_builder =
+            VapidSignatureBuilder::from_base64_no_sub(OTHER_PRIVATE_BASE64, base64::URL_SAFE_NO_PAD).unwrap();
+        let cache = VapidSignatureCache::new();
+
+        let fcm = cache.signature_for(&builder, "fcm.googleapis.com").unwrap();
+        let mozilla = cache
+            .signature_for(&builder, "updates.push.services.mozilla.com")
+            .unwrap();
+        let rotated = cache.signature_for(&other_builder, "fcm.googleapis.com").unwrap();
+
+        assert_ne!(fcm.auth_t, mozilla.auth_t);
+        assert_ne!(fcm.auth_k, rotated.auth_k);
+        assert_eq!(3, cache.signatures.lock().unwrap().len());
+    }
+
+    #[test]
+    fn rebuilds_signature_close_to_expiry() {
+        let builder = VapidSignatureBuilder::from_base64_no_sub(PRIVATE_BASE64, base64::URL_SAFE_NO_PAD).unwrap();
+        let cache = VapidSignatureCache::new();
+        let stale = VapidSignature {
+            auth_t: String::from("stale"),
+            auth_k: builder.get_public_key(),
+        };
+
+        cache.signatures.lock().unwrap().insert(
+            (builder.get_public_key(), String::from("https://fcm.googleapis.com")),
+            CachedSignature {
+                signature: stale.clone(),
+                expires_at: SystemTime::now() + Duration::from_secs(60),
+            },
+        );
+
+        let signature = cache.signature_for(&builder, "fcm.googleapis.com").unwrap();
+
+        assert_ne!(stale, signature);
+        assert!(signature.expires_at().unwrap() > SystemTime::now() + Duration::from_secs(60 * 60));
+    }
+}
@@ -1,5 +1,7 @@
 use jwt_simple::prelude::*;
 
+use crate::error::WebPushError;
+
 /// The P256 curve key pair used for VAPID ECDHSA.
 pub struct VapidKey(pub ES256KeyPair);
 
@@ -20,11 +22,65 @@ impl VapidKey {
     }
 }
 
+/// Length of an uncompressed P-256 public key: the `0x04` tag followed by both coordinates.
+const UNCOMPRESSED_PUBLIC_KEY_LEN: usize = 65;
+
+/// Encodes an uncompressed public key as the `applicationServerKey` expected by `pushManager.subscribe()` in the
+/// browser: base64url without padding.
+pub fn public_key_to_application_server_key(raw: &[u8]) -> String {
+    base64::encode_config(raw, base64::URL_SAFE_NO_PAD)
+}
+
+/// Decodes an `applicationServerKey`, for example one sent back by the browser, into the uncompressed public key.
+///
+/// Trailing padding is accepted. Returns `InvalidCryptoKeys` if the key is not base64url, or not an uncompressed
+/// P-256 public key.
+pub fn application_server_key_to_raw(key: &str) -> Result<Vec<u8>, WebPushError> {
+    let raw = base64::decode_config(key.trim_end_matches('='), base64::URL_SAFE_NO_PAD)?;
+
+    if raw.len() != UNCOMPRESSED_PUBLIC_KEY_LEN || raw[0] != 0x04 {
+        return Err(WebPushError::InvalidCryptoKeys);
+    }
+
+    Ok(raw)
+}
+
 #[cfg(test)]
 mod tests {
     use std::fs::File;
 
-    use crate::vapid::key::VapidKey;
+    use crate::error::WebPushError;
+    use crate::vapid::key::{application_server_key_to_raw, public_key_to_application_server_key, VapidKey};
+
+    #[test]
+    fn test_application_server_key_round_trip() {
+        let f = File::open("resources/vapid_test_key.pem").unwrap();
+        let key = VapidKey::new(crate::VapidSignatureBuilder::read_pem(f).unwrap());
+
+        let application_server_key = public_key_to_application_server_key(&key.public_key());
+
+        assert!(!application_server_key.contains(['=', '+', '/']));
+        assert_eq!(
+            key.public_key(),
+            application_server_key_to_raw(&application_server_key).unwrap()
+        );
+        assert_eq!(
+            key.public_key(),
+            application_server_key_to_raw(&format!("{}=", application_server_key)).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_application_server_key_rejects_invalid_keys() {
+        assert!(matches!(
+            application_server_key_to_raw("not base64!"),
+            Err(WebPushError::InvalidCryptoKeys)
+        ));
+        assert!(matches!(
+            application_server_key_to_raw("xS03Fi5ErfTNH_l9WHE9Ig"),
+            Err(WebPushError::InvalidCryptoKeys)
+        ));
+    }
 
     #[test]
     /// Tests that VapidKey derives the correct public key.
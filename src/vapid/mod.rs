@@ -1,10 +1,13 @@
 //! Contains tooling for signing with VAPID.
 
-pub use self::builder::VapidSignatureBuilder;
+pub use self::builder::{PreparedVapidSigner, VapidSignatureBuilder};
+pub use self::cache::VapidSignatureCache;
 use self::key::VapidKey;
+pub use self::key::{application_server_key_to_raw, public_key_to_application_server_key};
 pub use self::signer::VapidSignature;
 use self::signer::VapidSigner;
 
 pub mod builder;
+mod cache;
 mod key;
 mod signer;
@@ -1,4 +1,5 @@
 use std::collections::BTreeMap;
+use std::time::{SystemTime, UNIX_EPOCH};
 
 use http::uri::Uri;
 use jwt_simple::prelude::*;
@@ -12,10 +13,72 @@ use crate::{error::WebPushError, vapid::VapidKey};
 pub struct VapidSignature {
     /// The signed JWT, base64 encoded
     pub auth_t: String,
-    /// The public key bytes
+    /// The public key bytes, the 65 byte uncompressed P-256 point as sent in the `k` parameter. Encrypting a payload
+    /// with a key of another length fails with `InvalidVapidPublicKey`.
     pub auth_k: Vec<u8>,
 }
 
+impl VapidSignature {
+    /// Checks that the JWT was signed by the key with the uncompressed `public_key`, and has not expired.
+    ///
+    /// Returns `InvalidCryptoKeys` if `public_key` is not a P-256 public key, and `InvalidClaims` if the signature
+    /// doesn't match or the token has expired. Useful for relays that re-validate the tokens they forward.
+    pub fn verify(&self, public_key: &[u8]) -> Result<(), WebPushError> {
+        let public_key = ES256PublicKey::from_bytes(public_key).map_err(|_| WebPushError::InvalidCryptoKeys)?;
+
+        public_key
+            .verify_token::<BTreeMap<String, Value>>(&self.auth_t, None)
+            .map(|_| ())
+            .map_err(|_| WebPushError::InvalidClaims)
+    }
+
+    /// The `Authorization` header name and value for the signature, `vapid t=<jwt>, k=<key>` from RFC8292, as sent
+    /// with the aes128gcm encoding. Useful for custom HTTP clients that don't build a [`WebPushMessage`].
+    ///
+    /// [`WebPushMessage`]: crate::WebPushMessage
+    pub fn authorization_header(&self) -> (String, String) {
+        (
+            "Authorization".into(),
+            format!(
+                "vapid t={}, k={}",
+                self.auth_t,
+                base64::encode_config(&self.auth_k, base64::URL_SAFE_NO_PAD)
+            ),
+        )
+    }
+
+    /// How long the signature stays valid, decoded from the `exp` claim of the JWT. Returns `None` if the signature
+    /// has expired, or has no `exp` claim. Useful for deciding whether to reuse a cached signature.
+    pub fn time_until_expiry(&self) -> Option<std::time::Duration> {
+        self.expires_at()?
+            .duration_since(SystemTime::now())
+            .ok()
+            .filter(|remaining| !remaining.is_zero())
+    }
+
+    /// Decodes the `exp` claim from the signed JWT.
+    pub(crate) fn expires_at(&self) -> Option<SystemTime> {
+        self.decode_claims()?
+            .get("exp")
+            .and_then(Value::as_u64)
+            .map(|exp| UNIX_EPOCH + std::time::Duration::from_secs(exp))
+    }
+
+    /// Decodes the claims, the payload of the signed JWT.
+    fn decode_claims(&self) -> Option<Value> {
+        let payload = self.auth_t.split('.').nth(1)?;
+        let payload = base64::decode_config(payload, base64::URL_SAFE_NO_PAD).ok()?;
+
+        serde_json::from_slice(&payload).ok()
+    }
+}
+
+/// The claims of `signature`, for checking them in tests.
+#[cfg(test)]
+pub(crate) fn jwt_claims(signature: &VapidSignature) -> Value {
+    signature.decode_claims().unwrap()
+}
+
 /// JWT claims object. Custom claims are implemented as a map.
 pub type Claims = JWTClaims<BTreeMap<String /*Use String as lifetimes bug out when serializing a tuple*/, Value>>;
 
@@ -46,6 +109,11 @@ impl VapidSigner {
             claims.custom.remove("exp");
         }
 
+        // Some push services reject tokens without iat, so make sure it is always there.
+        if claims.issued_at.is_none() {
+            claims.issued_at = Some(Clock::now_since_epoch());
+        }
+
         // Add sub if not provided as some browsers (like firefox) require it even though the API doesn't say its needed >:[
         if !claims.custom.contains_key("sub") {
             claims = claims.with_subject("mailto:example@example.com".to_string());
@@ -55,7 +123,8 @@ impl VapidSigner {
 
         let auth_k = key.public_key();
 
-        //Generate JWT signature
+        //Generate JWT signature. jwt-simple sets the `alg: ES256` and `typ: JWT` header fields, which some push
+        //services require.
         let auth_t = key.0.sign(claims).map_err(|_| WebPushError::InvalidClaims)?;
 
         Ok(VapidSignature { auth_t, auth_k })
@@ -63,4 +132,107 @@ impl VapidSigner {
 }
 
 #[cfg(test)]
-mod tests {}
+mod tests {
+    use std::fs::File;
+
+    use jwt_simple::prelude::*;
+    use serde_json::Value;
+
+    use crate::error::WebPushError;
+    use crate::vapid::signer::jwt_claims;
+    use crate::vapid::{VapidKey, VapidSignature, VapidSigner};
+
+    fn signature() -> (VapidSignature, Vec<u8>) {
+        let f = File::open("resources/vapid_test_key.pem").unwrap();
+        let key = VapidKey::new(crate::VapidSignatureBuilder::read_pem(f).unwrap());
+        let public_key = key.public_key();
+        let endpoint = "https://fcm.googleapis.com/fcm/send/eKClHsXFm9E".parse().unwrap();
+        let claims = Claims::with_custom_claims(Default::default(), Duration::from_hours(12));
+
+        (VapidSigner::sign(key, &endpoint, claims).unwrap(), public_key)
+    }
+
+    #[test]
+    fn test_verify_signature() {
+        let (signature, public_key) = signature();
+
+        assert!(signature.verify(&public_key).is_ok());
+    }
+
+    #[test]
+    fn test_verify_rejects_tampered_signature() {
+        let (mut signature, public_key) = signature();
+
+        let mut parts: Vec<String> = signature.auth_t.split('.').map(String::from).collect();
+        parts[1] = base64::encode_config(r#"{"aud":"https://evil.example.com"}"#, base64::URL_SAFE_NO_PAD);
+        signature.auth_t = parts.join(".");
+
+        assert!(matches!(
+            signature.verify(&public_key),
+            Err(WebPushError::InvalidClaims)
+        ));
+        assert!(matches!(
+            signature.verify(&[4; 10]),
+            Err(WebPushError::InvalidCryptoKeys)
+        ));
+    }
+
+    #[test]
+    fn test_authorization_header() {
+        let signature = VapidSignature {
+            auth_t: String::from("foo"),
+            auth_k: String::from("bar").into_bytes(),
+        };
+
+        assert_eq!(
+            ("Authorization".to_string(), "vapid t=foo, k=YmFy".to_string()),
+            signature.authorization_header()
+        );
+    }
+
+    #[test]
+    fn test_time_until_expiry() {
+        let (signature, _) = signature();
+        let remaining = signature.time_until_expiry().unwrap();
+
+        assert!(remaining <= std::time::Duration::from_secs(12 * 60 * 60));
+        assert!(remaining > std::time::Duration::from_secs(12 * 60 * 60 - 60));
+
+        let f = File::open("resources/vapid_test_key.pem").unwrap();
+        let key = VapidKey::new(crate::VapidSignatureBuilder::read_pem(f).unwrap());
+        let endpoint = "https://fcm.googleapis.com/fcm/send/eKClHsXFm9E".parse().unwrap();
+        let mut claims = Claims::with_custom_claims(Default::default(), Duration::from_hours(12));
+        claims.expires_at = Some(Duration::from_secs(1_000_000_000));
+
+        assert_eq!(
+            None,
+            VapidSigner::sign(key, &endpoint, claims).unwrap().time_until_expiry()
+        );
+    }
+
+    #[test]
+    fn test_signature_always_has_iat() {
+        let f = File::open("resources/vapid_test_key.pem").unwrap();
+        let key = VapidKey::new(crate::VapidSignatureBuilder::read_pem(f).unwrap());
+        let endpoint = "https://fcm.googleapis.com/fcm/send/eKClHsXFm9E".parse().unwrap();
+        let mut claims = Claims::with_custom_claims(Default::default(), Duration::from_hours(12));
+        claims.issued_at = None;
+
+        let signature = VapidSigner::sign(key, &endpoint, claims).unwrap();
+        let payload = jwt_claims(&signature);
+
+        assert!(payload["iat"].as_u64().unwrap() > 0);
+    }
+
+    #[test]
+    fn test_signature_header_has_alg_and_typ() {
+        let (signature, _) = signature();
+
+        let header = signature.auth_t.split('.').next().unwrap();
+        let header: Value =
+            serde_json::from_slice(&base64::decode_config(header, base64::URL_SAFE_NO_PAD).unwrap()).unwrap();
+
+        assert_eq!(Some("ES256"), header["alg"].as_str());
+        assert_eq!(Some("JWT"), header["typ"].as_str());
+    }
+}